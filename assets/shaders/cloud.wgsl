@@ -1,8 +1,11 @@
 
+#import bevy_pbr::mesh_view_bindings
+#import resume::environment
+
+// Same as `cloud::MAX_LAYERS_ABOVE`
+const MAX_LAYERS_ABOVE: u32 = 3u;
+
 struct Material {
-    sun_direction: vec3<f32>,
-    camera_position: vec3<f32>,
-    time: f32,
     shadow_dist: f32,
     shadow_coef: f32,
     sun_pen: f32,
@@ -11,6 +14,15 @@ struct Material {
     cloud_coef: f32,
     cloud_height: f32,
     scroll: f32,
+    size: f32,
+    water: f32,
+    fade_start: f32,
+    fade_end: f32,
+    above_count: u32,
+    // Per layer above: height over this one, tile size, scroll and shadow strength
+    above_placement: array<vec4<f32>, MAX_LAYERS_ABOVE>,
+    // Per layer above: worley factor, value factor, cloud coef and cloud height
+    above_shape: array<vec4<f32>, MAX_LAYERS_ABOVE>,
 };
 
 @group(1) @binding(0)
@@ -23,6 +35,20 @@ var w_sampler: sampler;
 var v_tex: texture_2d<f32>;
 @group(1) @binding(4)
 var v_sampler: sampler;
+@group(1) @binding(7)
+var above_worley_0: texture_2d<f32>;
+@group(1) @binding(8)
+var above_sampler: sampler;
+@group(1) @binding(9)
+var above_value_0: texture_2d<f32>;
+@group(1) @binding(10)
+var above_worley_1: texture_2d<f32>;
+@group(1) @binding(11)
+var above_value_1: texture_2d<f32>;
+@group(1) @binding(12)
+var above_worley_2: texture_2d<f32>;
+@group(1) @binding(13)
+var above_value_2: texture_2d<f32>;
 
 fn step(a: f32, b: f32, t: f32) -> f32 {
     let x = t - a;
@@ -35,27 +61,81 @@ fn sabs(x: f32, j: f32) -> f32 {
 }
 
 fn cloud(p: vec2<f32>) -> f32 {
-    let g = sabs(length(p) - 0.8 + sin(material.time) * 0.2, 0.001) + 0.7 ;
-    let w = (textureSample(w_tex, w_sampler, p - material.time * 0.01).x) - material.worley_factor  ;
-    let z = textureSample(v_tex, v_sampler, p + material.time * vec2(0.01, -0.01)).x - material.value_factor ;
-    return z * (1. + w) * material.cloud_coef - step(0.8, 1.6, g)   ;
+    let w = (textureSample(w_tex, w_sampler, p - environment.time * 0.01).x) - material.worley_factor  ;
+    let z = textureSample(v_tex, v_sampler, p + environment.time * vec2(0.01, -0.01)).x - material.value_factor ;
+    return z * (1. + w) * material.cloud_coef;
 }
 
 
+// The plane follows the camera, so uvs come from the world position. Same layout as `shape::Plane` at the origin
+fn world_uv(xz: vec2<f32>, size: f32) -> vec2<f32> {
+    return vec2(xz.x, -xz.y) / size + 0.5;
+}
+
+// Textures can't be indexed, so pick the `i`th layer above by hand
+fn sample_above(i: u32, worley_uv: vec2<f32>, value_uv: vec2<f32>) -> vec2<f32> {
+    if i == 0u {
+        return vec2(
+            textureSampleLevel(above_worley_0, above_sampler, worley_uv, 0.0).x,
+            textureSampleLevel(above_value_0, above_sampler, value_uv, 0.0).x
+        );
+    }
+    if i == 1u {
+        return vec2(
+            textureSampleLevel(above_worley_1, above_sampler, worley_uv, 0.0).x,
+            textureSampleLevel(above_value_1, above_sampler, value_uv, 0.0).x
+        );
+    }
+    return vec2(
+        textureSampleLevel(above_worley_2, above_sampler, worley_uv, 0.0).x,
+        textureSampleLevel(above_value_2, above_sampler, value_uv, 0.0).x
+    );
+}
+
+// Density of the `i`th layer above, at the same uv convention as `cloud`
+fn cloud_above(i: u32, p: vec2<f32>) -> f32 {
+    let shape = material.above_shape[i];
+    let samp = sample_above(i, p - environment.time * 0.01, p + environment.time * vec2(0.01, -0.01));
+    let w = samp.x - shape.x;
+    let z = samp.y - shape.y;
+    return z * (1. + w) * shape.z;
+}
+
+// Follow the sun ray up through every layer above and multiply the light each one lets through
+fn above_shadow(world: vec3<f32>, sun_dir: vec3<f32>) -> f32 {
+    if sun_dir.y >= -0.01 {
+        return 1.0;
+    }
+    var transmittance = 1.0;
+    for (var i = 0u; i < min(material.above_count, MAX_LAYERS_ABOVE); i += 1u) {
+        let placement = material.above_placement[i];
+        if placement.w <= 0.0 {
+            continue;
+        }
+        let hit = world.xz - sun_dir.xz * (placement.x / -sun_dir.y);
+        let p = world_uv(hit, placement.y) + placement.z * vec2(0., 1.0);
+        let height = material.above_shape[i].w;
+        let dens = smoothstep(height, height + 0.02, cloud_above(i, p));
+        transmittance *= 1.0 - placement.w * dens;
+    }
+    return transmittance;
+}
 
 @fragment
 fn fragment(
     #import bevy_pbr::mesh_vertex_output
 ) -> @location(0) vec4<f32> {
-    let sun_dir = material.sun_direction  ;
-    var p = uv + material.scroll * vec2(0., 1.0);
+    let sun_dir = environment.sun_direction;
+    var p = world_uv(world_position.xz, material.size) + material.scroll * vec2(0., 1.0);
     let samp = cloud(p);
     let samps = cloud(p + sun_dir.xz * 0.001);
     let sampd = samps - samp;
     var h = samp;
     var sha = vec3(1.0);
     let minh = material.cloud_height ;
-    let dens = smoothstep(minh, minh + 0.02, samp);
+    let distance = length(world_position.xz - view.world_position.xz);
+    let fade = 1.0 - smoothstep(material.fade_start, material.fade_end, distance);
+    let dens = smoothstep(minh, minh + 0.02, samp) * fade;
     var maxh = h;
     var shap = vec3(1.0);
     if dens <= 0.1 {
@@ -81,18 +161,20 @@ fn fragment(
         // sha *= smoothstep(0.2,0.1,dens);
         p *= 4.;
         let pd = (sun_dir.xz * 0.00001 + p);
-        let rd = normalize(world_position.xyz - material.camera_position);
+        let rd = normalize(world_position.xyz - view.world_position);
         let sun = sun_dir * vec3(-1., 1., 1.);
-        let noi = 2.0 - 1.5 * abs(textureSample(v_tex, v_sampler, (p + material.time * 0.02)) * textureSample(v_tex, v_sampler, (p - material.time * 0.02 + vec2(1.123, 1.33123))) - 0.1) ;
-        let noid = 2.0 - 1.5 * abs(textureSample(v_tex, v_sampler, (pd + material.time * 0.02)) * textureSample(v_tex, v_sampler, (pd - material.time * 0.02 + vec2(1.123, 1.33123))) - 0.1) ;
+        let noi = 2.0 - 1.5 * abs(textureSample(v_tex, v_sampler, (p + environment.time * 0.02)) * textureSample(v_tex, v_sampler, (p - environment.time * 0.02 + vec2(1.123, 1.33123))) - 0.1) ;
+        let noid = 2.0 - 1.5 * abs(textureSample(v_tex, v_sampler, (pd + environment.time * 0.02)) * textureSample(v_tex, v_sampler, (pd - environment.time * 0.02 + vec2(1.123, 1.33123))) - 0.1) ;
         let s = (noi - noid) * 1000.;
         let shine = pow(max(0.0, dot(rd, sun) * 0.03 + s.x * 0.5), 2.5) * sha ;
         water = 1000.0 * shine + vec3(0.01, 0.02, 0.1) + 1.5 * vec3(0.06, 0.15, 0.12) * smoothstep(-0.4, 1., -s.x) * max(0., -noi.x + 2.5);
     }
 
 
-    let col = mix(light, water * (1.0 + sha), 1. - dens);
-    let at = exp(-vec3(4.0, 2.0, 1.0) * 0.00015 * (10. + length(world_position.xz)));
-    let ap = exp(-vec3(1.0, 2.0, 4.0) * 0.00015 * (10. + length(world_position.xz)));
-    return vec4(at * col + (1.0 - ap), 1.0);
+    let shadow = above_shadow(world_position.xyz, sun_dir);
+    let col = mix(light, water * (1.0 + sha), (1. - dens) * material.water) * shadow;
+    let alpha = mix(dens, 1.0, material.water);
+    let at = exp(-vec3(4.0, 2.0, 1.0) * 0.00015 * (10. + distance));
+    let ap = exp(-vec3(1.0, 2.0, 4.0) * 0.00015 * (10. + distance));
+    return vec4(at * col + (1.0 - ap) * alpha, alpha);
 }
@@ -1,8 +1,9 @@
+#import bevy_pbr::mesh_view_bindings
+#import resume::environment
+#import resume::phase
+
 struct CustomMaterial {
-    sun_direction: vec3<f32>,
-    camera_position: vec3<f32>,
     scale: vec3<f32>,
-    time: f32,
 };
 
 @group(1) @binding(0)
@@ -12,15 +13,6 @@ var noise_texture: texture_3d<f32>;
 @group(1) @binding(2)
 var noise_sampler: sampler;
 
-
-fn mie(costh: f32) -> f32 {
-    let params = array(9.805233e-06, -6.500000e+01, -5.500000e+01, 8.194068e-01, 1.388198e-01, -8.370334e+01, 7.810083e+00, 2.054747e-03, 2.600563e-02, -4.552125e-12);
-    let p1 = costh + params[3];
-    let expValues: vec4<f32> = exp(vec4(params[1] * costh + params[2], params[5] * p1 * p1, params[6] * costh, params[9] * costh));
-    let expValWeight: vec4<f32> = vec4(params[0], params[4], params[7], params[8]);
-    return dot(expValues, expValWeight) * 0.25;
-}
-
 fn almost_identity(x: f32, m: f32, n: f32) -> f32 {
     if x > m {return x;}
     let a = 2.0 * n - m;
@@ -33,13 +25,13 @@ fn almost_identity(x: f32, m: f32, n: f32) -> f32 {
 fn fragment(
     #import bevy_pbr::mesh_vertex_output
 ) -> @location(0) vec4<f32> {
-    let ray_direction = normalize(world_position.xyz - material.camera_position);
+    let ray_direction = normalize(world_position.xyz - view.world_position);
     var sample_position = world_position.xyz * 0.009   ;
     let normal = normalize(world_normal.xyz);
     let noise = textureSample(noise_texture, noise_sampler, abs(fract(0.12 * sample_position) - 0.5) * 2.).x;
     let dxnoise = textureSample(noise_texture, noise_sampler, abs(fract(0.12 * (sample_position - vec3(0., 10., 0.) - ray_direction * 3.)) - 0.5) * 2.).x;
-    let sun_dir = normalize(material.sun_direction * vec3(-1., -1., 1.));
-    let mie_signal = mie(dot(ray_direction, sun_dir)) ;
+    let sun_dir = normalize(environment.sun_direction * vec3(-1., -1., 1.));
+    let mie_signal = mie(dot(ray_direction, sun_dir)) * MIE_QUARTER_FIT;
     let sun_color = vec3(1.1, 1.1, 1.) ;
     let shadow_color = vec3(1.0,1.1,1.2)*1.4;
     let shallow = abs(dot(ray_direction, normal));
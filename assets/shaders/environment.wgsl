@@ -0,0 +1,34 @@
+#define_import_path resume::environment
+
+// Filled once per frame by `global_environment::GlobalEnvironment`
+struct Environment {
+    // The direction the sunlight travels in
+    sun_direction: vec3<f32>,
+    sun_illuminance: f32,
+    sun_color: vec4<f32>,
+    wind_offset: vec2<f32>,
+    wind_velocity: vec2<f32>,
+    // Alpha is zero when the camera has no fog
+    fog_color: vec4<f32>,
+    // x: 0 none, 1 linear, 2 exponential, 3 exponential squared
+    // y: linear start or density, z: linear end
+    fog_params: vec4<f32>,
+    time: f32,
+};
+
+@group(3) @binding(0)
+var<uniform> environment: Environment;
+
+fn environment_fog(color: vec3<f32>, distance: f32) -> vec3<f32> {
+    let mode = u32(environment.fog_params.x);
+    var amount = 0.0;
+    if mode == 1u {
+        amount = smoothstep(environment.fog_params.y, environment.fog_params.z, distance);
+    } else if mode == 2u {
+        amount = 1.0 - exp(-distance * environment.fog_params.y);
+    } else if mode == 3u {
+        let d = distance * environment.fog_params.y;
+        amount = 1.0 - exp(-d * d);
+    }
+    return mix(color, environment.fog_color.rgb, amount * environment.fog_color.a);
+}
@@ -1,3 +1,4 @@
+#import resume::phase
 
 struct CustomMaterial {
     sun_direction: vec3<f32>,
@@ -19,28 +20,6 @@ fn fast_ne_exp(x: f32) -> f32 {
     return b * b;
 }
 
-fn mie(costh: f32) -> f32 {
-    // This function was optimized to minimize (delta*delta)/reference in order to capture
-    // the low intensity behavior.
-    let params = array(
-        9.805233e-06,
-        -6.500000e+01,
-        -5.500000e+01,
-        8.194068e-01,
-        1.388198e-01,
-        -8.370334e+01,
-        7.810083e+00,
-        2.054747e-03,
-        2.600563e-02,
-        -4.552125e-12
-    );
-
-    let p1 = costh + params[3];
-    let expValues: vec4<f32> = exp(vec4(params[1] * costh + params[2], params[5] * p1 * p1, params[6] * costh, params[9] * costh));
-    let expValWeight: vec4<f32> = vec4(params[0], params[4], params[7], params[8]);
-    return dot(expValues, expValWeight) * 0.25;
-}
-
 fn hash(p: vec3<f32>) -> f32 {
     // replace this by something better {
     var p = fract(p * 0.3183099 + 0.1);
@@ -99,8 +78,6 @@ fn value_fbm(p: vec3<f32>) -> vec4<f32 > {
     return t / 2.7;
 }
 
-
-
 @fragment
 fn fragment(
     #import bevy_pbr::mesh_vertex_output
@@ -111,7 +88,7 @@ fn fragment(
     let nor = normalize(world_normal.xyz);
     let u = textureSample(noise_texture, noise_sampler, uv).xyz;
     // let u = textureSampleBaseClampToEdge(noise_texture,noise_sampler,uv).xy;
-    let me = mie(dot(rd, sun_dir)) + 0.5;
+    let me = mie(dot(rd, sun_dir)) * MIE_QUARTER_FIT + 0.5;
     let dens = uv.x;
     let sha = smoothstep(0.3, 1., u.y);
     var opa = 1.;
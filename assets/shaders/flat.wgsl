@@ -1,3 +1,4 @@
+#import resume::phase
 
 struct CustomMaterial {
     color: vec4<f32>,
@@ -8,39 +9,6 @@ struct CustomMaterial {
     time: f32,
 };
 
-fn rayleigh(costh: f32) -> f32 {
-    return 3.0 / (16.0 * 3.14159265358979323846) * (1.0 + costh * costh);
-}
-
-fn HenyeyGreenstein(g: f32, costh: f32) -> f32 {
-    let pi = 3.1415926535897932384626433;
-    return (1.0 - g * g) / (4.0 * pi * pow(1.0 + g * g - 2.0 * g * costh, 1.5));
-}
-
-
-fn mie(costh: f32) -> f32 {
-    // This function was optimized to minimize (delta*delta)/reference in order to capture
-    // the low intensity behavior.
-    let params = array(
-        9.805233e-06,
-        -6.500000e+01,
-        -5.500000e+01,
-        8.194068e-01,
-        1.388198e-01,
-        -8.370334e+01,
-        7.810083e+00,
-        2.054747e-03,
-        2.600563e-02,
-        -4.552125e-12
-    );
-
-    let p1 = costh + params[3];
-    let expValues: vec4<f32> = exp(vec4(params[1] * costh + params[2], params[5] * p1 * p1, params[6] * costh, params[9] * costh));
-    let expValWeight: vec4<f32> = vec4(params[0], params[4], params[7], params[8]);
-    return dot(expValues, expValWeight) * 0.25;
-}
-
-
 @group(1) @binding(0)
 var<uniform> material: CustomMaterial;
 @group(1) @binding(1)
@@ -104,7 +72,7 @@ fn fragment(
     let distance = distance(ro, world_position.xzy);
     let rd = normalize(world_position.xyz - material.camera_position);
     let sun_dir = normalize(vec3(cos(material.time), .3, sin(material.time)));
-    let mei = mie(dot(rd, sun_dir));
+    let mei = mie(dot(rd, sun_dir)) * MIE_QUARTER_FIT;
     let inv_sca = 1. / material.scale;
     let mo = ro + 0.5 * material.scale;
     var light = vec3(1.);
@@ -0,0 +1,90 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader
+
+// Filled per camera by `god_rays::GodRayUniform`
+struct GodRays {
+    sun_color: vec4<f32>,
+    inverse_view_projection: mat4x4<f32>,
+    // World position to cloud shadow map uv
+    cloud_shadow_projection: mat4x4<f32>,
+    camera_position: vec3<f32>,
+    cloud_altitude: f32,
+    to_sun: vec3<f32>,
+    falloff: f32,
+    sun_uv: vec2<f32>,
+    sun_visibility: f32,
+    intensity: f32,
+    decay: f32,
+    density: f32,
+    samples: u32,
+    near: f32,
+    sky_distance: f32,
+    // 0 when there's no cloud shadow map
+    cloud_occlusion: f32,
+};
+
+@group(0) @binding(0)
+var screen_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var screen_sampler: sampler;
+#ifdef MULTISAMPLED
+@group(0) @binding(2)
+var depth_texture: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(2)
+var depth_texture: texture_depth_2d;
+#endif
+@group(0) @binding(3)
+var<uniform> god_rays: GodRays;
+@group(0) @binding(4)
+var cloud_shadow_texture: texture_2d<f32>;
+
+// The sunlight coming through the pixel at uv, 0 where the scene is in the way
+fn sky_light(uv: vec2<f32>) -> f32 {
+    let size = vec2<f32>(textureDimensions(depth_texture));
+    let texel = vec2<i32>(clamp(uv, vec2(0.0), vec2(1.0)) * (size - 1.0));
+    // Reverse z, 0 is infinitely far away
+    let depth = textureLoad(depth_texture, texel, 0);
+    if depth > 0.0 && god_rays.near / depth < god_rays.sky_distance {
+        return 0.0;
+    }
+
+    let ndc = vec2(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+    let near_point = god_rays.inverse_view_projection * vec4(ndc, 1.0, 1.0);
+    let rd = normalize(near_point.xyz / near_point.w - god_rays.camera_position);
+    // Most of the light comes from right around the sun
+    let glow = pow(max(dot(rd, god_rays.to_sun), 0.0), god_rays.falloff);
+    if god_rays.cloud_occlusion == 0.0 {
+        return glow;
+    }
+
+    // Rays heading away from the cloud layer see clear sky
+    let height = god_rays.cloud_altitude - god_rays.camera_position.y;
+    if rd.y * height <= 0.0 {
+        return glow;
+    }
+    let cloud_point = god_rays.camera_position + rd * height / rd.y;
+    let shadow_uv = (god_rays.cloud_shadow_projection * vec4(cloud_point, 1.0)).xy;
+    let light = textureSampleLevel(cloud_shadow_texture, screen_sampler, shadow_uv, 0.0).r;
+    // Past the edge of the map the clouds are unknown, so let the light through
+    let edge = max(abs(shadow_uv.x - 0.5), abs(shadow_uv.y - 0.5));
+    return glow * mix(light, 1.0, smoothstep(0.45, 0.5, edge));
+}
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(screen_texture, screen_sampler, in.uv);
+
+    // Step towards the sun, each sample counting for a little less than the last
+    let delta = (god_rays.sun_uv - in.uv) * god_rays.density / f32(god_rays.samples);
+    var uv = in.uv;
+    var weight = 1.0;
+    var light = 0.0;
+    for (var i = 0u; i < god_rays.samples; i += 1u) {
+        light += sky_light(uv) * weight;
+        weight *= god_rays.decay;
+        uv += delta;
+    }
+    light *= god_rays.intensity * god_rays.sun_visibility / f32(god_rays.samples);
+
+    return vec4(color.rgb + god_rays.sun_color.rgb * light, color.a);
+}
@@ -0,0 +1,51 @@
+#define_import_path resume::phase
+
+// Mirrors `phase.rs`, every function integrates to 1 over the sphere
+
+// The shaders each had their own `mie` before this module, a quarter of the fit rather
+// than normalised, and their lighting is tuned for that. They scale by this to keep it.
+const MIE_QUARTER_FIT: f32 = 1.1475746;
+
+fn rayleigh(costh: f32) -> f32 {
+    return 3.0 / (16.0 * 3.14159265358979323846) * (1.0 + costh * costh);
+}
+
+fn henyey_greenstein(g: f32, costh: f32) -> f32 {
+    let pi = 3.1415926535897932384626433;
+    return (1.0 - g * g) / (4.0 * pi * pow(1.0 + g * g - 2.0 * g * costh, 1.5));
+}
+
+// forward blends from the g_back lobe at 0 to the g_forward lobe at 1
+fn dual_lobe_hg(g_forward: f32, g_back: f32, forward: f32, costh: f32) -> f32 {
+    return mix(henyey_greenstein(g_back, costh), henyey_greenstein(g_forward, costh), forward);
+}
+
+fn cornette_shanks(g: f32, costh: f32) -> f32 {
+    let pi = 3.1415926535897932384626433;
+    let g2 = g * g;
+    return 3.0 / (8.0 * pi) * (1.0 - g2) * (1.0 + costh * costh)
+        / ((2.0 + g2) * pow(1.0 + g2 - 2.0 * g * costh, 1.5));
+}
+
+fn mie(costh: f32) -> f32 {
+    // This function was optimized to minimize (delta*delta)/reference in order to capture
+    // the low intensity behavior.
+    let params = array(
+        9.805233e-06,
+        -6.500000e+01,
+        -5.500000e+01,
+        8.194068e-01,
+        1.388198e-01,
+        -8.370334e+01,
+        7.810083e+00,
+        2.054747e-03,
+        2.600563e-02,
+        -4.552125e-12
+    );
+
+    let p1 = costh + params[3];
+    let expValues: vec4<f32> = exp(vec4(params[1] * costh + params[2], params[5] * p1 * p1, params[6] * costh, params[9] * costh));
+    let expValWeight: vec4<f32> = vec4(params[0], params[4], params[7], params[8]);
+    // The fit integrates to 4.5903 over the sphere
+    return dot(expValues, expValWeight) / 4.5902985;
+}
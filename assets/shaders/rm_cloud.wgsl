@@ -1,52 +1,34 @@
+#import bevy_pbr::mesh_view_bindings
+#import resume::phase
+
 struct CustomMaterial {
-    color: vec4<f32>,
-    camera_position: vec3<f32>,
-    aabb_position: vec3<f32>,
+    // Towards the sun
+    sun_direction: vec3<f32>,
+    inverse_model: mat4x4<f32>,
     texture_dim: vec3<f32>,
-    scale: vec3<f32>,
+    density: f32,
+    steps: f32,
     time: f32,
+    // 0 shows the light from previous_volume_tex, 1 from volume_tex
+    light_blend: f32,
+    // Of the multiple scattering's phase lobe
+    eccentricity: f32,
+    powder: f32,
 };
 
-fn rayleigh(costh: f32) -> f32 {
-    return 3.0 / (16.0 * 3.14159265358979323846) * (1.0 + costh * costh);
-}
-
-fn HenyeyGreenstein(g: f32, costh: f32) -> f32
-{
-    let pi = 3.1415926535897932384626433;
-    return (1.0 - g * g) / (4.0 * pi * pow(1.0 + g*g - 2.0*g*costh, 1.5));
-}
-
-
-fn mie(costh: f32) -> f32 {
-    // This function was optimized to minimize (delta*delta)/reference in order to capture
-    // the low intensity behavior.
-    let params = array(
-        9.805233e-06,
-        -6.500000e+01,
-        -5.500000e+01,
-        8.194068e-01,
-        1.388198e-01,
-        -8.370334e+01,
-        7.810083e+00,
-        2.054747e-03,
-        2.600563e-02,
-        -4.552125e-12
-    );
-
-    let p1 = costh + params[3];
-    let expValues: vec4<f32> = exp(vec4(params[1] * costh + params[2], params[5] * p1 * p1, params[6] * costh, params[9] * costh));
-    let expValWeight: vec4<f32> = vec4(params[0], params[4], params[7], params[8]);
-    return dot(expValues, expValWeight) * 0.25;
-}
-
-
 @group(1) @binding(0)
 var<uniform> material: CustomMaterial;
 @group(1) @binding(1)
 var volume_tex: texture_3d<f32>;
 @group(1) @binding(2)
 var volume_sampler: sampler;
+@group(1) @binding(3)
+var previous_volume_tex: texture_3d<f32>;
+@group(1) @binding(4)
+var previous_volume_sampler: sampler;
+// With BRICKS, per 8³ voxel brick 0 when it's empty, otherwise its slot in volume_tex plus one
+@group(1) @binding(5)
+var brick_table: texture_3d<u32>;
 
 // @location(0) world_position: vec4<f32>,
 // @location(1) world_normal: vec3<f32>,
@@ -84,8 +66,61 @@ fn hash13(p3: vec3<f32>) -> f32 {
     return fract((p3.x + p3.y) * p3.z);
 }
 
+#ifdef BRICKS
+// The brick holding p, clamped to the volume
+fn brick_of(p: vec3<f32>) -> vec3<i32> {
+    let voxel = p * material.texture_dim - 0.5;
+    let last = vec3<i32>(textureDimensions(brick_table)) - 1;
+    return clamp(vec3<i32>(floor(voxel / 8.0)), vec3(0), last);
+}
+
+// Where p is in the atlas in xyz, w is 0 when p's brick is empty
+fn atlas_uv(p: vec3<f32>) -> vec4<f32> {
+    let brick = brick_of(p);
+    let slot = textureLoad(brick_table, brick, 0).r;
+    if slot == 0u {
+        return vec4(0.0);
+    }
+    // Slots are 10 voxels a side, the brick with a voxel of apron around it
+    let atlas = vec3<f32>(textureDimensions(volume_tex));
+    let slots = vec3<u32>(atlas / 10.0);
+    let s = slot - 1u;
+    let origin = vec3<f32>(vec3(s % slots.x, (s / slots.x) % slots.y, s / (slots.x * slots.y))) * 10.0;
+    let in_brick = clamp(p * material.texture_dim - 0.5 - vec3<f32>(brick) * 8.0, vec3(-0.5), vec3(8.5));
+    return vec4((origin + 1.5 + in_brick) / atlas, 1.0);
+}
+
+// Distance along rd to where p leaves its brick, or -1 if the brick has density
+fn empty_brick_exit(p: vec3<f32>, rd: vec3<f32>) -> f32 {
+    let brick = brick_of(p);
+    if textureLoad(brick_table, brick, 0).r != 0u {
+        return -1.0;
+    }
+    let low = (vec3<f32>(brick) * 8.0 + 0.5) / material.texture_dim;
+    let high = low + 8.0 / material.texture_dim;
+    let t = (select(low, high, rd > vec3(0.0)) - p) / rd;
+    let exit = select(vec3(1e9), t, rd != vec3(0.0));
+    return max(min(min(exit.x, exit.y), exit.z), 0.0);
+}
+#endif
+
 fn sdf(p: vec3<f32>) -> vec4<f32> {
-    return textureSample(volume_tex, volume_sampler, p);
+    var uvw = p;
+#ifdef BRICKS
+    let atlas = atlas_uv(p);
+    if atlas.w == 0.0 {
+        return vec4(0.0);
+    }
+    uvw = atlas.xyz;
+#endif
+    // x: multiple scattering, y: sun transmittance, z: density, w: powder
+    var samp = textureSampleLevel(volume_tex, volume_sampler, uvw, 0.0);
+    if material.light_blend < 1.0 {
+        let previous = textureSampleLevel(previous_volume_tex, previous_volume_sampler, uvw, 0.0);
+        let light = mix(previous, samp, material.light_blend);
+        samp = vec4(light.xy, samp.z, light.w);
+    }
+    return samp;
 }
 
 fn fast_ne_exp(x: f32) -> f32 {
@@ -96,16 +131,6 @@ fn fast_ne_exp(x: f32) -> f32 {
     return g*g;
 }
 
-fn powder(x: f32) -> f32 {
-    let a = x * 0.2 - 1.; // nearly exp(-x)
-    let b = a * a; // 
-    let c = b * b; // Base
-    let d = c * c; // pow 2
-    let e = d * d; // pow 4
-    let f = e * e; // pow 8
-    return d - f*f;
-}
-
 fn tpow(x: f32) -> f32{
 
     return fast_ne_exp(x)*x;
@@ -115,54 +140,53 @@ fn tpow(x: f32) -> f32{
 fn fragment(
     #import bevy_pbr::mesh_vertex_output
 ) -> @location(0) vec4<f32> {
-    let ro = material.camera_position - material.aabb_position;
-    let distance = distance(ro, world_position.xzy);
-    let rd = normalize(world_position.xyz - material.camera_position);
-    let sun_dir = normalize(vec3(1., .3, 1.));
-    let mei = mie(dot(rd, sun_dir));
-    let inv_sca = 1./material.scale;
-    let mo = ro +0.5*material.scale;
-    var p = ro;
-    var dt = 1.0 / abs(rd.y) ;
-    var steps = 0.;
+    let world_rd = normalize(world_position.xyz - view.world_position);
+    // March in box space, rd is left unnormalized so t stays in world units
+    let ro = (material.inverse_model * vec4(view.world_position, 1.0)).xyz;
+    let rd = (material.inverse_model * vec4(world_rd, 0.0)).xyz;
+    let intersection = boxIntersection(ro, rd, vec3(0.5));
+    if intersection.y < 0.0 {
+        discard;
+    }
+    let start = max(intersection.x, 0.0);
+    let dt = (intersection.y - start) / material.steps;
+    let costh = dot(world_rd, normalize(material.sun_direction));
+    let mei = mie(costh) * MIE_QUARTER_FIT;
+    let multiple_phase = henyey_greenstein(material.eccentricity, costh);
+    // Powdered edges show looking away from the sun, looking into it they glow instead
+    let powder_strength = material.powder * (0.5 - 0.5 * costh);
+
     var light = vec3(0.);
-    var absorbtion = 0.;
-    let intersection = boxIntersection(ro, rd, vec3(0.5)*material.scale);
-    var i = max(intersection.x, 0.) + hash13(vec3(uv * 913.123, material.time )) * dt;
-    for (; i < intersection.y; i += dt) {
-        // if absorbtion > 4. {
-        //     absorbtion = 6.;
-        //     break;
-        // }
-        steps += 1.;
-        p = mo + i * rd;
-        let samp = sdf(p*inv_sca );
-        let samp2 = sdf((p+vec3(0.0,-1.0,0.0))*inv_sca );
-        let dd = max(0.,samp2.x - samp.x) * 100.*vec3(1.,0.5,0.25) ;
-        // let d = samp.x - 0.03;
-        // if d < 0.0 {
-            let dm = 0.5;
-            let dens = max(0.0,samp.z - dm);
-        if dens != 0.0 {
-            absorbtion +=  100. * dens ;
-            let transmission = fast_ne_exp(min(absorbtion*0.1,10.)) * dt * 1.0;
-            // let direct = powder(max(0.0,dd*40.1)) * 10.1;
-            // let direct = dd * vec3(1.0,0.8,0.6)*100.;
-            let direct = 1.5 * fast_ne_exp(min(samp.y,10.0))*vec3(1.,0.9,0.8) ;
-            let scater = 10. * (vec3(0.01,0.02,0.03))  ;
-
-            // light += (scater + direct) * transmission ;
-            light += ( direct * sqrt(mei+0.4) + scater  ) * transmission ;
+    var transmittance = 1.0;
+    var i = start + hash13(vec3(uv * 913.123, material.time)) * dt;
+    for (var step = 0.0; step < material.steps; step += 1.0) {
+        let p = ro + rd * i + 0.5;
+#ifdef BRICKS
+        // Jump over empty bricks in whole steps so the jitter stays the same
+        let exit = empty_brick_exit(p, rd);
+        if exit >= 0.0 {
+            let skipped = max(ceil(exit / dt), 1.0);
+            i += skipped * dt;
+            step += skipped - 1.0;
+            continue;
         }
+#endif
+        let samp = sdf(p);
+        let dens = samp.z * material.density;
+        if dens > 0.0 {
+            let absorbed = 1.0 - exp(-dens * dt);
+            let powder = mix(1.0, 2.0 * samp.w, powder_strength);
+            let direct = 1.5 * samp.y * sqrt(mei + 0.4) * vec3(1., 0.9, 0.8);
+            let multiple = 1.5 * samp.x * sqrt(multiple_phase + 0.4) * vec3(1., 0.9, 0.8);
+            let scater = 10. * vec3(0.01, 0.02, 0.03);
+            light += ((direct + multiple) * powder + scater) * absorbed * transmittance;
+            transmittance *= 1.0 - absorbed;
+            if transmittance < 0.01 {
+                break;
+            }
+        }
+        i += dt;
     }
 
-    // return vec4( vec3(mie(dot(sun_dir,rd))),1.);
-    return vec4(light, 1. - exp(-absorbtion));
-    // return vec4(1.0);
-    // return vec4( vec3(steps*0.01),1.);
-    // return vec4(
-    //     vec3(pow(sdf(0.02*(ro+rd*intersection.x)).y*0.00k,100.0)),1.
-    // );
-
-
+    return vec4(light, 1. - transmittance);
 }
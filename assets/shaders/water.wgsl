@@ -1,8 +1,8 @@
+#import bevy_pbr::mesh_view_bindings
+#import resume::environment
+
 struct CustomMaterial {
-    sun_direction: vec3<f32>,
-    camera_position: vec3<f32>,
     scale: vec3<f32>,
-    time: f32,
 };
 
 @group(1) @binding(0)
@@ -14,6 +14,29 @@ var noise_texture: texture_3d<f32>;
 @group(1) @binding(2)
 var noise_sampler: sampler;
 
+struct CloudShadow {
+    projection: mat4x4<f32>,
+    floor: f32,
+};
+
+@group(1) @binding(3)
+var cloud_shadow_texture: texture_2d<f32>;
+@group(1) @binding(4)
+var cloud_shadow_sampler: sampler;
+@group(1) @binding(5)
+var<uniform> cloud_shadow: CloudShadow;
+
+// Same as in cloud_shadowed.wgsl. Without a shadow map the fallback texture is white, so
+// nothing is shadowed
+fn cloud_shadow_factor(world_position: vec3<f32>) -> f32 {
+    let uv = (cloud_shadow.projection * vec4(world_position, 1.0)).xy;
+    let light = textureSampleLevel(cloud_shadow_texture, cloud_shadow_sampler, uv, 0.0).r;
+    // Fade out towards the edge of the map instead of cutting off
+    let edge = max(abs(uv.x - 0.5), abs(uv.y - 0.5));
+    let inside = 1.0 - smoothstep(0.45, 0.5, edge);
+    return mix(1.0, mix(cloud_shadow.floor, 1.0, light), inside);
+}
+
 
 fn hash(p: vec3<f32>) -> f32 {
     // replace this by something better {
@@ -83,10 +106,10 @@ fn fragment(
     #import bevy_pbr::mesh_vertex_output
 ) -> @location(0) vec4<f32> {
     let pos = world_position.xyz;
-    let rd = normalize(world_position.xyz - material.camera_position);
+    let rd = normalize(world_position.xyz - view.world_position);
 
-    let sun_dir = normalize(material.sun_direction * vec3(-1.,-1.,1.));
-    var noi = value_fbm(pos * vec3(0.01,0.01,0.005) + vec3(material.time * .5, material.time * 0.1, 0.)) + value_fbm(pos * vec3(0.005,0.01,0.01) + vec3(0., material.time * 0.3, material.time * 0.4));
+    let sun_dir = normalize(environment.sun_direction * vec3(-1.,-1.,1.));
+    var noi = value_fbm(pos * vec3(0.01,0.01,0.005) + vec3(environment.time * .5, environment.time * 0.1, 0.)) + value_fbm(pos * vec3(0.005,0.01,0.01) + vec3(0., environment.time * 0.3, environment.time * 0.4));
     var nor = normalize(mix(noi.yzw, vec3(0., 1., 0.), 0.6));
     let fre = pow(sqrt(
         0.5+dot(nor,rd)*.5 + .5,
@@ -95,11 +118,13 @@ fn fragment(
     let sdn = dot(nor, sun_dir);
     let deep = vec3(0.075, 0.075, 0.14);
     let shallow = vec3(0.1, 0.5, 0.4);
-    let col = deep + spe(rd, nor, sun_dir) + 0.1 * max(0., sdn) ;
+    // Only the sunlight is shadowed, the deep colour stands in for the sky
+    let sunlit = spe(rd, nor, sun_dir) + 0.1 * max(0., sdn);
+    let col = deep + sunlit * cloud_shadow_factor(pos);
     let opa = smoothstep(5000.,4000.,distance(
-        material.camera_position.xz,
+        view.world_position.xz,
         world_position.xz
     ));
 
-    return vec4(col, 1.);
+    return vec4(environment_fog(col, distance(view.world_position, world_position.xyz)), 1.);
 }
@@ -0,0 +1,124 @@
+//! Times `rm_cloud::new_cloud_data` against the old bake, which kept every voxel behind a
+//! mutex. The old bake stored optical depth rather than transmittance, so it's timed as it
+//! was, without the conversion. The test in `rm_cloud` checks both give the same density and
+//! hard shadows once converted. Run with `--release`.
+
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+use antidote::Mutex;
+use bevy::math::{vec3, vec4, Vec3, Vec4};
+use itertools::Itertools;
+use rayon::prelude::*;
+use resume::noise::{self, fbmd};
+use resume::rm_cloud::{coord_to_pos, new_cloud_data, pos_to_coord, LightBake, LightQuality};
+
+const RUNS: u32 = 3;
+
+fn main() {
+    let noise_scale = vec3(5.0, 1.25, 5.0);
+    let threshold = 0.9;
+    let to_sun = vec3(1.0, 2.0, 0.5);
+    let light = LightBake::from(LightQuality::Hard);
+    for resolution in [[32, 8, 32], [64, 16, 64], [128, 32, 128]] {
+        let old_time = time(|| locked_cloud_data(resolution, noise_scale, threshold, to_sun));
+        let new_time = time(|| new_cloud_data(resolution, noise_scale, threshold, &light, to_sun));
+        let voxels: usize = resolution.iter().product();
+        let rows = resolution[0] * resolution[1];
+        let old_bytes = voxels * size_of::<Mutex<Vec4>>() + rows * size_of::<Vec<Mutex<Vec4>>>();
+        let new_bytes = voxels * (size_of::<Vec4>() + size_of::<f32>());
+        println!(
+            "{resolution:?}: mutex {old_time:?} {} KiB, flat {new_time:?} {} KiB, {:.1}x faster",
+            old_bytes / 1024,
+            new_bytes / 1024,
+            old_time.as_secs_f64() / new_time.as_secs_f64()
+        );
+    }
+}
+
+/// The fastest of `RUNS` runs.
+fn time<T>(mut bake: impl FnMut() -> T) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        std::hint::black_box(bake());
+        best = best.min(start.elapsed());
+    }
+    best
+}
+
+/// The bake as it was before the flat buffer. It stores the raw noise in `x` and the optical
+/// depth towards the sun in `y`.
+fn locked_cloud_data(
+    buffer_dimensions: [usize; 3],
+    noise_scale: Vec3,
+    threshold: f32,
+    to_sun: Vec3,
+) -> Vec<Vec4> {
+    let resolution = vec3(
+        buffer_dimensions[0] as f32,
+        buffer_dimensions[1] as f32,
+        buffer_dimensions[2] as f32,
+    );
+
+    let data = Vec::from_iter((0..buffer_dimensions[0]).map(|_| {
+        (0..buffer_dimensions[1])
+            .map(|_| {
+                (0..buffer_dimensions[2])
+                    .map(|_| Mutex::new(vec4(1., 1., 1., 1.)))
+                    .collect_vec()
+            })
+            .collect_vec()
+    }));
+    (0..buffer_dimensions[0])
+        .flat_map(move |x| {
+            (0..buffer_dimensions[1])
+                .flat_map(move |y| (0..buffer_dimensions[2]).map(move |z| (x, y, z)))
+        })
+        .par_bridge()
+        .for_each(|(x, y, z)| {
+            let p = coord_to_pos([x, y, z], resolution);
+            let n = (noise::wfbm(p * noise_scale, Vec3::ONE * 1000.0)
+                * (2.0 + fbmd(p * noise_scale + 110.123_12).x)
+                * 0.5)
+                .clamp(0.0, 3.0);
+            // Rounds off the bottom and top of the box
+            let height = (1.0 - (-4.0 * (p.y + 1.0)).exp()) * ((-p.y).exp() - 0.37);
+            let density = (n * height - threshold).max(0.0);
+            *data[x][y][z].lock() = vec4(n, 0., density, 0.);
+        });
+
+    // Sun raymarching, one voxel per step in the box's -1..1 space
+    let to_sun = to_sun.normalize();
+    let dt = 2. / resolution.max_element();
+    (0..buffer_dimensions[0])
+        .flat_map(|x| {
+            (0..buffer_dimensions[1])
+                .flat_map(move |y| (0..buffer_dimensions[2]).map(move |z| (x, y, z)))
+        })
+        .par_bridge()
+        .for_each(|(x, y, z)| {
+            let mut depth = 0.;
+            let mut p = coord_to_pos([x, y, z], resolution);
+            let mut sample_point = [x, y, z];
+            while let Some(samp) = data
+                .get(sample_point[0])
+                .and_then(|slice| slice.get(sample_point[1]))
+                .and_then(|row| row.get(sample_point[2]))
+            {
+                if p.x.abs() > 1. || p.y.abs() > 1. || p.z.abs() > 1. {
+                    break;
+                }
+                depth += samp.lock().z * dt;
+                p += to_sun * dt;
+                sample_point = pos_to_coord(p, resolution);
+            }
+            data[x][y][z].lock().y = depth;
+        });
+
+    (0..buffer_dimensions[2])
+        .flat_map(|z| (0..buffer_dimensions[1]).map(move |y| (y, z)))
+        .flat_map(|(y, z)| (0..buffer_dimensions[0]).map(move |x| (x, y, z)))
+        .map(|(x, y, z)| *data[x][y][z].lock())
+        .collect_vec()
+}
@@ -0,0 +1,27 @@
+//! Prints how much memory the sparse brick storage of `bricks` saves over a dense volume at
+//! a few resolutions. The tests in `bricks` check it against the dense bake. Run with
+//! `--release`.
+
+use bevy::math::{vec3, vec4, Vec3};
+use resume::bricks::BrickVolume;
+use resume::rm_cloud::{cloud_density, coord_to_pos};
+
+fn main() {
+    let noise_scale = vec3(5.0, 1.25, 5.0);
+    let threshold = 0.9;
+    for dimensions in [[128, 32, 128], [256, 64, 256], [512, 128, 512]] {
+        let bricks = BrickVolume::bake(dimensions, |coord| {
+            let p = coord_to_pos(coord, resolution(dimensions));
+            vec4(0., 0., cloud_density(p, noise_scale, threshold), 0.)
+        });
+        println!("{dimensions:?}: {}", bricks.memory());
+    }
+}
+
+fn resolution(dimensions: [usize; 3]) -> Vec3 {
+    vec3(
+        dimensions[0] as f32,
+        dimensions[1] as f32,
+        dimensions[2] as f32,
+    )
+}
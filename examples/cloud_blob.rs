@@ -0,0 +1,36 @@
+//! The field of cloud blobs, flown through with WASD, Q/E and right drag.
+
+use bevy::prelude::*;
+use resume::prelude::*;
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins.set(resume::image_plugin()))
+        .add_plugin(CameraRigPlugin)
+        .add_plugin(CloudBlobPlugin::default())
+        .add_startup_system(setup)
+        .run();
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn(DirectionalLightBundle {
+        transform: Transform::IDENTITY.looking_at(Vec3::new(-1., -0.3, 1.), Vec3::Y),
+        ..default()
+    });
+
+    let mut controller = CameraController::default();
+    controller.mode = CameraMode::FreeFly;
+    controller.fly.position = Vec3::new(0.0, 800.0, 6000.0);
+    controller.fly.speed = 500.0;
+    commands.spawn((
+        Camera3dBundle {
+            transform: controller.fly_pose(),
+            projection: Projection::Perspective(PerspectiveProjection {
+                far: 100_000.,
+                ..default()
+            }),
+            ..default()
+        },
+        controller,
+    ));
+}
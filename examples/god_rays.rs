@@ -0,0 +1,57 @@
+//! Light shafts through the ray marched cloud layers, seen from below against a low sun.
+//! Press G to turn the rays on and off. Right drag to look around.
+
+use bevy::{core_pipeline::prepass::DepthPrepass, prelude::*};
+use resume::prelude::*;
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins.set(resume::image_plugin()))
+        .add_plugin(CameraRigPlugin)
+        .add_plugin(RMCloudPlugin::default())
+        .add_plugin(CloudShadowPlugin)
+        .add_plugin(GodRayPlugin)
+        .add_startup_system(setup)
+        .add_system(toggle_god_rays)
+        .run();
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn(DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            color: Color::rgb(2.2, 1.8, 1.4),
+            illuminance: 20_000.0,
+            ..default()
+        },
+        transform: Transform::IDENTITY.looking_at(Vec3::new(-1., -0.35, 1.), Vec3::Y),
+        ..default()
+    });
+
+    let transform =
+        Transform::from_xyz(0.0, 20.0, 0.0).looking_at(Vec3::new(1.0, 0.45, -1.0), Vec3::Y);
+    commands.spawn((
+        Camera3dBundle {
+            transform,
+            projection: Projection::Perspective(PerspectiveProjection {
+                far: 100_000.,
+                ..default()
+            }),
+            camera: Camera {
+                hdr: true,
+                ..default()
+            },
+            ..default()
+        },
+        CameraController::at(transform),
+        DepthPrepass,
+        GodRays::default(),
+    ));
+}
+
+fn toggle_god_rays(keys: Res<Input<KeyCode>>, mut cameras: Query<&mut GodRays>) {
+    if keys.just_pressed(KeyCode::G) {
+        for mut god_rays in &mut cameras {
+            god_rays.enabled = !god_rays.enabled;
+        }
+    }
+}
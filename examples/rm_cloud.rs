@@ -0,0 +1,43 @@
+//! The ray marched cloud layers seen from above, with the default handheld camera.
+
+use bevy::prelude::*;
+use resume::prelude::*;
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins.set(resume::image_plugin()))
+        .add_plugin(CameraRigPlugin)
+        .add_plugin(RMCloudPlugin::default())
+        .add_startup_system(setup)
+        .run();
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn(DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            color: Color::rgb(2.2, 2.05, 1.9),
+            illuminance: 20_000.0,
+            ..default()
+        },
+        transform: Transform::IDENTITY.looking_at(Vec3::new(-1., -0.3, 1.), Vec3::Y),
+        ..default()
+    });
+
+    let transform = Transform::from_xyz(0.0, 600.0, 0.0).with_rotation(Quat::from_euler(
+        EulerRot::XYZ,
+        -1.5,
+        0.0,
+        std::f32::consts::PI,
+    ));
+    commands.spawn((
+        Camera3dBundle {
+            transform,
+            projection: Projection::Perspective(PerspectiveProjection {
+                far: 100_000.,
+                ..default()
+            }),
+            ..default()
+        },
+        CameraController::at(transform),
+    ));
+}
@@ -0,0 +1,32 @@
+//! The skybox on its own, slowly orbited. Right drag to look around.
+
+use bevy::prelude::*;
+use resume::prelude::*;
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins.set(resume::image_plugin()))
+        .add_plugin(CameraRigPlugin)
+        .add_plugin(SkyBoxPlugin {})
+        .add_startup_system(setup)
+        .run();
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn(DirectionalLightBundle {
+        transform: Transform::IDENTITY.looking_at(Vec3::new(-1., -0.3, 1.), Vec3::Y),
+        ..default()
+    });
+
+    let mut controller = CameraController::default();
+    controller.mode = CameraMode::Orbit;
+    controller.orbit.distance = 10.0;
+    controller.orbit.pitch = 0.0;
+    commands.spawn((
+        Camera3dBundle {
+            transform: controller.orbit_pose(),
+            ..default()
+        },
+        controller,
+    ));
+}
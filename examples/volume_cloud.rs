@@ -0,0 +1,57 @@
+//! Two baked volume cloud boxes, orbited by the camera. Right drag to look around. The sun
+//! slowly sets, so the boxes re-bake their lighting as it moves. The second box is stored
+//! as sparse bricks, which lets it bake at a higher resolution.
+
+use bevy::prelude::*;
+use resume::prelude::*;
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins.set(resume::image_plugin()))
+        .add_plugin(CameraRigPlugin)
+        .add_plugin(VolumeCloudPlugin {
+            boxes: vec![
+                VolumeCloudBox::default(),
+                VolumeCloudBox {
+                    transform: Transform::from_xyz(-600.0, 300.0, -400.0)
+                        .with_rotation(Quat::from_rotation_y(0.6))
+                        .with_scale(Vec3::new(600.0, 300.0, 300.0)),
+                    bake: VolumeBake {
+                        resolution: UVec3::new(192, 96, 96),
+                        noise_scale: Vec3::new(3.0, 1.5, 1.5),
+                        sparse: true,
+                        ..default()
+                    },
+                    ..default()
+                },
+            ],
+        })
+        .add_startup_system(setup)
+        .add_system(set_sun)
+        .run();
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn(DirectionalLightBundle {
+        transform: Transform::IDENTITY.looking_at(Vec3::new(-1., -1., 0.), Vec3::Y),
+        ..default()
+    });
+
+    let mut controller = CameraController::default();
+    controller.mode = CameraMode::Orbit;
+    controller.orbit.target = Vec3::new(-200.0, 200.0, 0.0);
+    controller.orbit.distance = 2000.0;
+    commands.spawn((
+        Camera3dBundle {
+            transform: controller.orbit_pose(),
+            ..default()
+        },
+        controller,
+    ));
+}
+
+fn set_sun(time: Res<Time>, mut suns: Query<&mut Transform, With<DirectionalLight>>) {
+    for mut transform in &mut suns {
+        transform.rotate_z(0.05 * time.delta_seconds());
+    }
+}
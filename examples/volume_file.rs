@@ -0,0 +1,31 @@
+//! Writes a baked cloud to both layouts of `volume_file` and prints their sizes. Pass a path
+//! under `assets` to also save the bake there, for a `VolumeCloudBox::file` to load.
+
+use std::fs::File;
+use std::io::BufWriter;
+
+use bevy::math::vec3;
+use resume::rm_cloud::bake_density;
+use resume::volume_file::DensityGrid;
+
+fn main() {
+    let dimensions = [100, 30, 90];
+    let volume = bake_density(dimensions, vec3(5.0, 1.25, 5.0), 0.9);
+    let grid = DensityGrid::from_volume(dimensions, &volume);
+
+    let mut dense = vec![];
+    grid.write_dense(&mut dense).unwrap();
+    let mut leaves = vec![];
+    grid.write_leaves(&mut leaves).unwrap();
+    println!(
+        "{dimensions:?}: dense {} KiB, leaves {} KiB",
+        dense.len() / 1024,
+        leaves.len() / 1024
+    );
+
+    if let Some(path) = std::env::args().nth(1) {
+        let file = File::create(std::path::Path::new("assets").join(&path)).unwrap();
+        grid.write_leaves(BufWriter::new(file)).unwrap();
+        println!("saved assets/{path}");
+    }
+}
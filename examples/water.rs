@@ -0,0 +1,38 @@
+//! The water plane under a low sun.
+
+use bevy::prelude::*;
+use resume::prelude::*;
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins.set(resume::image_plugin()))
+        .add_plugin(CameraRigPlugin)
+        .add_plugin(WaterPlugin::default())
+        .add_startup_system(setup)
+        .run();
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn(DirectionalLightBundle {
+        transform: Transform::IDENTITY.looking_at(Vec3::new(-1., -0.1, 0.4), Vec3::Y),
+        ..default()
+    });
+
+    let transform = Transform::from_xyz(0.0, 40.0, 0.0).with_rotation(Quat::from_euler(
+        EulerRot::XYZ,
+        -0.1,
+        0.0,
+        0.0,
+    ));
+    commands.spawn((
+        Camera3dBundle {
+            transform,
+            projection: Projection::Perspective(PerspectiveProjection {
+                far: 100_000.,
+                ..default()
+            }),
+            ..default()
+        },
+        CameraController::at(transform),
+    ));
+}
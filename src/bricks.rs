@@ -0,0 +1,379 @@
+//! Sparse storage for baked cloud volumes. The volume is cut into bricks of `BRICK_SIZE`³
+//! voxels and only the bricks with some density in them are kept, packed one after another
+//! into an atlas. A table with an entry per brick holds 0 for an empty brick, otherwise the
+//! brick's slot in the atlas plus one.
+//!
+//! Bricks sit in the atlas with an apron of one voxel copied from their neighbours, so
+//! linear filtering across a brick's edge blends into the next brick like the dense texture
+//! does. Next to an empty brick the apron has no density but keeps the edge's light.
+
+use std::fmt;
+
+use bevy::{math::vec4, prelude::*};
+use rayon::prelude::*;
+
+use crate::rm_cloud::{dimensions_f32, received_light, voxel_index, LightBake};
+
+pub const BRICK_SIZE: usize = 8;
+/// A brick along with its apron, as it's laid out in the atlas.
+pub const PADDED_BRICK_SIZE: usize = BRICK_SIZE + 2;
+const BRICK_VOXELS: usize = BRICK_SIZE * BRICK_SIZE * BRICK_SIZE;
+
+#[derive(Clone, Debug)]
+pub struct BrickVolume {
+    /// Voxels along each axis.
+    pub dimensions: [usize; 3],
+    /// Bricks along each axis, the last ones can hang over the edge of the volume.
+    pub bricks: [usize; 3],
+    /// Per brick in `voxel_index` order, 0 when empty, otherwise its slot plus one.
+    pub table: Vec<u32>,
+    /// The brick each slot holds.
+    pub slots: Vec<[usize; 3]>,
+    /// `BRICK_SIZE`³ voxels per slot, x varying fastest. Voxels past the edge of the volume
+    /// are left at zero.
+    pub voxels: Vec<Vec4>,
+}
+
+/// Bytes taken by a volume stored as bricks and as a dense texture.
+#[derive(Clone, Copy, Debug)]
+pub struct BrickMemory {
+    pub bricks: usize,
+    pub occupied: usize,
+    pub atlas_bytes: usize,
+    pub table_bytes: usize,
+    pub dense_bytes: usize,
+}
+
+impl BrickMemory {
+    pub fn sparse_bytes(&self) -> usize {
+        self.atlas_bytes + self.table_bytes
+    }
+}
+
+impl fmt::Display for BrickMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} bricks occupied, sparse {} KiB (atlas {} KiB, table {} KiB), dense {} KiB, {:.1}%",
+            self.occupied,
+            self.bricks,
+            self.sparse_bytes() / 1024,
+            self.atlas_bytes / 1024,
+            self.table_bytes / 1024,
+            self.dense_bytes / 1024,
+            100.0 * self.sparse_bytes() as f64 / self.dense_bytes as f64
+        )
+    }
+}
+
+/// The brick holding `voxel`.
+pub fn brick_of(voxel: [usize; 3]) -> [usize; 3] {
+    voxel.map(|v| v / BRICK_SIZE)
+}
+
+/// Index of `voxel` within its brick's `BRICK_SIZE`³ voxels.
+pub fn index_in_brick(voxel: [usize; 3]) -> usize {
+    let [x, y, z] = voxel.map(|v| v % BRICK_SIZE);
+    (z * BRICK_SIZE + y) * BRICK_SIZE + x
+}
+
+/// The voxel at `index_in_brick` of `brick`, the inverse of `index_in_brick`.
+pub fn brick_voxel(brick: [usize; 3], index_in_brick: usize) -> [usize; 3] {
+    let offset = [
+        index_in_brick % BRICK_SIZE,
+        (index_in_brick / BRICK_SIZE) % BRICK_SIZE,
+        index_in_brick / (BRICK_SIZE * BRICK_SIZE),
+    ];
+    [0, 1, 2].map(|axis| brick[axis] * BRICK_SIZE + offset[axis])
+}
+
+/// Slots along each axis of an atlas holding `occupied` bricks, as close to a cube as fits.
+pub fn atlas_slots(occupied: usize) -> [usize; 3] {
+    let occupied = occupied.max(1);
+    let side = (occupied as f64).cbrt().ceil() as usize;
+    [side, side, occupied.div_ceil(side * side)]
+}
+
+/// The first voxel of `slot`'s apron in an atlas laid out by `atlas_slots`.
+pub fn atlas_origin(slot: usize, slots: [usize; 3]) -> [usize; 3] {
+    [
+        slot % slots[0],
+        (slot / slots[0]) % slots[1],
+        slot / (slots[0] * slots[1]),
+    ]
+    .map(|s| s * PADDED_BRICK_SIZE)
+}
+
+impl BrickVolume {
+    /// Bakes `voxel` for every voxel of `dimensions`, keeping the bricks where any voxel has
+    /// density in `z`.
+    pub fn bake(dimensions: [usize; 3], voxel: impl Fn([usize; 3]) -> Vec4 + Sync) -> Self {
+        let bricks = dimensions.map(|d| d.div_ceil(BRICK_SIZE));
+        let baked = (0..bricks.iter().product::<usize>())
+            .into_par_iter()
+            .map(|index| {
+                let brick = [
+                    index % bricks[0],
+                    (index / bricks[0]) % bricks[1],
+                    index / (bricks[0] * bricks[1]),
+                ];
+                let mut voxels = vec![Vec4::ZERO; BRICK_VOXELS];
+                for (i, value) in voxels.iter_mut().enumerate() {
+                    let coord = brick_voxel(brick, i);
+                    if voxel_index(coord, dimensions).is_some() {
+                        *value = voxel(coord);
+                    }
+                }
+                voxels
+                    .iter()
+                    .any(|voxel| voxel.z > 0.0)
+                    .then_some((brick, voxels))
+            })
+            .collect::<Vec<_>>();
+
+        let mut volume = Self {
+            dimensions,
+            bricks,
+            table: vec![0; baked.len()],
+            slots: vec![],
+            voxels: vec![],
+        };
+        for (index, (brick, voxels)) in baked
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, brick)| Some((index, brick?)))
+        {
+            volume.slots.push(brick);
+            volume.table[index] = volume.slots.len() as u32;
+            volume.voxels.extend(voxels);
+        }
+        volume
+    }
+
+    pub fn occupied(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// The atlas slot of `brick`, `None` when it's empty or outside the volume.
+    pub fn slot(&self, brick: [usize; 3]) -> Option<usize> {
+        let index = voxel_index(brick, self.bricks)?;
+        (self.table[index] > 0).then(|| self.table[index] as usize - 1)
+    }
+
+    /// The voxel at `coord`, zero in empty bricks and outside the volume.
+    pub fn get(&self, coord: [usize; 3]) -> Vec4 {
+        voxel_index(coord, self.dimensions)
+            .and_then(|_| self.slot(brick_of(coord)))
+            .map_or(Vec4::ZERO, |slot| {
+                self.voxels[slot * BRICK_VOXELS + index_in_brick(coord)]
+            })
+    }
+
+    /// `bake_light` for the occupied bricks only. The empty ones have no density to light,
+    /// and the rays crossing them add no optical depth.
+    pub fn bake_light(&mut self, light: &LightBake, to_sun: Vec3) {
+        let directions = light.directions(to_sun);
+        let dt = 2. / dimensions_f32(self.dimensions).max_element();
+        let received = self
+            .slots
+            .par_iter()
+            .flat_map_iter(|&brick| (0..BRICK_VOXELS).map(move |i| brick_voxel(brick, i)))
+            .map(|coord| {
+                if voxel_index(coord, self.dimensions).is_none() {
+                    return Vec3::ZERO;
+                }
+                received_light(coord, self.dimensions, &directions, dt, light, |c| {
+                    self.get(c).z
+                })
+            })
+            .collect::<Vec<_>>();
+        for (voxel, sum) in self.voxels.iter_mut().zip(received) {
+            let [single, multiple, powder] = sum.to_array();
+            (voxel.x, voxel.y, voxel.w) = (multiple, single, powder);
+        }
+    }
+
+    /// The bricks with their aprons packed into a volume laid out by `atlas_slots`, and its
+    /// size in voxels.
+    pub fn atlas(&self) -> (UVec3, Vec<Vec4>) {
+        let slots = atlas_slots(self.occupied());
+        let size = slots.map(|s| s * PADDED_BRICK_SIZE);
+        let mut atlas = vec![Vec4::ZERO; size.iter().product()];
+        let last = self.dimensions.map(|d| d as isize - 1);
+
+        // One row of x per chunk
+        atlas
+            .par_chunks_mut(size[0])
+            .enumerate()
+            .for_each(|(row, voxels)| {
+                let (y, z) = (row % size[1], row / size[1]);
+                for (x, voxel) in voxels.iter_mut().enumerate() {
+                    let atlas_coord = [x, y, z];
+                    let slot_coord = atlas_coord.map(|c| c / PADDED_BRICK_SIZE);
+                    let slot =
+                        (slot_coord[2] * slots[1] + slot_coord[1]) * slots[0] + slot_coord[0];
+                    let Some(&brick) = self.slots.get(slot) else {
+                        continue;
+                    };
+                    // -1 and BRICK_SIZE are the apron
+                    let offset = atlas_coord.map(|c| (c % PADDED_BRICK_SIZE) as isize - 1);
+                    let source = [0, 1, 2].map(|axis| {
+                        (brick[axis] as isize * BRICK_SIZE as isize + offset[axis])
+                            .clamp(0, last[axis]) as usize
+                    });
+                    *voxel = if self.slot(brick_of(source)).is_some() {
+                        self.get(source)
+                    } else {
+                        // Next to an empty brick, keep the light of the nearest voxel inside
+                        let inside = [0, 1, 2].map(|axis| {
+                            let first = brick[axis] * BRICK_SIZE;
+                            source[axis].clamp(first, first + BRICK_SIZE - 1)
+                        });
+                        let edge = self.get(inside);
+                        vec4(edge.x, edge.y, 0.0, edge.w)
+                    };
+                }
+            });
+        (UVec3::from(size.map(|s| s as u32)), atlas)
+    }
+
+    pub fn memory(&self) -> BrickMemory {
+        let voxel_bytes = std::mem::size_of::<Vec4>();
+        let atlas_voxels: usize = atlas_slots(self.occupied())
+            .map(|s| s * PADDED_BRICK_SIZE)
+            .iter()
+            .product();
+        BrickMemory {
+            bricks: self.table.len(),
+            occupied: self.occupied(),
+            atlas_bytes: atlas_voxels * voxel_bytes,
+            table_bytes: self.table.len() * std::mem::size_of::<u32>(),
+            dense_bytes: self.dimensions.iter().product::<usize>() * voxel_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use bevy::math::vec3;
+
+    use super::*;
+    use crate::rm_cloud::{bake_density, bake_light, cloud_density, coord_to_pos, LightQuality};
+
+    // Not a multiple of the brick size, so the last bricks hang over the edge
+    const DIMENSIONS: [usize; 3] = [28, 12, 20];
+    const NOISE_SCALE: Vec3 = Vec3::new(5.0, 1.25, 5.0);
+    const THRESHOLD: f32 = 0.9;
+
+    fn baked_bricks() -> BrickVolume {
+        let resolution = dimensions_f32(DIMENSIONS);
+        let mut bricks = BrickVolume::bake(DIMENSIONS, |coord| {
+            let p = coord_to_pos(coord, resolution);
+            vec4(0., 0., cloud_density(p, NOISE_SCALE, THRESHOLD), 0.)
+        });
+        bricks.bake_light(&LightBake::from(LightQuality::Low), vec3(1.0, 2.0, 0.5));
+        bricks
+    }
+
+    /// Every voxel maps to one brick and index and back, and no two voxels share them.
+    #[test]
+    fn addressing() {
+        let mut seen = HashSet::new();
+        for z in 0..DIMENSIONS[2] {
+            for y in 0..DIMENSIONS[1] {
+                for x in 0..DIMENSIONS[0] {
+                    let coord = [x, y, z];
+                    let (brick, index) = (brick_of(coord), index_in_brick(coord));
+                    assert!(index < BRICK_VOXELS);
+                    assert_eq!(brick_voxel(brick, index), coord);
+                    assert!(seen.insert((brick, index)), "{coord:?} shares its address");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn atlas_slots_fit() {
+        for occupied in [1, 7, 8, 9, 100, 1000] {
+            let slots = atlas_slots(occupied);
+            assert!(slots.iter().product::<usize>() >= occupied);
+            let size = slots.map(|s| s * PADDED_BRICK_SIZE);
+            let origins = (0..occupied)
+                .map(|slot| atlas_origin(slot, slots))
+                .collect::<HashSet<_>>();
+            assert_eq!(origins.len(), occupied, "slots share an origin");
+            assert!(origins
+                .iter()
+                .all(|origin| (0..3).all(|axis| origin[axis] + PADDED_BRICK_SIZE <= size[axis])));
+        }
+    }
+
+    /// The bricks hold the same density and light as the dense bake.
+    #[test]
+    fn matches_dense_bake() {
+        let mut dense = bake_density(DIMENSIONS, NOISE_SCALE, THRESHOLD);
+        bake_light(
+            &mut dense,
+            DIMENSIONS,
+            &LightBake::from(LightQuality::Low),
+            vec3(1.0, 2.0, 0.5),
+            1,
+        );
+        let bricks = baked_bricks();
+        assert!(
+            bricks.occupied() > 0 && bricks.occupied() < bricks.table.len(),
+            "expected some empty and some occupied bricks"
+        );
+        for (index, voxel) in dense.iter().enumerate() {
+            let coord = [
+                index % DIMENSIONS[0],
+                (index / DIMENSIONS[0]) % DIMENSIONS[1],
+                index / (DIMENSIONS[0] * DIMENSIONS[1]),
+            ];
+            if bricks.slot(brick_of(coord)).is_some() {
+                assert_eq!(
+                    bricks.get(coord).to_array().map(f32::to_bits),
+                    voxel.to_array().map(f32::to_bits),
+                    "voxel {coord:?} differs from the dense bake"
+                );
+            } else {
+                assert_eq!(voxel.z, 0.0, "empty brick at {coord:?} has density");
+            }
+        }
+    }
+
+    /// Each slot of the atlas holds its brick's voxels, and an apron matching the neighbours
+    /// that have density.
+    #[test]
+    fn atlas_holds_bricks_and_aprons() {
+        let bricks = baked_bricks();
+        let (size, atlas) = bricks.atlas();
+        let slots = atlas_slots(bricks.occupied());
+        let size = [size.x as usize, size.y as usize, size.z as usize];
+        let last = bricks.dimensions.map(|d| d as isize - 1);
+        for (slot, &brick) in bricks.slots.iter().enumerate() {
+            assert_eq!(bricks.slot(brick), Some(slot));
+            let origin = atlas_origin(slot, slots);
+            for index in 0..PADDED_BRICK_SIZE.pow(3) {
+                let offset = [
+                    index % PADDED_BRICK_SIZE,
+                    (index / PADDED_BRICK_SIZE) % PADDED_BRICK_SIZE,
+                    index / (PADDED_BRICK_SIZE * PADDED_BRICK_SIZE),
+                ];
+                let atlas_coord = [0, 1, 2].map(|axis| origin[axis] + offset[axis]);
+                let value = atlas[voxel_index(atlas_coord, size).unwrap()];
+                let source = [0, 1, 2].map(|axis| {
+                    (brick[axis] as isize * BRICK_SIZE as isize + offset[axis] as isize - 1)
+                        .clamp(0, last[axis]) as usize
+                });
+                if bricks.slot(brick_of(source)).is_some() {
+                    assert_eq!(value, bricks.get(source), "atlas differs at {source:?}");
+                } else {
+                    assert_eq!(value.z, 0.0, "apron next to an empty brick has density");
+                }
+            }
+        }
+    }
+}
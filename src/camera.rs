@@ -1,31 +1,421 @@
 use std::f32::consts::{E, PI};
 
-use bevy::{math::vec3, prelude::*};
+use bevy::{input::mouse::MouseMotion, math::vec3, prelude::*};
 
+use crate::camera_path::follow_camera_path;
+use crate::easing::Easing;
 use crate::noise::value_noise;
+use crate::timeline::apply_timeline;
 
-#[derive(Component)]
-pub struct CameraController {}
+/*
+The camera rig has a few modes that each work out where the camera wants to
+be. Other systems only ever write to the rig (the timeline moves the handheld
+anchor, a path follower writes `path_pose`) and `camera_controller` turns the
+active mode into the actual transform, easing from the old pose whenever the
+mode changes.
+
+Shake works on trauma: anything can send a `CameraShake` event to add some,
+it drains away over time, and the offsets scale with trauma squared so small
+knocks stay subtle while big ones really rattle.
+*/
+
+pub struct CameraRigPlugin;
+impl Plugin for CameraRigPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<CameraController>();
+        app.register_type::<CameraMode>();
+        app.register_type::<HandheldRig>();
+        app.register_type::<OrbitRig>();
+        app.register_type::<FlyRig>();
+        app.register_type::<ShakeRig>();
+        app.register_type::<MotionSettings>();
+        app.init_resource::<MotionSettings>();
+        app.add_event::<CameraShake>();
+        app.add_system(add_trauma);
+        app.add_system(cycle_camera_mode);
+        app.add_system(rig_input.after(cycle_camera_mode));
+        app.add_system(
+            camera_controller
+                .after(rig_input)
+                .after(add_trauma)
+                .after(apply_timeline)
+                .after(follow_camera_path),
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect, FromReflect)]
+pub enum CameraMode {
+    /// Sits wherever the timeline puts it with a gentle noise sway.
+    #[default]
+    Handheld,
+    /// Circles `OrbitRig::target`, right drag to look around.
+    Orbit,
+    /// WASD to move, Q/E for down/up, shift to go faster, right drag to look.
+    FreeFly,
+    /// Follows the `CameraPathFollower` on the same entity.
+    PathFollow,
+}
+
+impl CameraMode {
+    pub fn next(self) -> Self {
+        match self {
+            CameraMode::Handheld => CameraMode::Orbit,
+            CameraMode::Orbit => CameraMode::FreeFly,
+            CameraMode::FreeFly => CameraMode::PathFollow,
+            CameraMode::PathFollow => CameraMode::Handheld,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Reflect, FromReflect)]
+pub struct HandheldRig {
+    pub position: Vec3,
+    /// The rotation the noise sway is applied on top of.
+    pub base_rotation: Quat,
+    /// Largest sway angle in radians.
+    pub amplitude: f32,
+    /// How quickly the sway wanders, in noise cells per second.
+    pub frequency: f32,
+}
+
+impl Default for HandheldRig {
+    fn default() -> Self {
+        Self {
+            position: vec3(0.0, 600.0, 0.0),
+            base_rotation: Quat::from_euler(EulerRot::XYZ, -1.5, 0.0, PI),
+            amplitude: 0.2,
+            frequency: 0.1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Reflect, FromReflect)]
+pub struct OrbitRig {
+    pub target: Vec3,
+    pub distance: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+    /// Radians per second the orbit turns on its own.
+    pub auto_rotate: f32,
+    /// Radians per pixel of mouse drag.
+    pub sensitivity: f32,
+}
+
+impl Default for OrbitRig {
+    fn default() -> Self {
+        Self {
+            target: Vec3::ZERO,
+            distance: 800.0,
+            yaw: 0.0,
+            pitch: -0.6,
+            auto_rotate: 0.05,
+            sensitivity: 0.005,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Reflect, FromReflect)]
+pub struct FlyRig {
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    /// Units per second, shift moves five times faster.
+    pub speed: f32,
+    /// Radians per pixel of mouse drag.
+    pub sensitivity: f32,
+}
+
+impl Default for FlyRig {
+    fn default() -> Self {
+        Self {
+            position: vec3(0.0, 600.0, 0.0),
+            yaw: 0.0,
+            pitch: 0.0,
+            speed: 100.0,
+            sensitivity: 0.003,
+        }
+    }
+}
+
+/// Adds `trauma` to every camera rig, the total is clamped to `0..=1`.
+pub struct CameraShake {
+    pub trauma: f32,
+}
+
+#[derive(Resource, Reflect, Default)]
+#[reflect(Resource)]
+pub struct MotionSettings {
+    /// Turns off shake and handheld sway.
+    pub reduced_motion: bool,
+}
+
+#[derive(Clone, Debug, Reflect, FromReflect)]
+pub struct ShakeRig {
+    pub trauma: f32,
+    /// Trauma lost per second.
+    pub decay: f32,
+    /// Largest offset along each local axis, in world units.
+    pub translation: Vec3,
+    /// Largest rotation about each local axis, in radians.
+    pub rotation: Vec3,
+    /// How quickly the shake changes direction, in noise cells per second.
+    pub frequency: f32,
+}
+
+impl Default for ShakeRig {
+    fn default() -> Self {
+        Self {
+            trauma: 0.0,
+            decay: 0.8,
+            translation: vec3(3.0, 3.0, 1.5),
+            rotation: vec3(0.04, 0.04, 0.08),
+            frequency: 12.0,
+        }
+    }
+}
+
+impl ShakeRig {
+    /// Local space offset to add on top of a pose.
+    pub fn offset(&self, elapsed: f32) -> Transform {
+        let amount = self.trauma * self.trauma;
+        // Each channel reads its own stretch of noise so they move independently
+        let channel = |seed: f32| {
+            (value_noise(vec3(elapsed * self.frequency, seed, seed * E)) - 0.5) * 2.0 * amount
+        };
+        let translation = vec3(channel(11.0), channel(23.0), channel(37.0)) * self.translation;
+        let rotation = vec3(channel(41.0), channel(53.0), channel(67.0)) * self.rotation;
+        Transform::from_translation(translation).with_rotation(Quat::from_euler(
+            EulerRot::XYZ,
+            rotation.x,
+            rotation.y,
+            rotation.z,
+        ))
+    }
+}
+
+#[derive(Component, Reflect)]
+#[reflect(Component)]
+pub struct CameraController {
+    /// Change this, from code, the inspector or with Tab, to switch rig.
+    pub mode: CameraMode,
+    pub handheld: HandheldRig,
+    pub orbit: OrbitRig,
+    pub fly: FlyRig,
+    /// Written by `CameraPathFollower`.
+    pub path_pose: Transform,
+    /// Seconds taken to ease between modes.
+    pub blend_time: f32,
+    pub blend_easing: Easing,
+    pub shake: ShakeRig,
+    #[reflect(ignore)]
+    active_mode: CameraMode,
+    #[reflect(ignore)]
+    blend: Option<(Transform, f32)>,
+}
 
 impl Default for CameraController {
     fn default() -> Self {
-        Self {}
+        let handheld = HandheldRig::default();
+        Self {
+            mode: CameraMode::default(),
+            path_pose: Transform::from_translation(handheld.position)
+                .with_rotation(handheld.base_rotation),
+            handheld,
+            orbit: OrbitRig::default(),
+            fly: FlyRig::default(),
+            blend_time: 1.5,
+            blend_easing: Easing::SmoothStep,
+            shake: ShakeRig::default(),
+            active_mode: CameraMode::default(),
+            blend: None,
+        }
+    }
+}
+
+impl CameraController {
+    /// A handheld rig anchored at `transform`.
+    pub fn at(transform: Transform) -> Self {
+        Self {
+            handheld: HandheldRig {
+                position: transform.translation,
+                base_rotation: transform.rotation,
+                ..default()
+            },
+            path_pose: transform,
+            ..default()
+        }
+    }
+
+    pub fn handheld_pose(&self, elapsed: f32) -> Transform {
+        let HandheldRig {
+            position,
+            base_rotation,
+            amplitude,
+            frequency,
+        } = self.handheld;
+        let t = elapsed;
+        let sway = |p: Vec3| (value_noise(p * frequency) - 0.5) * amplitude;
+        Transform::from_translation(position).with_rotation(
+            base_rotation
+                * Quat::from_euler(
+                    EulerRot::XYZ,
+                    sway(vec3(t, t * PI, t * E)),
+                    sway(vec3(t * E, t, t * PI)),
+                    sway(vec3(t * PI, t * E, t)),
+                ),
+        )
+    }
+
+    pub fn orbit_pose(&self) -> Transform {
+        let orbit = &self.orbit;
+        let offset =
+            Quat::from_euler(EulerRot::YXZ, orbit.yaw, orbit.pitch, 0.0) * Vec3::Z * orbit.distance;
+        Transform::from_translation(orbit.target + offset).looking_at(orbit.target, Vec3::Y)
+    }
+
+    pub fn fly_pose(&self) -> Transform {
+        Transform::from_translation(self.fly.position).with_rotation(Quat::from_euler(
+            EulerRot::YXZ,
+            self.fly.yaw,
+            self.fly.pitch,
+            0.0,
+        ))
+    }
+}
+
+fn add_trauma(
+    time: Res<Time>,
+    mut shakes: EventReader<CameraShake>,
+    mut query: Query<&mut CameraController>,
+) {
+    let trauma = shakes.iter().map(|shake| shake.trauma).sum::<f32>();
+    let dt = time.delta_seconds();
+    for mut controller in query.iter_mut() {
+        let shake = &mut controller.shake;
+        shake.trauma = (shake.trauma - shake.decay * dt + trauma).clamp(0.0, 1.0);
+    }
+}
+
+fn cycle_camera_mode(keys: Res<Input<KeyCode>>, mut query: Query<&mut CameraController>) {
+    if keys.just_pressed(KeyCode::Tab) {
+        for mut controller in query.iter_mut() {
+            controller.mode = controller.mode.next();
+        }
+    }
+}
+
+fn rig_input(
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    mouse: Res<Input<MouseButton>>,
+    mut motion: EventReader<MouseMotion>,
+    mut query: Query<&mut CameraController>,
+) {
+    let dt = time.delta_seconds();
+    let look = if mouse.pressed(MouseButton::Right) {
+        motion.iter().map(|m| m.delta).sum::<Vec2>()
+    } else {
+        motion.clear();
+        Vec2::ZERO
+    };
+
+    for mut controller in query.iter_mut() {
+        match controller.mode {
+            CameraMode::Orbit => {
+                let orbit = &mut controller.orbit;
+                orbit.yaw += orbit.auto_rotate * dt - look.x * orbit.sensitivity;
+                orbit.pitch = (orbit.pitch - look.y * orbit.sensitivity).clamp(-1.5, 1.5);
+            }
+            CameraMode::FreeFly => {
+                let fly = &mut controller.fly;
+                fly.yaw -= look.x * fly.sensitivity;
+                fly.pitch = (fly.pitch - look.y * fly.sensitivity).clamp(-1.5, 1.5);
+
+                let rotation = Quat::from_euler(EulerRot::YXZ, fly.yaw, 0.0, 0.0);
+                let mut direction = Vec3::ZERO;
+                for (key, dir) in [
+                    (KeyCode::W, Vec3::NEG_Z),
+                    (KeyCode::S, Vec3::Z),
+                    (KeyCode::A, Vec3::NEG_X),
+                    (KeyCode::D, Vec3::X),
+                ] {
+                    if keys.pressed(key) {
+                        direction += rotation * dir;
+                    }
+                }
+                if keys.pressed(KeyCode::E) {
+                    direction += Vec3::Y;
+                }
+                if keys.pressed(KeyCode::Q) {
+                    direction -= Vec3::Y;
+                }
+                let boost = if keys.pressed(KeyCode::LShift) {
+                    5.0
+                } else {
+                    1.0
+                };
+                fly.position += direction.normalize_or_zero() * fly.speed * boost * dt;
+            }
+            CameraMode::Handheld | CameraMode::PathFollow => {}
+        }
     }
 }
 
-#[allow(dead_code)]
 pub fn camera_controller(
     time: Res<Time>,
-    mut query: Query<&mut Transform, With<CameraController>>,
+    motion: Res<MotionSettings>,
+    mut query: Query<(&mut Transform, &mut CameraController)>,
 ) {
-    let dt = time.elapsed_seconds();
+    let elapsed = time.elapsed_seconds();
+    let dt = time.delta_seconds();
 
-    if let Ok(mut transform) = query.get_single_mut() {
-        transform.rotation = Quat::from_euler(
-            EulerRot::XYZ,
-            (value_noise(vec3(dt, dt * PI, dt * E) * 0.1) - 0.5) * 0.2 - 1.5,
-            (value_noise(vec3(dt * E, dt, dt * PI) * 0.1) - 0.5) * 0.2,
-            (value_noise(vec3(dt * PI, dt * E, dt) * 0.1) - 0.5) * 0.2 + PI,
-        );
+    for (mut transform, mut controller) in query.iter_mut() {
+        let controller = controller.as_mut();
+        if controller.mode != controller.active_mode {
+            // Pick up free fly from wherever the camera is so it doesn't jump
+            if controller.mode == CameraMode::FreeFly {
+                let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+                controller.fly.position = transform.translation;
+                controller.fly.yaw = yaw;
+                controller.fly.pitch = pitch;
+            }
+            controller.blend = Some((*transform, 0.0));
+            controller.active_mode = controller.mode;
+        }
+
+        let pose = match controller.mode {
+            CameraMode::Handheld if motion.reduced_motion => {
+                Transform::from_translation(controller.handheld.position)
+                    .with_rotation(controller.handheld.base_rotation)
+            }
+            CameraMode::Handheld => controller.handheld_pose(elapsed),
+            CameraMode::Orbit => controller.orbit_pose(),
+            CameraMode::FreeFly => controller.fly_pose(),
+            CameraMode::PathFollow => controller.path_pose,
+        };
+
+        *transform = match controller.blend.as_mut() {
+            Some((from, blended)) => {
+                *blended += dt / controller.blend_time.max(f32::EPSILON);
+                let s = controller.blend_easing.apply(*blended);
+                Transform {
+                    translation: from.translation.lerp(pose.translation, s),
+                    rotation: from.rotation.slerp(pose.rotation, s),
+                    scale: pose.scale,
+                }
+            }
+            None => pose,
+        };
+        if matches!(controller.blend, Some((_, blended)) if blended >= 1.0) {
+            controller.blend = None;
+        }
+
+        if !motion.reduced_motion && controller.shake.trauma > 0.0 {
+            let offset = controller.shake.offset(elapsed);
+            let rotation = transform.rotation;
+            transform.translation += rotation * offset.translation;
+            transform.rotation = rotation * offset.rotation;
+        }
     }
 }
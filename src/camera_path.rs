@@ -0,0 +1,482 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    render::mesh::PrimitiveTopology,
+    utils::{BoxedFuture, HashMap},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::camera::CameraController;
+use crate::easing::Easing;
+use crate::timeline::{apply_timeline, ScrollTimeline};
+
+/*
+Camera moves stored as data in `*.path.ron` files. Positions follow a
+Catmull-Rom spline through the keyframes, with tangents scaled by the keyframe
+timing so uneven spacing doesn't overshoot, and rotations use squad so they
+turn smoothly through each keyframe instead of kinking like chained slerps.
+*/
+
+pub struct CameraPathPlugin;
+impl Plugin for CameraPathPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<CameraPath>();
+        app.init_asset_loader::<CameraPathLoader>();
+        app.init_resource::<CameraPathDebug>();
+        app.add_system(follow_camera_path.after(apply_timeline));
+        app.add_system(toggle_path_debug);
+        app.add_system(draw_camera_paths);
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PathKeyframe {
+    /// Seconds from the start of the path.
+    pub time: f32,
+    pub position: [f32; 3],
+    /// Euler angles in radians, applied in XYZ order.
+    pub rotation: [f32; 3],
+    /// Easing used on the segment leading into this keyframe.
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+impl PathKeyframe {
+    pub fn position(&self) -> Vec3 {
+        Vec3::from(self.position)
+    }
+
+    pub fn rotation(&self) -> Quat {
+        let [x, y, z] = self.rotation;
+        Quat::from_euler(EulerRot::XYZ, x, y, z)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, TypeUuid)]
+#[uuid = "8a6b7f43-2f5e-4b1d-a0c6-5e9d2c7b3f11"]
+pub struct CameraPath {
+    /// Sorted by `time`.
+    pub keyframes: Vec<PathKeyframe>,
+}
+
+impl CameraPath {
+    /// Parses a `.path.ron` file, sorting the keyframes by time.
+    pub fn from_ron(bytes: &[u8]) -> Result<Self, ron::error::SpannedError> {
+        let mut path: CameraPath = ron::de::from_bytes(bytes)?;
+        path.keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Ok(path)
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |key| key.time)
+    }
+
+    /// The segment containing `time` and the eased position within it.
+    fn segment(&self, time: f32) -> Option<(usize, f32)> {
+        let keys = &self.keyframes;
+        if keys.len() < 2 {
+            return None;
+        }
+        let time = time.clamp(keys[0].time, self.duration());
+        let i = keys
+            .windows(2)
+            .position(|pair| time <= pair[1].time)
+            .unwrap_or(keys.len() - 2);
+        let (a, b) = (&keys[i], &keys[i + 1]);
+        let t = (time - a.time) / (b.time - a.time).max(f32::EPSILON);
+        Some((i, b.easing.apply(t)))
+    }
+
+    pub fn position_at(&self, time: f32) -> Option<Vec3> {
+        let keys = &self.keyframes;
+        let Some((i, t)) = self.segment(time) else {
+            return keys.first().map(PathKeyframe::position);
+        };
+        let (k1, k2) = (&keys[i], &keys[i + 1]);
+        let k0 = &keys[i.saturating_sub(1)];
+        let k3 = &keys[(i + 2).min(keys.len() - 1)];
+        let dt = (k2.time - k1.time).max(f32::EPSILON);
+
+        // Finite difference tangents over the neighbouring keys, in units per second
+        let tangent = |a: &PathKeyframe, b: &PathKeyframe| {
+            (b.position() - a.position()) / (b.time - a.time).max(f32::EPSILON)
+        };
+        let m1 = tangent(k0, k2) * dt;
+        let m2 = tangent(k1, k3) * dt;
+
+        let (t2, t3) = (t * t, t * t * t);
+        Some(
+            (2. * t3 - 3. * t2 + 1.) * k1.position()
+                + (t3 - 2. * t2 + t) * m1
+                + (-2. * t3 + 3. * t2) * k2.position()
+                + (t3 - t2) * m2,
+        )
+    }
+
+    pub fn rotation_at(&self, time: f32) -> Option<Quat> {
+        let keys = &self.keyframes;
+        let Some((i, t)) = self.segment(time) else {
+            return keys.first().map(PathKeyframe::rotation);
+        };
+        // Keep neighbouring keys in the same hemisphere so squad takes the short way round
+        let mut rotations = [
+            keys[i.saturating_sub(1)].rotation(),
+            keys[i].rotation(),
+            keys[i + 1].rotation(),
+            keys[(i + 2).min(keys.len() - 1)].rotation(),
+        ];
+        for j in 1..4 {
+            if rotations[j].dot(rotations[j - 1]) < 0.0 {
+                rotations[j] = -rotations[j];
+            }
+        }
+        let [q0, q1, q2, q3] = rotations;
+        let s1 = squad_control(q0, q1, q2);
+        let s2 = squad_control(q1, q2, q3);
+        Some(squad(q1, q2, s1, s2, t))
+    }
+
+    pub fn transform_at(&self, time: f32) -> Option<Transform> {
+        Some(Transform {
+            translation: self.position_at(time)?,
+            rotation: self.rotation_at(time)?,
+            ..default()
+        })
+    }
+}
+
+fn quat_log(q: Quat) -> Vec3 {
+    let v = Vec3::new(q.x, q.y, q.z);
+    let length = v.length();
+    if length < 1e-6 {
+        return Vec3::ZERO;
+    }
+    v / length * length.atan2(q.w)
+}
+
+fn quat_exp(v: Vec3) -> Quat {
+    let angle = v.length();
+    if angle < 1e-6 {
+        return Quat::IDENTITY;
+    }
+    let (sin, cos) = angle.sin_cos();
+    let axis = v / angle * sin;
+    Quat::from_xyzw(axis.x, axis.y, axis.z, cos)
+}
+
+/// The inner control point for `current` given its neighbours.
+fn squad_control(previous: Quat, current: Quat, next: Quat) -> Quat {
+    let inverse = current.inverse();
+    let a = quat_log(inverse * next);
+    let b = quat_log(inverse * previous);
+    current * quat_exp(-(a + b) * 0.25)
+}
+
+fn squad(q1: Quat, q2: Quat, s1: Quat, s2: Quat, t: f32) -> Quat {
+    q1.slerp(q2, t)
+        .slerp(s1.slerp(s2, t), 2.0 * t * (1.0 - t))
+        .normalize()
+}
+
+#[derive(Default)]
+pub struct CameraPathLoader;
+
+impl AssetLoader for CameraPathLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let path = CameraPath::from_ron(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(path));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["path.ron"]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PathDrive {
+    /// Play through in real time.
+    Time { speed: f32, looping: bool },
+    /// Map a range of `ScrollTimeline::progress` onto the whole path.
+    Scroll { from: f32, to: f32 },
+}
+
+/// Put on the camera to move it along a path.
+#[derive(Component)]
+pub struct CameraPathFollower {
+    pub path: Handle<CameraPath>,
+    pub drive: PathDrive,
+    /// Current position along the path in seconds.
+    pub time: f32,
+    pub enabled: bool,
+}
+
+impl CameraPathFollower {
+    pub fn new(path: Handle<CameraPath>, drive: PathDrive) -> Self {
+        Self {
+            path,
+            drive,
+            time: 0.0,
+            enabled: true,
+        }
+    }
+}
+
+pub fn follow_camera_path(
+    time: Res<Time>,
+    timeline: Option<Res<ScrollTimeline>>,
+    paths: Res<Assets<CameraPath>>,
+    mut followers: Query<(
+        &mut CameraPathFollower,
+        &mut Transform,
+        Option<&mut CameraController>,
+    )>,
+) {
+    for (mut follower, mut transform, controller) in followers.iter_mut() {
+        if !follower.enabled {
+            continue;
+        }
+        let Some(path) = paths.get(&follower.path) else {
+            continue;
+        };
+        let duration = path.duration();
+        follower.time = match follower.drive {
+            PathDrive::Time { speed, looping } => {
+                let t = follower.time + time.delta_seconds() * speed;
+                if looping && duration > 0.0 {
+                    t.rem_euclid(duration)
+                } else {
+                    t.clamp(0.0, duration)
+                }
+            }
+            PathDrive::Scroll { from, to } => {
+                let progress = timeline.as_ref().map_or(0.0, |timeline| timeline.progress);
+                ((progress - from) / (to - from).max(f32::EPSILON)).clamp(0.0, 1.0) * duration
+            }
+        };
+        let Some(pose) = path.transform_at(follower.time) else {
+            continue;
+        };
+        // A rig decides for itself whether to use the pose
+        match controller {
+            Some(mut controller) => controller.path_pose = pose,
+            None => *transform = pose,
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct CameraPathDebug {
+    pub enabled: bool,
+    pub samples_per_second: f32,
+    pub color: Color,
+}
+
+impl Default for CameraPathDebug {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            samples_per_second: 30.0,
+            color: Color::rgb(1.0, 0.2, 0.8),
+        }
+    }
+}
+
+fn toggle_path_debug(keys: Res<Input<KeyCode>>, mut debug: ResMut<CameraPathDebug>) {
+    if keys.just_pressed(KeyCode::F3) {
+        debug.enabled = !debug.enabled;
+    }
+}
+
+#[derive(Component)]
+struct CameraPathGizmo;
+
+/// Bevy has no immediate mode line drawing yet, so each path gets a line list mesh
+/// that is rebuilt whenever the file changes.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn draw_camera_paths(
+    mut commands: Commands,
+    debug: Res<CameraPathDebug>,
+    paths: Res<Assets<CameraPath>>,
+    mut path_events: EventReader<AssetEvent<CameraPath>>,
+    followers: Query<&CameraPathFollower>,
+    mut gizmos: Query<&mut Visibility, With<CameraPathGizmo>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut spawned: Local<HashMap<Handle<CameraPath>, (Entity, Handle<Mesh>)>>,
+) {
+    for event in path_events.iter() {
+        if let AssetEvent::Modified { handle } = event {
+            if let (Some((_, mesh)), Some(path)) = (spawned.get(handle), paths.get(handle)) {
+                let _ = meshes.set(mesh, path_mesh(path, debug.samples_per_second));
+            }
+        }
+    }
+
+    if debug.enabled {
+        for follower in &followers {
+            if spawned.contains_key(&follower.path) {
+                continue;
+            }
+            let Some(path) = paths.get(&follower.path) else {
+                continue;
+            };
+            let mesh = meshes.add(path_mesh(path, debug.samples_per_second));
+            let entity = commands
+                .spawn((
+                    PbrBundle {
+                        mesh: mesh.clone(),
+                        material: materials.add(StandardMaterial {
+                            base_color: debug.color,
+                            unlit: true,
+                            ..default()
+                        }),
+                        ..default()
+                    },
+                    CameraPathGizmo,
+                ))
+                .id();
+            spawned.insert(follower.path.clone(), (entity, mesh));
+        }
+    }
+
+    let visibility = if debug.enabled {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    for mut gizmo in gizmos.iter_mut() {
+        *gizmo = visibility;
+    }
+}
+
+fn path_mesh(path: &CameraPath, samples_per_second: f32) -> Mesh {
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let duration = path.duration();
+    let samples = ((duration * samples_per_second) as usize).max(1);
+    let curve = (0..=samples)
+        .filter_map(|i| path.position_at(duration * i as f32 / samples as f32))
+        .collect::<Vec<_>>();
+    for pair in curve.windows(2) {
+        positions.push(pair[0].into());
+        positions.push(pair[1].into());
+    }
+    // A small axis cross at each keyframe, pointing along the camera's forward
+    for key in &path.keyframes {
+        let p = key.position();
+        let rotation = key.rotation();
+        let size = 5.0;
+        for axis in [Vec3::X, Vec3::Y] {
+            let offset = rotation * axis * size;
+            positions.push((p - offset).into());
+            positions.push((p + offset).into());
+        }
+        positions.push(p.into());
+        positions.push((p + rotation * Vec3::NEG_Z * size * 4.0).into());
+    }
+
+    let normals = vec![[0.0, 1.0, 0.0]; positions.len()];
+    let uvs = vec![[0.0, 0.0]; positions.len()];
+    let mut mesh = Mesh::new(PrimitiveTopology::LineList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(time: f32, position: [f32; 3], rotation: [f32; 3], easing: Easing) -> PathKeyframe {
+        PathKeyframe {
+            time,
+            position,
+            rotation,
+            easing,
+        }
+    }
+
+    /// Uneven spacing, a mix of easings and a sharp turn at the end.
+    fn path() -> CameraPath {
+        CameraPath {
+            keyframes: vec![
+                key(0.0, [0.0, 10.0, 0.0], [-1.5, 0.0, 0.0], Easing::Linear),
+                key(1.0, [5.0, 8.0, -3.0], [-1.2, 0.8, 0.1], Easing::SmoothStep),
+                key(4.0, [-2.0, 12.0, 6.0], [-0.4, 2.5, 0.0], Easing::EaseInOut),
+                key(4.5, [0.0, 3.0, 0.0], [0.3, -2.9, -0.2], Easing::EaseOut),
+            ],
+        }
+    }
+
+    fn assert_same_rotation(actual: Quat, expected: Quat) {
+        assert!(
+            actual.dot(expected).abs() > 1.0 - 1e-5,
+            "{actual:?} is not {expected:?}"
+        );
+    }
+
+    #[test]
+    fn spline_passes_through_keys() {
+        let path = path();
+        for key in &path.keyframes {
+            let position = path.position_at(key.time).unwrap();
+            assert!(
+                position.abs_diff_eq(key.position(), 1e-4),
+                "{position} is not {} at {}",
+                key.position(),
+                key.time
+            );
+        }
+        // Clamped to the ends outside the path
+        assert_eq!(path.position_at(-1.0), path.position_at(0.0));
+        assert_eq!(path.position_at(10.0), path.position_at(4.5));
+    }
+
+    #[test]
+    fn squad_hits_keys_and_stays_normalised() {
+        let path = path();
+        for key in &path.keyframes {
+            assert_same_rotation(path.rotation_at(key.time).unwrap(), key.rotation());
+        }
+        for i in 0..=90 {
+            let rotation = path.rotation_at(i as f32 * 0.05).unwrap();
+            assert!(rotation.is_normalized(), "{rotation:?} at step {i}");
+        }
+
+        let (q1, q2) = (path.keyframes[1].rotation(), path.keyframes[2].rotation());
+        let s1 = squad_control(path.keyframes[0].rotation(), q1, q2);
+        let s2 = squad_control(q1, q2, path.keyframes[3].rotation());
+        assert_same_rotation(squad(q1, q2, s1, s2, 0.0), q1);
+        assert_same_rotation(squad(q1, q2, s1, s2, 1.0), q2);
+    }
+
+    #[test]
+    fn parses_path_files() {
+        let fixture = br#"
+            // Out of order on purpose
+            (
+                keyframes: [
+                    (time: 2.0, position: (1.0, 2.0, 3.0), rotation: (0.0, 1.0, 0.0), easing: EaseIn),
+                    (time: 0.0, position: (0.0, 0.0, 0.0), rotation: (0.0, 0.0, 0.0)),
+                ],
+            )
+        "#;
+        let path = CameraPath::from_ron(fixture).unwrap();
+        assert_eq!(path.duration(), 2.0);
+        assert_eq!(path.keyframes[0].easing, Easing::SmoothStep);
+        assert_eq!(path.keyframes[1].position, [1.0, 2.0, 3.0]);
+        assert_eq!(path.keyframes[1].easing, Easing::EaseIn);
+
+        assert!(CameraPath::from_ron(b"(keyframes: [(time: 1.0)])").is_err());
+        let shipped = CameraPath::from_ron(include_bytes!("../assets/paths/flythrough.path.ron"));
+        assert_eq!(shipped.unwrap().keyframes.len(), 5);
+    }
+}
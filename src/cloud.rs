@@ -1,14 +1,34 @@
+use std::sync::Arc;
+
 use bevy::math::vec2;
 
 // use crate::noise::fbmd;
-use crate::{noise, CameraController};
+use crate::cloud_query::CloudTextures;
+use crate::environment::{resolve_environment, EnvironmentView};
+use crate::global_environment::{add_global_environment, specialize_global_environment};
+use crate::noise;
+pub use crate::weather::CloudParams;
 use bevy::{
+    core_pipeline::core_3d::Transparent3d,
     math::vec3,
+    pbr::{MaterialPipeline, MaterialPipelineKey},
     prelude::*,
     reflect::TypeUuid,
-    render::render_resource::{AsBindGroup, Extent3d, ShaderRef, TextureDimension, TextureFormat},
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_phase::{sort_phase_system, RenderPhase},
+        render_resource::{
+            AsBindGroup, Extent3d, RenderPipelineDescriptor, ShaderRef,
+            SpecializedMeshPipelineError, TextureDimension, TextureFormat,
+        },
+        view::ExtractedView,
+        Extract, ExtractSchedule, RenderApp, RenderSet,
+    },
 };
 
+/// How many of the layers above shadow each layer. Higher ones are left out.
+pub const MAX_LAYERS_ABOVE: usize = 3;
+
 #[derive(Component, Default, Reflect)]
 pub struct RMCloud {
     pub handle: Handle<RMCloudMaterial>,
@@ -20,134 +40,484 @@ pub struct RMCloud {
     pub cloud_coef: f32,
     pub cloud_height: f32,
     pub scroll: f32,
+    pub altitude: f32,
+    /// World size of one tile of the cloud textures.
+    pub size: f32,
+    /// Vertical extent used by `density_at`, centred on `altitude`.
+    pub thickness: f32,
+    pub parallax: f32,
+    /// The plane recentres under the camera in steps of this many units, 0 leaves it in place.
+    pub snap: f32,
+    /// Distance from the camera where the clouds start to fade into the horizon.
+    pub fade_start: f32,
+    /// Distance from the camera where the clouds are gone.
+    pub fade_end: f32,
+    pub follows_weather: bool,
+    /// How much this layer darkens the layers below it.
+    pub shadow_strength: f32,
+    /// The layers above, nearest first, whose shadows are multiplied together on this one.
+    pub above: Vec<Entity>,
+    /// CPU copies of the worley and value textures for density queries.
+    #[reflect(ignore)]
+    pub data: Option<Arc<CloudTextures>>,
+}
+
+impl CloudParams {
+    pub fn apply(&self, cloud: &mut RMCloud) {
+        cloud.shadow_dist = self.shadow_dist;
+        cloud.shadow_coef = self.shadow_coef;
+        cloud.sun_pen = self.sun_pen;
+        cloud.worley_factor = self.worley_factor;
+        cloud.value_factor = self.value_factor;
+        cloud.cloud_coef = self.cloud_coef;
+        cloud.cloud_height = self.cloud_height;
+    }
+}
+
+/// Everything needed to spawn one cloud plane.
+#[derive(Clone, Debug)]
+pub struct CloudLayer {
+    pub altitude: f32,
+    /// World size of one tile of the cloud textures.
+    pub size: f32,
+    /// Size of the plane mesh, which follows the camera so this only needs to reach the horizon.
+    pub extent: f32,
+    pub thickness: f32,
+    pub snap: f32,
+    pub fade_start: f32,
+    pub fade_end: f32,
+    pub texture_resolution: (usize, usize),
+    pub noise_scale: Vec2,
+    pub params: CloudParams,
+    /// How fast the layer moves with `RMCloud::scroll`, higher layers should move less.
+    pub parallax: f32,
+    /// How much this layer darkens the layer below it.
+    pub shadow_strength: f32,
+    pub follows_weather: bool,
+}
+
+impl CloudLayer {
+    pub fn cumulus() -> Self {
+        Self {
+            altitude: 0.0,
+            size: 1000.0,
+            extent: 200_000.0,
+            thickness: 40.0,
+            snap: 1000.0,
+            fade_start: 3_000.0,
+            fade_end: 40_000.0,
+            texture_resolution: (1000, 1000),
+            noise_scale: vec2(5., 5.),
+            params: CloudParams {
+                shadow_dist: 50.0,
+                shadow_coef: 0.1,
+                sun_pen: 30.,
+                worley_factor: 0.1,
+                value_factor: 0.0,
+                cloud_coef: 0.2,
+                cloud_height: 0.2,
+            },
+            parallax: 1.0,
+            shadow_strength: 0.6,
+            follows_weather: true,
+        }
+    }
+
+    pub fn altostratus() -> Self {
+        Self {
+            altitude: 150.0,
+            size: 1400.0,
+            extent: 200_000.0,
+            thickness: 20.0,
+            snap: 1000.0,
+            fade_start: 3_000.0,
+            fade_end: 40_000.0,
+            texture_resolution: (512, 512),
+            noise_scale: vec2(3., 3.),
+            params: CloudParams {
+                shadow_dist: 20.0,
+                shadow_coef: 0.05,
+                sun_pen: 15.,
+                worley_factor: 0.3,
+                value_factor: 0.1,
+                cloud_coef: 0.15,
+                cloud_height: 0.25,
+            },
+            parallax: 0.7,
+            shadow_strength: 0.4,
+            follows_weather: false,
+        }
+    }
+
+    pub fn cirrus() -> Self {
+        Self {
+            altitude: 300.0,
+            size: 2000.0,
+            extent: 200_000.0,
+            thickness: 10.0,
+            snap: 1000.0,
+            fade_start: 3_000.0,
+            fade_end: 40_000.0,
+            texture_resolution: (512, 512),
+            noise_scale: vec2(8., 2.),
+            params: CloudParams {
+                shadow_dist: 5.0,
+                shadow_coef: 0.02,
+                sun_pen: 5.,
+                worley_factor: 0.4,
+                value_factor: 0.2,
+                cloud_coef: 0.1,
+                cloud_height: 0.3,
+            },
+            parallax: 0.4,
+            shadow_strength: 0.2,
+            follows_weather: false,
+        }
+    }
+}
+
+/// The layers `RMCloudPlugin` spawned at startup.
+#[derive(Resource, Clone, Debug)]
+pub struct RMCloudLayers(pub Vec<CloudLayer>);
+
+impl Default for RMCloudLayers {
+    fn default() -> Self {
+        Self(vec![
+            CloudLayer::cumulus(),
+            CloudLayer::altostratus(),
+            CloudLayer::cirrus(),
+        ])
+    }
+}
+
+/// Draws the ray marched cloud layers. `layers` are spawned at startup, use
+/// `RMCloudBundle` or `spawn_cloud_layers` to add more later.
+pub struct RMCloudPlugin {
+    pub layers: Vec<CloudLayer>,
+}
+
+impl Default for RMCloudPlugin {
+    fn default() -> Self {
+        Self {
+            layers: RMCloudLayers::default().0,
+        }
+    }
 }
 
-pub struct RMCloudPlugin;
 impl Plugin for RMCloudPlugin {
     fn build(&self, app: &mut App) {
         app.register_type::<RMCloud>();
         app.add_plugin(MaterialPlugin::<RMCloudMaterial>::default());
-        app.add_system(
-            |cam: Query<&Transform, With<CameraController>>,
-             clouds: Query<(&RMCloud, &Transform)>,
-             sun: Query<&Transform, With<DirectionalLight>>,
-             mut cloud_materials: ResMut<Assets<RMCloudMaterial>>,
-             time: Res<Time>| {
-                let camera_position = cam.get_single().unwrap().translation;
-                let sun_dir = sun.get_single().unwrap().forward();
-                for (cloud, _transform) in &clouds {
-                    if let Some(material) = cloud_materials.get_mut(&cloud.handle) {
-                        material.camera_position = camera_position;
-                        material.time = time.raw_elapsed_seconds();
-                        material.sun_direction = sun_dir;
-                    }
-                }
-            },
-        );
+        app.insert_resource(RMCloudLayers(self.layers.clone()));
+        add_global_environment::<RMCloudMaterial>(app);
+        app.add_system(sync_params);
+        app.add_system(follow_camera.after(resolve_environment));
+        app.add_startup_system(spawn_startup_layers);
 
-        app.add_system(
-            |mut clouds: Query<&mut RMCloud>, mut materials: ResMut<Assets<RMCloudMaterial>>| {
-                for cloud in clouds.iter_mut() {
-                    match materials.get_mut(&cloud.handle) {
-                        Some(material) => {
-                            material.shadow_dist = cloud.shadow_dist;
-                            material.shadow_coef = cloud.shadow_coef;
-                            material.worley_factor = cloud.worley_factor;
-                            material.value_factor = cloud.value_factor;
-                            material.cloud_coef = cloud.cloud_coef;
-                            material.cloud_height = cloud.cloud_height;
-                            material.sun_pen = cloud.sun_pen;
-                            material.scroll = cloud.scroll;
-                        }
-                        None => {}
-                    };
-                }
-            },
-        );
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .add_system(extract_cloud_altitudes.in_schedule(ExtractSchedule))
+                .add_system(
+                    sort_layers
+                        .in_set(RenderSet::PhaseSort)
+                        .before(sort_phase_system::<Transparent3d>),
+                );
+        }
+    }
+}
 
-        app.add_startup_system(
-            |mut commands: Commands,
-             // asset_server: Res<AssetServer>,
-             mut meshes: ResMut<Assets<Mesh>>,
-             // mut materials: ResMut<Assets<StandardMaterial>>,
-             mut cloud_materials: ResMut<Assets<RMCloudMaterial>>,
-             // mut noise_materials: ResMut<Assets<NoiseMaterial>>,
-             mut images: ResMut<Assets<Image>>| {
-                let res = (1000, 1000);
-                let re3 = 2;
-
-                let w3d = {
-                    images.add(Image::new(
-                        Extent3d {
-                            width: re3 as u32,
-                            height: re3 as u32,
-                            depth_or_array_layers: re3 as u32,
-                        },
-                        TextureDimension::D3,
-                        w3noise(re3)
-                            .iter()
-                            .flat_map(|f| f.to_ne_bytes())
-                            .collect::<Vec<u8>>(),
-                        TextureFormat::R32Float,
-                    ))
-                };
-
-                let mut make_image = |data: &[f32]| {
-                    images.add(Image::new(
-                        Extent3d {
-                            width: res.0 as u32,
-                            height: res.1 as u32,
-                            depth_or_array_layers: 1,
-                        },
-                        TextureDimension::D2,
-                        data.iter()
-                            .flat_map(|f| f.to_ne_bytes())
-                            .collect::<Vec<u8>>(),
-                        TextureFormat::R32Float,
-                    ))
-                };
-
-                let wnoise = worley_texture_data(res, vec2(5., 5.));
-                let vnoise = value_texture_data(res, vec2(5., 5.));
-                let worley = make_image(&wnoise);
-                let value = make_image(&vnoise);
-                let material = cloud_materials.add(RMCloudMaterial {
-                    worley: Some(worley.clone()),
-                    value: Some(value.clone()),
-                    w3d: Some(w3d),
-                    sun_direction: vec3(1., 1., 0.).normalize(),
-                    ..default()
-                });
-
-                commands.spawn((
-                    RMCloud {
-                        handle: material.clone(),
-                        shadow_dist: 50.0,
-                        shadow_coef: 0.1,
-                        sun_pen: 30.,
-                        worley_factor: 0.1,
-                        value_factor: 0.0,
-                        cloud_coef: 0.2,
-                        cloud_height: 0.2,
-                        ..Default::default()
-                    },
-                    MaterialMeshBundle {
-                        // mesh: meshes.add(cloud_gen::new(100.)),
-                        mesh: meshes.add(
-                            shape::Plane {
-                                size: 1000.0,
-                                ..default()
-                            }
-                            .into(),
-                        ),
-                        material,
+/// A single cloud plane. Layers spawned this way don't shadow each other until
+/// `RMCloud::above` is filled in, `spawn_cloud_layers` links a whole stack.
+#[derive(Bundle)]
+pub struct RMCloudBundle {
+    pub cloud: RMCloud,
+    pub material_mesh: MaterialMeshBundle<RMCloudMaterial>,
+}
+
+impl RMCloudBundle {
+    pub fn new(
+        layer: &CloudLayer,
+        meshes: &mut Assets<Mesh>,
+        cloud_materials: &mut Assets<RMCloudMaterial>,
+        images: &mut Assets<Image>,
+    ) -> Self {
+        let w3d = w3d_texture(images);
+        let textures = LayerTextures::new(layer, images);
+        let material = cloud_materials.add(RMCloudMaterial {
+            water: 1.0,
+            ..layer_material(layer, &textures, w3d)
+        });
+        Self::with_material(layer, material, textures.data, meshes)
+    }
 
+    fn with_material(
+        layer: &CloudLayer,
+        material: Handle<RMCloudMaterial>,
+        data: Arc<CloudTextures>,
+        meshes: &mut Assets<Mesh>,
+    ) -> Self {
+        let mut cloud = RMCloud {
+            handle: material.clone(),
+            altitude: layer.altitude,
+            size: layer.size,
+            thickness: layer.thickness,
+            parallax: layer.parallax,
+            data: Some(data),
+            snap: layer.snap,
+            fade_start: layer.fade_start,
+            fade_end: layer.fade_end,
+            follows_weather: layer.follows_weather,
+            shadow_strength: layer.shadow_strength,
+            ..Default::default()
+        };
+        layer.params.apply(&mut cloud);
+        Self {
+            cloud,
+            material_mesh: MaterialMeshBundle {
+                mesh: meshes.add(
+                    shape::Plane {
+                        size: layer.extent,
                         ..default()
-                    },
-                ));
+                    }
+                    .into(),
+                ),
+                material,
+                transform: Transform::from_xyz(0.0, layer.altitude, 0.0),
+                ..default()
             },
+        }
+    }
+}
+
+/// A layer's altitude in the render world, for `sort_layers`.
+#[derive(Component)]
+struct CloudAltitude(f32);
+
+fn extract_cloud_altitudes(mut commands: Commands, clouds: Extract<Query<(Entity, &RMCloud)>>) {
+    for (entity, cloud) in &clouds {
+        commands
+            .get_or_spawn(entity)
+            .insert(CloudAltitude(cloud.altitude));
+    }
+}
+
+/// Transparent meshes are sorted by their origin, which says little about the order of huge
+/// overlapping planes. Sort the layers by their height from each camera instead, farthest
+/// first.
+fn sort_layers(
+    mut views: Query<(&ExtractedView, &mut RenderPhase<Transparent3d>)>,
+    clouds: Query<&CloudAltitude>,
+) {
+    for (view, mut phase) in &mut views {
+        let camera_height = view.transform.translation().y;
+        for item in &mut phase.items {
+            if let Ok(CloudAltitude(altitude)) = clouds.get(item.entity) {
+                item.distance = -(camera_height - altitude).abs();
+            }
+        }
+    }
+}
+
+/// Layers shadow the ones below them, so a change to any layer rewrites every material.
+fn sync_params(
+    clouds: Query<Ref<RMCloud>>,
+    mut removed: RemovedComponents<RMCloud>,
+    mut materials: ResMut<Assets<RMCloudMaterial>>,
+) {
+    let removed = removed.iter().count() > 0;
+    if !removed && !clouds.iter().any(|cloud| cloud.is_changed()) {
+        return;
+    }
+    for cloud in clouds.iter() {
+        let above = cloud
+            .above
+            .iter()
+            .filter_map(|&entity| {
+                let above = clouds.get(entity).ok()?;
+                let material = materials.get(&above.handle)?;
+                Some((above, material.worley.clone(), material.value.clone()))
+            })
+            .take(MAX_LAYERS_ABOVE)
+            .collect::<Vec<_>>();
+        let Some(material) = materials.get_mut(&cloud.handle) else {
+            continue;
+        };
+        material.shadow_dist = cloud.shadow_dist;
+        material.shadow_coef = cloud.shadow_coef;
+        material.worley_factor = cloud.worley_factor;
+        material.value_factor = cloud.value_factor;
+        material.cloud_coef = cloud.cloud_coef;
+        material.cloud_height = cloud.cloud_height;
+        material.sun_pen = cloud.sun_pen;
+        material.scroll = cloud.scroll * cloud.parallax;
+        material.size = cloud.size;
+        material.fade_start = cloud.fade_start;
+        material.fade_end = cloud.fade_end;
+        material.above_count = above.len() as u32;
+        for (i, (above, worley, value)) in above.into_iter().enumerate() {
+            material.above_placement[i] = Vec4::new(
+                above.altitude - cloud.altitude,
+                above.size,
+                above.scroll * above.parallax,
+                above.shadow_strength,
+            );
+            material.above_shape[i] = Vec4::new(
+                above.worley_factor,
+                above.value_factor,
+                above.cloud_coef,
+                above.cloud_height,
+            );
+            let (above_worley, above_value) = material.above_textures_mut(i);
+            *above_worley = worley;
+            *above_value = value;
+        }
+    }
+}
+
+fn spawn_startup_layers(
+    mut commands: Commands,
+    layers: Res<RMCloudLayers>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut cloud_materials: ResMut<Assets<RMCloudMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    spawn_cloud_layers(
+        &mut commands,
+        &mut meshes,
+        &mut cloud_materials,
+        &mut images,
+        &layers.0,
+    );
+}
+
+/// The GPU and CPU copies of one layer's noise textures.
+struct LayerTextures {
+    worley: Handle<Image>,
+    value: Handle<Image>,
+    data: Arc<CloudTextures>,
+}
+
+impl LayerTextures {
+    fn new(layer: &CloudLayer, images: &mut Assets<Image>) -> Self {
+        let res = layer.texture_resolution;
+        let mut make_image = |data: &[f32]| {
+            images.add(Image::new(
+                Extent3d {
+                    width: res.0 as u32,
+                    height: res.1 as u32,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                data.iter()
+                    .flat_map(|f| f.to_ne_bytes())
+                    .collect::<Vec<u8>>(),
+                TextureFormat::R32Float,
+            ))
+        };
+        let data = CloudTextures {
+            resolution: res,
+            worley: worley_texture_data(res, layer.noise_scale),
+            value: value_texture_data(res, layer.noise_scale),
+        };
+        Self {
+            worley: make_image(&data.worley),
+            value: make_image(&data.value),
+            data: Arc::new(data),
+        }
+    }
+}
+
+fn w3d_texture(images: &mut Assets<Image>) -> Handle<Image> {
+    let re3 = 2;
+    images.add(Image::new(
+        Extent3d {
+            width: re3 as u32,
+            height: re3 as u32,
+            depth_or_array_layers: re3 as u32,
+        },
+        TextureDimension::D3,
+        w3noise(re3)
+            .iter()
+            .flat_map(|f| f.to_ne_bytes())
+            .collect::<Vec<u8>>(),
+        TextureFormat::R32Float,
+    ))
+}
+
+fn layer_material(
+    layer: &CloudLayer,
+    textures: &LayerTextures,
+    w3d: Handle<Image>,
+) -> RMCloudMaterial {
+    RMCloudMaterial {
+        worley: Some(textures.worley.clone()),
+        value: Some(textures.value.clone()),
+        w3d: Some(w3d),
+        size: layer.size,
+        ..default()
+    }
+}
+
+/// Spawns the layers and links each one to the layers above it for shadowing. Only the
+/// lowest layer draws the water underneath, the others are blended over it.
+pub fn spawn_cloud_layers(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    cloud_materials: &mut Assets<RMCloudMaterial>,
+    images: &mut Assets<Image>,
+    layers: &[CloudLayer],
+) -> Vec<Entity> {
+    let w3d = w3d_texture(images);
+
+    let mut layers = layers.to_vec();
+    layers.sort_by(|a, b| a.altitude.total_cmp(&b.altitude));
+    let textures = layers
+        .iter()
+        .map(|layer| LayerTextures::new(layer, images))
+        .collect::<Vec<_>>();
+
+    if layers.len() > MAX_LAYERS_ABOVE + 1 {
+        warn!(
+            "{} cloud layers, only the {MAX_LAYERS_ABOVE} nearest above each one shadow it",
+            layers.len()
         );
     }
+
+    // Top to bottom, so the layers above are already spawned, nearest last
+    let mut entities: Vec<Entity> = Vec::with_capacity(layers.len());
+    for (i, layer) in layers.iter().enumerate().rev() {
+        let material = cloud_materials.add(RMCloudMaterial {
+            water: if i == 0 { 1.0 } else { 0.0 },
+            ..layer_material(layer, &textures[i], w3d.clone())
+        });
+
+        let mut bundle =
+            RMCloudBundle::with_material(layer, material, textures[i].data.clone(), meshes);
+        bundle.cloud.above = entities.iter().rev().copied().collect();
+        entities.push(commands.spawn(bundle).id());
+    }
+    entities.reverse();
+    entities
+}
+
+/// Keeps each layer under the camera. The shader derives uvs from the world position,
+/// so moving the mesh does not move the clouds.
+fn follow_camera(view: Res<EnvironmentView>, mut clouds: Query<(&RMCloud, &mut Transform)>) {
+    if view.camera.is_none() {
+        return;
+    }
+    let camera_position = view.camera_position();
+    for (cloud, mut transform) in clouds.iter_mut() {
+        let mut translation = vec3(0.0, cloud.altitude, 0.0);
+        if cloud.snap > 0.0 {
+            let snapped = (camera_position / cloud.snap).round() * cloud.snap;
+            translation.x = snapped.x;
+            translation.z = snapped.z;
+        }
+        if transform.translation != translation {
+            transform.translation = translation;
+        }
+    }
 }
 
 fn w3noise(res: usize) -> Vec<f32> {
@@ -189,110 +559,6 @@ pub fn value_texture_data(buffer_dimensions: (usize, usize), scale: Vec2) -> Vec
         .collect()
 }
 
-// pub fn new_cloud_data(buffer_dimensions: [usize; 3]) -> Vec<Vec4> {
-//     let resolution = vec3(
-//         buffer_dimensions[0] as f32,
-//         buffer_dimensions[1] as f32,
-//         buffer_dimensions[2] as f32,
-//     );
-
-//     let data = Vec::from_iter((0..buffer_dimensions[0]).map(|_| {
-//         (0..buffer_dimensions[1])
-//             .map(|_| {
-//                 (0..buffer_dimensions[2])
-//                     .map(|_| Mutex::new(vec4(1., 1., 1., 1.)))
-//                     .collect_vec()
-//             })
-//             .collect_vec()
-//     }));
-//     (0..buffer_dimensions[0])
-//         .flat_map(move |x| {
-//             (0..buffer_dimensions[1])
-//                 .flat_map(move |y| (0..buffer_dimensions[2]).map(move |z| (x, y, z)))
-//         })
-//         .par_bridge()
-//         .for_each(|(x, y, z)| {
-//             let p = coord_to_pos([x, y, z], resolution);
-//             // let d = cloud_sdf(p);
-//             let sca = vec3(0.50, 0.50, 0.50) / 100.0 * resolution;
-//             let n = ((noise::wfbm(p * sca, Vec3::ONE * 1000.0)
-//                 * (2.0 + fbmd(p * sca + 110.1231231).x)
-//                 * 0.5)/*
-//              * ((1.0 - (-4.0 * (p.y + 1.0)).exp()) * ((-p.y).exp() - 0.37))*/)
-//                 .clamp(0.0, 3.0);
-//             *data[x][y][z].lock() = vec4(n, 0., n, 0.);
-//         });
-
-//     // for x in 0..buffer_dimensions[0] {
-//     //     for y in 0..buffer_dimensions[1] {
-//     //         for z in 0..buffer_dimensions[2] {
-//     //         }
-//     //     }
-//     // }
-//     // Sun light info requires sdf and density info
-
-//     let sun_base = vec3(-0., 2., 0.).normalize();
-
-//     let mut sun_directions = Vec::with_capacity(27);
-//     sun_directions.push((sun_base, mie(1.)));
-//     // let theta = 0.15;
-//     // let angles = [-theta, 0.0, theta];
-//     // for x in angles {
-//     //     for y in angles {
-//     //         for z in angles {
-//     //             let direction = rotate(sun_base, x, y, z);
-//     //             sun_directions.push((direction, mie(sun_base.dot(direction))));
-//     //         }
-//     //     }
-//     // }
-
-//     // Sun raymarching
-
-//     (0..buffer_dimensions[0])
-//         .flat_map(|x| {
-//             (0..buffer_dimensions[1])
-//                 .flat_map(move |y| (0..buffer_dimensions[2]).map(move |z| (x, y, z)))
-//         })
-//         .par_bridge()
-//         .for_each(|(x, y, z)| {
-//             let mut total = 0.;
-//             let dt = 2. / resolution.x.min(resolution.y).min(resolution.z) * 0.1;
-//             for (sun_direction, phase) in sun_directions.iter() {
-//                 let mut t = 1.;
-//                 let mut p = coord_to_pos([x, y, z], resolution);
-//                 let mut sample_point = [x, y, z];
-//                 while let Some(samp) = data
-//                     .get(sample_point[0])
-//                     .and_then(|slice| slice.get(sample_point[1]))
-//                     .and_then(|row| row.get(sample_point[2]))
-//                 {
-//                     let samp = samp.lock();
-//                     if p.x.abs() > 1. || p.y.abs() > 1. || p.z.abs() > 1. {
-//                         break;
-//                     }
-
-//                     let dm = 0.5;
-//                     let noise = 0.0_f32.max(samp.z - dm);
-//                     // if noise > 0.0 {
-//                     t += noise * dt * 5.0;
-//                     // println!("{noise} {t}");
-//                     // }
-//                     p += *sun_direction * dt * *phase;
-//                     sample_point = pos_to_coord(p, resolution);
-//                 }
-//                 total += t;
-//             }
-//             data[x][y][z].lock().y = total / sun_directions.len() as f32;
-//         });
-//     data.iter()
-//         .flat_map(|row_col| {
-//             row_col
-//                 .iter()
-//                 .flat_map(|row| row.iter().map(|c| c.lock().clone()))
-//         })
-//         .collect_vec()
-// }
-
 #[allow(dead_code)]
 fn rotate(v: Vec3, x: f32, y: f32, z: f32) -> Vec3 {
     Mat3::from_euler(bevy::prelude::EulerRot::XYZ, x, y, z) * v
@@ -327,18 +593,22 @@ impl Material for RMCloudMaterial {
     fn alpha_mode(&self) -> AlphaMode {
         AlphaMode::Blend
     }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        specialize_global_environment(descriptor, &key);
+        Ok(())
+    }
 }
 
 // This is the struct that will be passed to your shader
 #[derive(AsBindGroup, TypeUuid, Debug, Clone, Default, Reflect)]
 #[uuid = "f690fdae-d598-45ab-8225-97e2a3f056e0"]
 pub struct RMCloudMaterial {
-    #[uniform(0)]
-    pub sun_direction: Vec3,
-    #[uniform(0)]
-    pub camera_position: Vec3,
-    #[uniform(0)]
-    pub time: f32,
     #[uniform(0)]
     pub shadow_dist: f32,
     #[uniform(0)]
@@ -355,6 +625,23 @@ pub struct RMCloudMaterial {
     pub cloud_height: f32,
     #[uniform(0)]
     pub scroll: f32,
+    #[uniform(0)]
+    pub size: f32,
+    /// 1 for the bottom layer, which draws the water where there is no cloud.
+    #[uniform(0)]
+    pub water: f32,
+    #[uniform(0)]
+    pub fade_start: f32,
+    #[uniform(0)]
+    pub fade_end: f32,
+    #[uniform(0)]
+    pub above_count: u32,
+    /// For each layer above: height over this one, tile size, scroll and shadow strength.
+    #[uniform(0)]
+    pub above_placement: [Vec4; MAX_LAYERS_ABOVE],
+    /// For each layer above: worley factor, value factor, cloud coef and cloud height.
+    #[uniform(0)]
+    pub above_shape: [Vec4; MAX_LAYERS_ABOVE],
 
     #[texture(1)]
     #[sampler(2)]
@@ -365,4 +652,31 @@ pub struct RMCloudMaterial {
     #[texture(5, dimension = "3d")]
     #[sampler(6)]
     pub w3d: Option<Handle<Image>>,
+    #[texture(7)]
+    #[sampler(8)]
+    pub above_worley_0: Option<Handle<Image>>,
+    #[texture(9)]
+    pub above_value_0: Option<Handle<Image>>,
+    #[texture(10)]
+    pub above_worley_1: Option<Handle<Image>>,
+    #[texture(11)]
+    pub above_value_1: Option<Handle<Image>>,
+    #[texture(12)]
+    pub above_worley_2: Option<Handle<Image>>,
+    #[texture(13)]
+    pub above_value_2: Option<Handle<Image>>,
+}
+
+impl RMCloudMaterial {
+    /// The worley and value textures of the `i`th layer above.
+    fn above_textures_mut(
+        &mut self,
+        i: usize,
+    ) -> (&mut Option<Handle<Image>>, &mut Option<Handle<Image>>) {
+        match i {
+            0 => (&mut self.above_worley_0, &mut self.above_value_0),
+            1 => (&mut self.above_worley_1, &mut self.above_value_1),
+            _ => (&mut self.above_worley_2, &mut self.above_value_2),
+        }
+    }
 }
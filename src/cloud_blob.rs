@@ -1,133 +1,125 @@
 use bevy::{
     math::{vec2, vec3},
+    pbr::{MaterialPipeline, MaterialPipelineKey},
     prelude::*,
     reflect::TypeUuid,
-    render::render_resource::{AsBindGroup, ShaderRef, TextureFormat},
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_resource::{
+            AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError,
+            TextureFormat,
+        },
+    },
 };
 use rand::prelude::*;
 use std::ops::{Add, Mul, Sub};
 
-use crate::{noise, CameraController};
+use crate::global_environment::{add_global_environment, specialize_global_environment};
+use crate::noise;
+use crate::phase::add_phase_shader;
 
 #[derive(Component, Default)]
-struct CloudBlob {
-    handle: Handle<CloudBlobMaterial>,
+pub struct CloudBlob {
+    pub handle: Handle<CloudBlobMaterial>,
 }
 
-pub struct CloudBlobPlugin;
+/// Scatters blob clouds over a dome. `blobs` lone blobs and `clusters` groups of six are
+/// spawned at startup, anything else can be added later with `CloudBlobBundle`.
+pub struct CloudBlobPlugin {
+    pub blobs: usize,
+    pub clusters: usize,
+    /// Width of the square the blobs are scattered over.
+    pub spread: f32,
+    /// Side length of the shared 3d noise texture, cached in `assets/noise_data`.
+    pub texture_resolution: usize,
+}
+
+impl Default for CloudBlobPlugin {
+    fn default() -> Self {
+        Self {
+            blobs: 200,
+            clusters: 20,
+            spread: 10_000.,
+            texture_resolution: 200,
+        }
+    }
+}
+
+/// The mesh and noise texture every blob shares, available once startup has run.
+#[derive(Resource, Clone)]
+pub struct CloudBlobAssets {
+    pub mesh: Handle<Mesh>,
+    pub noise: Handle<Image>,
+}
+
+#[derive(Bundle)]
+pub struct CloudBlobBundle {
+    pub blob: CloudBlob,
+    pub material_mesh: MaterialMeshBundle<CloudBlobMaterial>,
+}
+
+impl CloudBlobBundle {
+    pub fn new(
+        assets: &CloudBlobAssets,
+        transform: Transform,
+        materials: &mut Assets<CloudBlobMaterial>,
+    ) -> Self {
+        let material = materials.add(CloudBlobMaterial {
+            noise: Some(assets.noise.clone()),
+            ..default()
+        });
+        Self {
+            blob: CloudBlob {
+                handle: material.clone(),
+            },
+            material_mesh: MaterialMeshBundle {
+                mesh: assets.mesh.clone(),
+                material,
+                transform,
+                ..default()
+            },
+        }
+    }
+}
 
-#[allow(dead_code)]
 impl Plugin for CloudBlobPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(MaterialPlugin::<CloudBlobMaterial>::default());
-        app.add_system(
-            |camera: Query<&Transform, With<CameraController>>,
-             sun: Query<&Transform, With<DirectionalLight>>,
-             clouds: Query<(&CloudBlob, &Transform)>,
-             mut materials: ResMut<Assets<CloudBlobMaterial>>,
-             time: Res<Time>| {
-                let camera_position = camera.get_single().unwrap().translation;
-                let sun_facing = sun.get_single().unwrap().forward();
-                for (cloud, transform) in &clouds {
-                    if let Some(material) = materials.get_mut(&cloud.handle) {
-                        material.camera_position = camera_position;
-                        material.time = time.raw_elapsed_seconds();
-                        material.scale = transform.scale;
-                        material.sun_direction = sun_facing;
-                    }
-                }
-            },
-        );
+        add_global_environment::<CloudBlobMaterial>(app);
+        add_phase_shader(app);
+        app.add_system(sync_blob_scale);
 
-        const SECTORS: usize = 10;
-        const STACKS: usize = 10;
-        const TEXTURE_RES: usize = 200;
+        let &Self {
+            blobs,
+            clusters,
+            spread,
+            texture_resolution,
+        } = self;
         app.add_startup_system(
-            |mut materials: ResMut<Assets<CloudBlobMaterial>>,
-             mut commands: Commands,
-             mut meshes: ResMut<Assets<Mesh>>,
-             mut images: ResMut<Assets<Image>>| {
-                let path = "assets/noise_data";
-                let data = match std::fs::read(path) {
-                    Ok(data) => data,
-                    Err(_) => {
-                        let mut data = Box::new([[[0.0; TEXTURE_RES]; TEXTURE_RES]; TEXTURE_RES]);
-                        for x in 0..TEXTURE_RES {
-                            for y in 0..TEXTURE_RES {
-                                for z in 0..TEXTURE_RES {
-                                    let sample_pos = vec3(
-                                        x as f32 / TEXTURE_RES as f32,
-                                        y as f32 / TEXTURE_RES as f32,
-                                        z as f32 / TEXTURE_RES as f32,
-                                    ) * 10.;
-                                    data[x][y][z] = mix(
-                                        noise::fbmd(sample_pos).x,
-                                        noise::wfbm(sample_pos * 0.5, Vec3::ONE * 100.),
-                                        0.7,
-                                    )
-                                }
-                            }
-                        }
-                        let data = data
-                            .iter()
-                            .flatten()
-                            .flatten()
-                            .flat_map(|f| f.to_ne_bytes())
-                            .collect();
-                        if let Err(e) = std::fs::write(path, &data) {
-                            println!("Error writing noise data {:?}", e);
+            move |mut materials: ResMut<Assets<CloudBlobMaterial>>,
+                  mut commands: Commands,
+                  mut meshes: ResMut<Assets<Mesh>>,
+                  mut images: ResMut<Assets<Image>>| {
+                let assets = CloudBlobAssets {
+                    mesh: meshes.add(
+                        shape::UVSphere {
+                            radius: 1.0,
+                            sectors: SECTORS,
+                            stacks: STACKS,
                         }
-                        data
-                    }
+                        .into(),
+                    ),
+                    noise: images.add(noise_texture(texture_resolution)),
                 };
 
-                let texture = images.add(Image::new(
-                    bevy::render::render_resource::Extent3d {
-                        width: TEXTURE_RES as u32,
-                        height: TEXTURE_RES as u32,
-                        depth_or_array_layers: TEXTURE_RES as u32,
-                    },
-                    bevy::render::render_resource::TextureDimension::D3,
-                    data,
-                    TextureFormat::R32Float,
-                ));
-                let mesh = meshes.add(
-                    shape::UVSphere {
-                        radius: 1.0,
-                        sectors: SECTORS,
-                        stacks: STACKS,
-                    }
-                    .into(),
-                );
                 let mut rng = thread_rng();
-                for _ in 0..200 {
-                    let xz =
-                        vec2(rng.gen(), rng.gen()).add(vec2(-0.5, -0.5)) * vec2(10000., 10000.);
-                    let y = (10_000. - xz.length()).sqrt().sub(10.).mul(10.);
-                    let pos = vec3(xz.x, y, xz.y);
-
-                    let material = materials.add(CloudBlobMaterial {
-                        noise: Some(texture.clone()),
-                        ..default()
-                    });
-                    commands.spawn((
-                        CloudBlob {
-                            handle: material.clone(),
-                        },
-                        MaterialMeshBundle {
-                            mesh: mesh.clone(),
-                            material,
-                            transform: Transform::from_xyz(pos.x, pos.y, pos.z)
-                                .with_scale(vec3(400., 300., 400.) * rng.gen_range(0.5..1.0)),
-                            ..default()
-                        },
-                    ));
+                for _ in 0..blobs {
+                    let transform = Transform::from_translation(dome_point(&mut rng, spread))
+                        .with_scale(vec3(400., 300., 400.) * rng.gen_range(0.5..1.0));
+                    commands.spawn(CloudBlobBundle::new(&assets, transform, &mut materials));
                 }
-                for _ in 0..20 {
-                    let xz =
-                        vec2(rng.gen(), rng.gen()).add(vec2(-0.5, -0.5)) * vec2(10000., 10000.);
-                    let y = (10_000. - xz.length()).sqrt().sub(10.).mul(10.);
-                    let pos = vec3(xz.x, y, xz.y);
+                for _ in 0..clusters {
+                    let pos = dome_point(&mut rng, spread);
                     for (scale, offset) in [
                         (
                             vec3(1000., 400., 1000.) * rng.gen_range(0.5..1.0),
@@ -154,34 +146,76 @@ impl Plugin for CloudBlobPlugin {
                             vec3(-200., 50., -200.) * rng.gen_range(0.75..1.0),
                         ),
                     ] {
-                        // let offset = (vec3(rng.gen(), rng.gen(), rng.gen()) - 0.5) * 400.;
-                        let material = materials.add(CloudBlobMaterial {
-                            noise: Some(texture.clone()),
-                            ..default()
-                        });
-                        commands.spawn((
-                            CloudBlob {
-                                handle: material.clone(),
-                            },
-                            MaterialMeshBundle {
-                                mesh: mesh.clone(),
-                                material,
-                                transform: Transform::from_xyz(
-                                    pos.x + offset.x,
-                                    pos.y + offset.y,
-                                    pos.z + offset.z,
-                                )
-                                .with_scale(scale),
-                                ..default()
-                            },
-                        ));
+                        let transform = Transform::from_translation(pos + offset).with_scale(scale);
+                        commands.spawn(CloudBlobBundle::new(&assets, transform, &mut materials));
                     }
                 }
+                commands.insert_resource(assets);
             },
         );
     }
 }
 
+const SECTORS: usize = 10;
+const STACKS: usize = 10;
+
+fn dome_point(rng: &mut impl Rng, spread: f32) -> Vec3 {
+    let xz = vec2(rng.gen(), rng.gen()).add(vec2(-0.5, -0.5)) * spread;
+    let y = (spread - xz.length()).max(0.).sqrt().sub(10.).mul(10.);
+    vec3(xz.x, y, xz.y)
+}
+
+fn sync_blob_scale(
+    clouds: Query<(&CloudBlob, &Transform), Changed<Transform>>,
+    mut materials: ResMut<Assets<CloudBlobMaterial>>,
+) {
+    for (cloud, transform) in &clouds {
+        if let Some(material) = materials.get_mut(&cloud.handle) {
+            material.scale = transform.scale;
+        }
+    }
+}
+
+/// Loads the cached noise volume, regenerating it if it is missing or was baked at another
+/// resolution.
+fn noise_texture(res: usize) -> Image {
+    let path = "assets/noise_data";
+    let data = match std::fs::read(path) {
+        Ok(data) if data.len() == res * res * res * 4 => data,
+        _ => {
+            let mut data = vec![0.0; res * res * res];
+            for x in 0..res {
+                for y in 0..res {
+                    for z in 0..res {
+                        let sample_pos = vec3(x as f32, y as f32, z as f32) / res as f32 * 10.;
+                        data[(x * res + y) * res + z] = mix(
+                            noise::fbmd(sample_pos).x,
+                            noise::wfbm(sample_pos * 0.5, Vec3::ONE * 100.),
+                            0.7,
+                        )
+                    }
+                }
+            }
+            let data = data.iter().flat_map(|f| f.to_ne_bytes()).collect();
+            if let Err(e) = std::fs::write(path, &data) {
+                println!("Error writing noise data {:?}", e);
+            }
+            data
+        }
+    };
+
+    Image::new(
+        bevy::render::render_resource::Extent3d {
+            width: res as u32,
+            height: res as u32,
+            depth_or_array_layers: res as u32,
+        },
+        bevy::render::render_resource::TextureDimension::D3,
+        data,
+        TextureFormat::R32Float,
+    )
+}
+
 fn mix(a: f32, b: f32, t: f32) -> f32 {
     a * (1. - t) + b * t
 }
@@ -189,14 +223,8 @@ fn mix(a: f32, b: f32, t: f32) -> f32 {
 #[derive(AsBindGroup, TypeUuid, Debug, Clone, Default, Reflect)]
 #[uuid = "f690fd8e-d598-45ab-8225-97e2a3f056e0"]
 pub struct CloudBlobMaterial {
-    #[uniform(0)]
-    pub sun_direction: Vec3,
-    #[uniform(0)]
-    pub camera_position: Vec3,
     #[uniform(0)]
     pub scale: Vec3,
-    #[uniform(0)]
-    pub time: f32,
     #[texture(1, dimension = "3d")]
     #[sampler(2)]
     pub noise: Option<Handle<Image>>,
@@ -210,4 +238,14 @@ impl Material for CloudBlobMaterial {
     fn alpha_mode(&self) -> AlphaMode {
         AlphaMode::Premultiplied
     }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        specialize_global_environment(descriptor, &key);
+        Ok(())
+    }
 }
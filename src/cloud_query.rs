@@ -0,0 +1,326 @@
+use bevy::{
+    ecs::system::SystemParam,
+    math::{vec2, Vec3Swizzles},
+    prelude::*,
+};
+
+use crate::cloud::RMCloud;
+
+/*
+CPU mirror of `cloud()` in cloud.wgsl, sampling the same worley/value data the
+layer textures were built from, so gameplay and camera code can ask where the
+clouds are.
+*/
+
+/// The data behind a layer's worley and value textures.
+pub struct CloudTextures {
+    pub resolution: (usize, usize),
+    pub worley: Vec<f32>,
+    pub value: Vec<f32>,
+}
+
+impl CloudTextures {
+    pub fn sample_worley(&self, uv: Vec2) -> f32 {
+        sample_bilinear(&self.worley, self.resolution, uv)
+    }
+
+    pub fn sample_value(&self, uv: Vec2) -> f32 {
+        sample_bilinear(&self.value, self.resolution, uv)
+    }
+}
+
+/// Linear filtering with repeat addressing, the same as the default image sampler in `main`.
+/// Texels are laid out the way `Image::new` reads them, `data[row * width + column]`.
+/// Returns 0 when `data` doesn't hold `resolution` texels.
+pub fn sample_bilinear(data: &[f32], resolution: (usize, usize), uv: Vec2) -> f32 {
+    if resolution.0 == 0 || resolution.1 == 0 || data.len() < resolution.0 * resolution.1 {
+        return 0.0;
+    }
+    let (width, height) = (resolution.0 as i64, resolution.1 as i64);
+    let x = uv.x * width as f32 - 0.5;
+    let y = uv.y * height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let texel = |column: i64, row: i64| {
+        data[(row.rem_euclid(height) * width + column.rem_euclid(width)) as usize]
+    };
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = texel(x0, y0) * (1. - fx) + texel(x0 + 1, y0) * fx;
+    let bottom = texel(x0, y0 + 1) * (1. - fx) + texel(x0 + 1, y0 + 1) * fx;
+    top * (1. - fy) + bottom * fy
+}
+
+fn smoothstep(e0: f32, e1: f32, x: f32) -> f32 {
+    let t = ((x - e0) / (e1 - e0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+impl RMCloud {
+    /// Same as `world_uv` in cloud.wgsl plus the scroll offset.
+    pub fn uv_at(&self, xz: Vec2) -> Vec2 {
+        vec2(xz.x, -xz.y) / self.size + 0.5 + vec2(0., self.scroll * self.parallax)
+    }
+
+    /// The raw height field `cloud(p)` from cloud.wgsl.
+    pub fn height_at(&self, xz: Vec2, time: f32) -> f32 {
+        let Some(data) = &self.data else {
+            return 0.0;
+        };
+        let p = self.uv_at(xz);
+        let w = data.sample_worley(p - time * 0.01) - self.worley_factor;
+        let z = data.sample_value(p + time * vec2(0.01, -0.01)) - self.value_factor;
+        z * (1. + w) * self.cloud_coef
+    }
+
+    /// Cloud coverage in `0..=1` looking straight down, ignoring the horizon fade.
+    pub fn coverage_at(&self, xz: Vec2, time: f32) -> f32 {
+        smoothstep(
+            self.cloud_height,
+            self.cloud_height + 0.02,
+            self.height_at(xz, time),
+        )
+    }
+
+    /// Coverage inside the layer's slab, zero above or below it.
+    /// `time` is the shader's clock, `Time::raw_elapsed_seconds`.
+    pub fn density_at(&self, world_pos: Vec3, time: f32) -> f32 {
+        if (world_pos.y - self.altitude).abs() > self.thickness * 0.5 {
+            return 0.0;
+        }
+        self.coverage_at(world_pos.xz(), time)
+    }
+
+    /// Fraction of light that makes it along the segment through this layer.
+    /// A vertical pass through the slab lets through `1 - coverage`; longer
+    /// slanted paths count as proportionally more passes.
+    pub fn segment_transmittance(&self, start: Vec3, end: Vec3, time: f32) -> f32 {
+        const SAMPLES: usize = 8;
+        let half = self.thickness.max(0.0) * 0.5;
+        let (low, high) = (self.altitude - half, self.altitude + half);
+        let delta = end - start;
+
+        let (t0, t1) = if delta.y.abs() < f32::EPSILON {
+            if start.y < low || start.y > high {
+                return 1.0;
+            }
+            (0.0, 1.0)
+        } else {
+            let a = (low - start.y) / delta.y;
+            let b = (high - start.y) / delta.y;
+            (a.min(b).max(0.0), a.max(b).min(1.0))
+        };
+        if t0 > t1 {
+            return 1.0;
+        }
+
+        let coverage = (0..SAMPLES)
+            .map(|i| {
+                let t = t0 + (t1 - t0) * (i as f32 + 0.5) / SAMPLES as f32;
+                self.coverage_at((start + delta * t).xz(), time)
+            })
+            .sum::<f32>()
+            / SAMPLES as f32;
+        let passes = if self.thickness > 0.0 {
+            (delta.length() * (t1 - t0) / self.thickness).max(1.0)
+        } else {
+            1.0
+        };
+        (1.0 - coverage).max(0.0).powf(passes)
+    }
+}
+
+/// Occlusion queries against every cloud layer in the scene.
+#[derive(SystemParam)]
+pub struct CloudQuery<'w, 's> {
+    clouds: Query<'w, 's, &'static RMCloud>,
+    time: Res<'w, Time>,
+}
+
+impl<'w, 's> CloudQuery<'w, 's> {
+    pub fn time(&self) -> f32 {
+        self.time.raw_elapsed_seconds()
+    }
+
+    /// The densest layer at `world_pos`.
+    pub fn density_at(&self, world_pos: Vec3) -> f32 {
+        let time = self.time();
+        self.clouds
+            .iter()
+            .map(|cloud| cloud.density_at(world_pos, time))
+            .fold(0.0, f32::max)
+    }
+
+    pub fn segment_transmittance(&self, start: Vec3, end: Vec3) -> f32 {
+        let time = self.time();
+        self.clouds
+            .iter()
+            .map(|cloud| cloud.segment_transmittance(start, end, time))
+            .product()
+    }
+
+    /// 1 for a zero `direction`, which doesn't go through any cloud.
+    pub fn ray_transmittance(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> f32 {
+        let Some(direction) = direction.try_normalize() else {
+            return 1.0;
+        };
+        self.segment_transmittance(origin, origin + direction * max_distance)
+    }
+
+    /// True when less than `threshold` of the light gets through.
+    pub fn is_occluded(&self, start: Vec3, end: Vec3, threshold: f32) -> bool {
+        self.segment_transmittance(start, end) < threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bevy::{ecs::system::SystemState, math::vec3};
+
+    use super::*;
+
+    /// 2×2 textures tiling every 2 units, so the texel centres are at x and z of ±0.5.
+    fn cloud() -> RMCloud {
+        RMCloud {
+            worley_factor: 0.0,
+            value_factor: 0.2,
+            cloud_coef: 1.0,
+            cloud_height: 0.0,
+            altitude: 10.0,
+            size: 2.0,
+            thickness: 2.0,
+            data: Some(Arc::new(CloudTextures {
+                resolution: (2, 2),
+                worley: vec![0.2, 0.4, 0.6, 0.8],
+                value: vec![0.1, 0.7, 0.9, 1.1],
+            })),
+            ..default()
+        }
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "{actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn bilinear_hits_texel_centres() {
+        let data = [0.0, 1.0, 2.0, 3.0];
+        assert_near(sample_bilinear(&data, (2, 2), vec2(0.25, 0.25)), 0.0);
+        assert_near(sample_bilinear(&data, (2, 2), vec2(0.75, 0.25)), 1.0);
+        assert_near(sample_bilinear(&data, (2, 2), vec2(0.25, 0.75)), 2.0);
+        assert_near(sample_bilinear(&data, (2, 2), vec2(0.75, 0.75)), 3.0);
+    }
+
+    #[test]
+    fn bilinear_blends_and_repeats() {
+        let data = [0.0, 1.0, 2.0, 3.0];
+        assert_near(sample_bilinear(&data, (2, 2), vec2(0.5, 0.5)), 1.5);
+        // Half way between the last column and the first one again
+        assert_near(sample_bilinear(&data, (2, 2), vec2(0.0, 0.25)), 0.5);
+        assert_near(sample_bilinear(&data, (2, 2), vec2(1.25, -0.75)), 0.0);
+    }
+
+    #[test]
+    fn bilinear_without_data() {
+        assert_eq!(sample_bilinear(&[], (2, 2), Vec2::ZERO), 0.0);
+        assert_eq!(sample_bilinear(&[1.0], (0, 1), Vec2::ZERO), 0.0);
+        assert_eq!(sample_bilinear(&[1.0, 2.0], (2, 2), Vec2::ZERO), 0.0);
+    }
+
+    #[test]
+    fn height_from_texels() {
+        let cloud = cloud();
+        // (0.4 - 0) worley and (0.7 - 0.2) value: 0.5 * (1 + 0.4)
+        assert_near(cloud.height_at(vec2(0.5, 0.5), 0.0), 0.7);
+        // (0.6 - 0) worley and (0.9 - 0.2) value, the bottom row is at -z
+        assert_near(cloud.height_at(vec2(-0.5, -0.5), 0.0), 0.7 * 1.6);
+        assert_eq!(RMCloud::default().height_at(Vec2::ZERO, 0.0), 0.0);
+    }
+
+    #[test]
+    fn density_inside_the_slab() {
+        let cloud = cloud();
+        assert_near(cloud.density_at(vec3(0.5, 10.5, 0.5), 0.0), 1.0);
+        assert_eq!(cloud.density_at(vec3(0.5, 12.0, 0.5), 0.0), 0.0);
+        // The value texel is under `value_factor`, so the height is negative
+        assert_eq!(cloud.density_at(vec3(-0.5, 10.0, 0.5), 0.0), 0.0);
+    }
+
+    #[test]
+    fn queries_follow_the_generated_textures() {
+        use crate::cloud::{value_texture_data, worley_texture_data};
+
+        const RES: usize = 16;
+        let textures = CloudTextures {
+            resolution: (RES, RES),
+            worley: worley_texture_data((RES, RES), vec2(5., 5.)),
+            value: value_texture_data((RES, RES), vec2(5., 5.)),
+        };
+        let raw_height = |i: usize| (textures.value[i] - 0.3) * (1. + textures.worley[i] - 0.1);
+        let cloud = RMCloud {
+            worley_factor: 0.1,
+            value_factor: 0.3,
+            cloud_coef: 1.0,
+            cloud_height: raw_height(RES * 5 + 9) - 0.01,
+            altitude: 10.0,
+            size: 16.0,
+            thickness: 2.0,
+            data: Some(Arc::new(CloudTextures {
+                resolution: textures.resolution,
+                worley: textures.worley.clone(),
+                value: textures.value.clone(),
+            })),
+            ..default()
+        };
+        // Texel (column, row) has its centre at uv ((column, row) + 0.5) / RES
+        let xz_at = |uv: Vec2| vec2(uv.x - 0.5, 0.5 - uv.y) * cloud.size;
+
+        for (column, row) in [(0, 0), (9, 5), (3, 12), (15, 15)] {
+            let i = row * RES + column;
+            let uv = (vec2(column as f32, row as f32) + 0.5) / RES as f32;
+            let xz = xz_at(uv);
+            assert_near(cloud.height_at(xz, 0.0), raw_height(i));
+            let coverage = smoothstep(cloud.cloud_height, cloud.cloud_height + 0.02, raw_height(i));
+            assert_near(cloud.coverage_at(xz, 0.0), coverage);
+            assert_near(cloud.density_at(xz.extend(10.5).xzy(), 0.0), coverage);
+            assert_eq!(cloud.density_at(xz.extend(11.5).xzy(), 0.0), 0.0);
+        }
+
+        // Half way between texels (4, 7) and (5, 7)
+        let uv = vec2(5.0, 7.5) / RES as f32;
+        let (left, right) = (7 * RES + 4, 7 * RES + 5);
+        assert_near(
+            textures.sample_worley(uv),
+            (textures.worley[left] + textures.worley[right]) * 0.5,
+        );
+        assert_near(
+            textures.sample_value(uv),
+            (textures.value[left] + textures.value[right]) * 0.5,
+        );
+    }
+
+    #[test]
+    fn ray_transmittance() {
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.spawn(cloud());
+        let mut state = SystemState::<CloudQuery>::new(&mut world);
+        let query = state.get(&world);
+
+        let inside = vec3(0.5, 10.0, 0.5);
+        assert_eq!(query.ray_transmittance(inside, Vec3::ZERO, 100.0), 1.0);
+        assert_near(
+            query.ray_transmittance(vec3(0.5, 0.0, 0.5), Vec3::Y, 20.0),
+            0.0,
+        );
+        assert_eq!(query.ray_transmittance(inside, Vec3::NEG_Y, 1.0), 0.0);
+        assert_eq!(
+            query.ray_transmittance(vec3(0.5, 20.0, 0.5), Vec3::Y, 20.0),
+            1.0
+        );
+    }
+}
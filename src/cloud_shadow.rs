@@ -0,0 +1,491 @@
+use bevy::{
+    math::Vec3Swizzles,
+    pbr::{MaterialPipeline, MaterialPipelineKey, StandardMaterialUniform},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_resource::{
+            AsBindGroup, AsBindGroupShaderType, Extent3d, Face, RenderPipelineDescriptor,
+            ShaderRef, SpecializedMeshPipelineError, TextureDimension, TextureFormat,
+        },
+    },
+    utils::HashMap,
+};
+use rayon::prelude::*;
+
+use crate::cloud::RMCloud;
+use crate::environment::{resolve_environment, EnvironmentView, EnvironmentViewPlugin};
+#[cfg(feature = "water")]
+use crate::water::WaterMaterial;
+
+/*
+A top-down map of how much sunlight gets through the cloud layers, rebuilt on
+the CPU around the camera whenever it moves a texel, and every `refresh`
+seconds to follow the drifting clouds and the sun. `projection` takes a world
+position along the sun direction down to the map plane and into uv space, so
+any point under the clouds can look up its shadow.
+
+Meshes tagged with `CloudShadowReceiver` have their `StandardMaterial` swapped
+for a `CloudShadowedMaterial`, which is the standard PBR shader with the
+shadow applied on top. Changes to the original `StandardMaterial` are copied
+over. The water samples the same map when built with the `water` feature.
+*/
+
+pub struct CloudShadowPlugin;
+impl Plugin for CloudShadowPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(MaterialPlugin::<CloudShadowedMaterial>::default());
+        app.init_resource::<CloudShadowMap>();
+        if !app.is_plugin_added::<EnvironmentViewPlugin>() {
+            app.add_plugin(EnvironmentViewPlugin);
+        }
+        app.init_resource::<SwappedMaterials>();
+        app.add_system(update_shadow_map.after(resolve_environment));
+        app.add_system(swap_receiver_materials);
+        app.add_system(sync_swapped_materials.after(swap_receiver_materials));
+        app.add_system(update_shadowed_materials::<CloudShadowedMaterial>.after(update_shadow_map));
+        #[cfg(feature = "water")]
+        app.add_system(update_shadowed_materials::<WaterMaterial>.after(update_shadow_map));
+    }
+}
+
+#[derive(Resource)]
+pub struct CloudShadowMap {
+    pub image: Handle<Image>,
+    /// World position to shadow map uv in `xy`.
+    pub projection: Mat4,
+    pub resolution: u32,
+    /// World size covered by the map, centred on the camera.
+    pub extent: f32,
+    /// Height of the plane the map is projected onto.
+    pub ground_height: f32,
+    /// How much light is left in full shadow, standing in for the unshadowed sky light.
+    pub floor: f32,
+    /// Seconds between rebakes while the camera stays within a texel.
+    pub refresh: f32,
+}
+
+impl FromWorld for CloudShadowMap {
+    fn from_world(world: &mut World) -> Self {
+        let resolution = 128;
+        let image = world.resource_mut::<Assets<Image>>().add(Image::new(
+            Extent3d {
+                width: resolution,
+                height: resolution,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            vec![255; (resolution * resolution) as usize],
+            TextureFormat::R8Unorm,
+        ));
+        Self {
+            image,
+            projection: Mat4::IDENTITY,
+            resolution,
+            extent: 4000.0,
+            ground_height: 0.0,
+            floor: 0.35,
+            refresh: 0.25,
+        }
+    }
+}
+
+impl CloudShadowMap {
+    /// `camera` snapped to the texel grid, so the map only moves in whole texels.
+    fn center_for(&self, camera: Vec2) -> Vec2 {
+        let texel = self.extent / self.resolution as f32;
+        (camera / texel).round() * texel
+    }
+
+    fn projection_for(&self, center: Vec2, sun_direction: Vec3) -> Mat4 {
+        let k = sun_direction.xz() / sun_direction.y;
+        let g = self.ground_height;
+        let e = self.extent;
+        Mat4::from_cols(
+            Vec4::new(1.0 / e, 0.0, 0.0, 0.0),
+            Vec4::new(-k.x / e, -k.y / e, 0.0, 0.0),
+            Vec4::new(0.0, 1.0 / e, 0.0, 0.0),
+            Vec4::new(
+                (k.x * g - center.x) / e + 0.5,
+                (k.y * g - center.y) / e + 0.5,
+                0.0,
+                1.0,
+            ),
+        )
+    }
+}
+
+/// Marks a mesh, or the root of a scene, whose standard materials should receive cloud shadows.
+#[derive(Component, Default)]
+pub struct CloudShadowReceiver;
+
+/// A material that samples the `CloudShadowMap`.
+pub trait CloudShadowed: Material {
+    fn set_cloud_shadow(&mut self, map: &CloudShadowMap);
+}
+
+/// The centre and time of the last bake.
+#[derive(Default)]
+struct LastBake(Option<(Vec2, f32)>);
+
+impl LastBake {
+    /// Whether the map needs baking at `center`, either because it moved or because `refresh`
+    /// seconds have passed.
+    fn is_due(&self, center: Vec2, time: f32, refresh: f32) -> bool {
+        match self.0 {
+            Some((last_center, last_time)) => last_center != center || time - last_time >= refresh,
+            None => true,
+        }
+    }
+}
+
+fn update_shadow_map(
+    mut map: ResMut<CloudShadowMap>,
+    mut images: ResMut<Assets<Image>>,
+    view: Res<EnvironmentView>,
+    clouds: Query<&RMCloud>,
+    mut last_bake: Local<LastBake>,
+) {
+    if !view.is_resolved() {
+        return;
+    }
+    let center = map.center_for(view.camera_position().xz());
+    if !last_bake.is_due(center, view.time, map.refresh) {
+        return;
+    }
+    last_bake.0 = Some((center, view.time));
+
+    let mut sun_direction = view.sun_direction;
+    // Keep the projection finite when the sun sits on the horizon
+    sun_direction.y = sun_direction.y.min(-0.05);
+    let sun_direction = sun_direction.normalize();
+    map.projection = map.projection_for(center, sun_direction);
+
+    let time = view.time;
+    let clouds = clouds.iter().collect::<Vec<_>>();
+    let resolution = map.resolution as usize;
+    let (extent, ground) = (map.extent, map.ground_height);
+    let Some(image) = images.get_mut(&map.image) else {
+        return;
+    };
+    image
+        .data
+        .par_chunks_mut(resolution)
+        .enumerate()
+        .for_each(|(row, texels)| {
+            for (column, texel) in texels.iter_mut().enumerate() {
+                let uv = (Vec2::new(column as f32, row as f32) + 0.5) / resolution as f32;
+                let xz = center + (uv - 0.5) * extent;
+                let start = Vec3::new(xz.x, ground, xz.y);
+                let end = start - sun_direction * 100_000.;
+                let transmittance = clouds
+                    .iter()
+                    .map(|cloud| cloud.segment_transmittance(start, end, time))
+                    .product::<f32>();
+                *texel = (transmittance.clamp(0.0, 1.0) * 255.0) as u8;
+            }
+        });
+}
+
+/// The `CloudShadowedMaterial` standing in for each swapped `StandardMaterial`. The keys are
+/// weak, so an entry is dropped once nothing else holds its `StandardMaterial`.
+#[derive(Resource, Default)]
+struct SwappedMaterials(HashMap<Handle<StandardMaterial>, Handle<CloudShadowedMaterial>>);
+
+#[allow(clippy::too_many_arguments)]
+fn swap_receiver_materials(
+    mut commands: Commands,
+    receivers: Query<Entity, With<CloudShadowReceiver>>,
+    children: Query<&Children>,
+    standard: Query<&Handle<StandardMaterial>>,
+    standard_materials: Res<Assets<StandardMaterial>>,
+    mut shadowed_materials: ResMut<Assets<CloudShadowedMaterial>>,
+    map: Res<CloudShadowMap>,
+    mut swapped: ResMut<SwappedMaterials>,
+) {
+    // Scenes spawn their meshes a few frames after the root, so keep looking
+    for root in &receivers {
+        let entities = std::iter::once(root).chain(children.iter_descendants(root));
+        for entity in entities {
+            let Ok(handle) = standard.get(entity) else {
+                continue;
+            };
+            let Some(material) = standard_materials.get(handle) else {
+                continue;
+            };
+            let shadowed = swapped
+                .0
+                .entry(handle.clone_weak())
+                .or_insert_with(|| {
+                    shadowed_materials.add(CloudShadowedMaterial::new(material.clone(), &map))
+                })
+                .clone();
+            commands
+                .entity(entity)
+                .remove::<Handle<StandardMaterial>>()
+                .insert(shadowed);
+        }
+    }
+}
+
+/// Copies edits of a swapped `StandardMaterial` to the material standing in for it, and
+/// forgets the pair once the `StandardMaterial` is gone.
+fn sync_swapped_materials(
+    mut events: EventReader<AssetEvent<StandardMaterial>>,
+    standard_materials: Res<Assets<StandardMaterial>>,
+    mut shadowed_materials: ResMut<Assets<CloudShadowedMaterial>>,
+    mut swapped: ResMut<SwappedMaterials>,
+) {
+    for event in events.iter() {
+        match event {
+            AssetEvent::Modified { handle } => {
+                let (Some(shadowed), Some(material)) =
+                    (swapped.0.get(handle), standard_materials.get(handle))
+                else {
+                    continue;
+                };
+                if let Some(shadowed) = shadowed_materials.get_mut(shadowed) {
+                    shadowed.set_base(material.clone());
+                }
+            }
+            AssetEvent::Removed { handle } => {
+                swapped.0.remove(handle);
+            }
+            AssetEvent::Created { .. } => {}
+        }
+    }
+}
+
+/// Points every `M` at the shadow map after it's rebaked, and new ones straight away.
+fn update_shadowed_materials<M: CloudShadowed>(
+    map: Res<CloudShadowMap>,
+    mut events: EventReader<AssetEvent<M>>,
+    mut materials: ResMut<Assets<M>>,
+) {
+    if map.is_changed() {
+        events.clear();
+        for (_, material) in materials.iter_mut() {
+            material.set_cloud_shadow(&map);
+        }
+        return;
+    }
+    let created = events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } => Some(handle.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for handle in created {
+        if let Some(material) = materials.get_mut(&handle) {
+            material.set_cloud_shadow(&map);
+        }
+    }
+}
+
+/// `StandardMaterial` plus the cloud shadow map. The texture fields mirror the ones on `base`
+/// and use the same bindings, so the shader can import `bevy_pbr::pbr_bindings` as is.
+#[derive(AsBindGroup, TypeUuid, Debug, Clone)]
+#[uuid = "3d0f2b9e-71a4-4c1f-9a53-2be0c4d1f6a7"]
+#[bind_group_data(CloudShadowedMaterialKey)]
+#[uniform(0, StandardMaterialUniform)]
+pub struct CloudShadowedMaterial {
+    pub base: StandardMaterial,
+    #[texture(1)]
+    #[sampler(2)]
+    base_color_texture: Option<Handle<Image>>,
+    #[texture(3)]
+    #[sampler(4)]
+    emissive_texture: Option<Handle<Image>>,
+    #[texture(5)]
+    #[sampler(6)]
+    metallic_roughness_texture: Option<Handle<Image>>,
+    #[texture(7)]
+    #[sampler(8)]
+    occlusion_texture: Option<Handle<Image>>,
+    #[texture(9)]
+    #[sampler(10)]
+    normal_map_texture: Option<Handle<Image>>,
+    #[texture(13)]
+    #[sampler(14)]
+    pub shadow_map: Option<Handle<Image>>,
+    #[uniform(15)]
+    pub shadow_projection: Mat4,
+    #[uniform(15)]
+    pub shadow_floor: f32,
+}
+
+impl CloudShadowedMaterial {
+    pub fn new(base: StandardMaterial, map: &CloudShadowMap) -> Self {
+        let mut material = Self {
+            base_color_texture: None,
+            emissive_texture: None,
+            metallic_roughness_texture: None,
+            occlusion_texture: None,
+            normal_map_texture: None,
+            base: StandardMaterial::default(),
+            shadow_map: None,
+            shadow_projection: Mat4::IDENTITY,
+            shadow_floor: 1.0,
+        };
+        material.set_base(base);
+        material.set_cloud_shadow(map);
+        material
+    }
+
+    /// Replaces the standard material underneath, along with its textures.
+    pub fn set_base(&mut self, base: StandardMaterial) {
+        self.base_color_texture = base.base_color_texture.clone();
+        self.emissive_texture = base.emissive_texture.clone();
+        self.metallic_roughness_texture = base.metallic_roughness_texture.clone();
+        self.occlusion_texture = base.occlusion_texture.clone();
+        self.normal_map_texture = base.normal_map_texture.clone();
+        self.base = base;
+    }
+}
+
+impl CloudShadowed for CloudShadowedMaterial {
+    fn set_cloud_shadow(&mut self, map: &CloudShadowMap) {
+        self.shadow_map = Some(map.image.clone());
+        self.shadow_projection = map.projection;
+        self.shadow_floor = map.floor;
+    }
+}
+
+#[cfg(feature = "water")]
+impl CloudShadowed for WaterMaterial {
+    fn set_cloud_shadow(&mut self, map: &CloudShadowMap) {
+        self.shadow_map = Some(map.image.clone());
+        self.shadow_projection = map.projection;
+        self.shadow_floor = map.floor;
+    }
+}
+
+impl AsBindGroupShaderType<StandardMaterialUniform> for CloudShadowedMaterial {
+    fn as_bind_group_shader_type(&self, images: &RenderAssets<Image>) -> StandardMaterialUniform {
+        self.base.as_bind_group_shader_type(images)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct CloudShadowedMaterialKey {
+    normal_map: bool,
+    cull_mode: Option<Face>,
+}
+
+impl From<&CloudShadowedMaterial> for CloudShadowedMaterialKey {
+    fn from(material: &CloudShadowedMaterial) -> Self {
+        Self {
+            normal_map: material.base.normal_map_texture.is_some(),
+            cull_mode: material.base.cull_mode,
+        }
+    }
+}
+
+impl Material for CloudShadowedMaterial {
+    fn fragment_shader() -> ShaderRef {
+        "shaders/cloud_shadowed.wgsl".into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        self.base.alpha_mode
+    }
+
+    fn depth_bias(&self) -> f32 {
+        self.base.depth_bias
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if key.bind_group_data.normal_map {
+            if let Some(fragment) = descriptor.fragment.as_mut() {
+                fragment
+                    .shader_defs
+                    .push("STANDARDMATERIAL_NORMAL_MAP".into());
+            }
+        }
+        descriptor.primitive.cull_mode = key.bind_group_data.cull_mode;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> CloudShadowMap {
+        CloudShadowMap {
+            image: Handle::default(),
+            projection: Mat4::IDENTITY,
+            resolution: 100,
+            extent: 1000.0,
+            ground_height: 20.0,
+            floor: 0.35,
+            refresh: 0.25,
+        }
+    }
+
+    fn uv(projection: Mat4, world: Vec3) -> Vec2 {
+        projection.transform_point3(world).xy()
+    }
+
+    #[test]
+    fn projection_covers_the_map() {
+        let map = map();
+        let center = Vec2::new(300.0, -100.0);
+        let sun_direction = Vec3::new(0.4, -1.0, -0.2).normalize();
+        let projection = map.projection_for(center, sun_direction);
+        let half = map.extent / 2.0;
+        let g = map.ground_height;
+        let corners = [
+            (
+                Vec3::new(center.x - half, g, center.y - half),
+                Vec2::new(0.0, 0.0),
+            ),
+            (
+                Vec3::new(center.x + half, g, center.y - half),
+                Vec2::new(1.0, 0.0),
+            ),
+            (
+                Vec3::new(center.x - half, g, center.y + half),
+                Vec2::new(0.0, 1.0),
+            ),
+            (
+                Vec3::new(center.x + half, g, center.y + half),
+                Vec2::new(1.0, 1.0),
+            ),
+            (Vec3::new(center.x, g, center.y), Vec2::splat(0.5)),
+        ];
+        for (world, expected) in corners {
+            assert!(uv(projection, world).abs_diff_eq(expected, 1e-5), "{world}");
+            // Anywhere up the sun ray shares the shadow of where it meets the ground
+            let above = world - sun_direction * 250.0;
+            assert!(uv(projection, above).abs_diff_eq(expected, 1e-4), "{above}");
+        }
+    }
+
+    #[test]
+    fn rebakes_when_due() {
+        let map = map();
+        let texel = map.extent / map.resolution as f32;
+        let center = map.center_for(Vec2::new(5.0, 5.0));
+        let last = LastBake(Some((center, 10.0)));
+        assert!(LastBake::default().is_due(center, 0.0, map.refresh));
+        // Within the texel and the refresh time
+        let nudged = map.center_for(Vec2::new(5.0 + texel * 0.4, 5.0));
+        assert_eq!(nudged, center);
+        assert!(!last.is_due(nudged, 10.1, map.refresh));
+        // A texel over
+        let moved = map.center_for(Vec2::new(5.0 + texel, 5.0));
+        assert_ne!(moved, center);
+        assert!(last.is_due(moved, 10.1, map.refresh));
+        // Time to follow the clouds
+        assert!(last.is_due(center, 10.25, map.refresh));
+    }
+}
@@ -0,0 +1,45 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Reflect, FromReflect, Serialize, Deserialize,
+)]
+pub enum Easing {
+    Linear,
+    #[default]
+    SmoothStep,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    /// Maps linear progress `t` in `0..=1` onto the eased curve.
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::SmoothStep => t * t * (3.0 - 2.0 * t),
+            Easing::EaseIn => t * t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t).powi(3),
+            Easing::EaseInOut => {
+                if t < 0.5 {
+                    4.0 * t * t * t
+                } else {
+                    1.0 - (-2.0 * t + 2.0).powi(3) * 0.5
+                }
+            }
+        }
+    }
+}
+
+pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a * (1. - t) + b * t
+}
+
+pub fn lerp_color(a: Color, b: Color, t: f32) -> Color {
+    let a = Vec4::from(a.as_rgba_f32());
+    let b = Vec4::from(b.as_rgba_f32());
+    let c = a.lerp(b, t);
+    Color::rgba(c.x, c.y, c.z, c.w)
+}
@@ -0,0 +1,147 @@
+use bevy::prelude::*;
+
+use crate::camera::{camera_controller, CameraController};
+use crate::scene::SceneSun;
+
+/*
+The camera, sun and time every environment material shades with.
+
+`resolve_environment` picks one main camera and one sun each frame and stores
+them in `EnvironmentView`. Materials implement `EnvironmentUniforms` and are
+registered with `add_environment_uniforms`, which copies the view into every
+material of that type. When there is no camera or no sun the materials keep
+their last values.
+
+The camera and sun are placed in world space, through their parents. Transform
+propagation only runs after `Update`, so the parent's `GlobalTransform` is
+combined with this frame's local `Transform` rather than reading a
+`GlobalTransform` that is a frame behind the camera controller.
+*/
+
+/// Marks the camera the environment is shaded for. Without one, the active
+/// `CameraController` camera with the highest order is used, then any active camera.
+#[derive(Component, Default)]
+pub struct MainCamera;
+
+/// The main camera and sun for this frame.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct EnvironmentView {
+    pub camera: Option<Entity>,
+    pub camera_transform: Transform,
+    pub sun: Option<Entity>,
+    /// The direction the sunlight travels in.
+    pub sun_direction: Vec3,
+    pub time: f32,
+}
+
+impl Default for EnvironmentView {
+    fn default() -> Self {
+        Self {
+            camera: None,
+            camera_transform: Transform::IDENTITY,
+            sun: None,
+            sun_direction: Vec3::NEG_Y,
+            time: 0.0,
+        }
+    }
+}
+
+impl EnvironmentView {
+    pub fn camera_position(&self) -> Vec3 {
+        self.camera_transform.translation
+    }
+
+    /// Both a camera and a sun were found, so the view is safe to shade with.
+    pub fn is_resolved(&self) -> bool {
+        self.camera.is_some() && self.sun.is_some()
+    }
+}
+
+/// A material with camera, sun or time uniforms.
+pub trait EnvironmentUniforms: Material {
+    fn set_environment(&mut self, view: &EnvironmentView);
+}
+
+pub struct EnvironmentViewPlugin;
+
+impl Plugin for EnvironmentViewPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<EnvironmentView>();
+        app.add_system(resolve_environment.after(camera_controller));
+    }
+}
+
+/// Keeps every `M` in sync with the `EnvironmentView`, adding `EnvironmentViewPlugin` if needed.
+pub fn add_environment_uniforms<M: EnvironmentUniforms>(app: &mut App) {
+    if !app.is_plugin_added::<EnvironmentViewPlugin>() {
+        app.add_plugin(EnvironmentViewPlugin);
+    }
+    app.add_system(sync_environment_uniforms::<M>.after(resolve_environment));
+}
+
+/// `transform` in world space, below the `GlobalTransform` of its parent if it has one.
+fn world_transform(
+    transform: &Transform,
+    parent: Option<&Parent>,
+    globals: &Query<&GlobalTransform>,
+) -> Transform {
+    match parent.and_then(|parent| globals.get(parent.get()).ok()) {
+        Some(parent) => parent.mul_transform(*transform).compute_transform(),
+        None => *transform,
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn resolve_environment(
+    mut view: ResMut<EnvironmentView>,
+    cameras: Query<(
+        Entity,
+        &Camera,
+        &Transform,
+        Option<&Parent>,
+        Option<&MainCamera>,
+        Option<&CameraController>,
+    )>,
+    suns: Query<(
+        Entity,
+        &DirectionalLight,
+        &Transform,
+        Option<&Parent>,
+        Option<&SceneSun>,
+    )>,
+    globals: Query<&GlobalTransform>,
+    time: Res<Time>,
+) {
+    let camera = cameras
+        .iter()
+        .filter(|(_, camera, ..)| camera.is_active)
+        .max_by_key(|(_, camera, _, _, main, controller)| {
+            (main.is_some(), controller.is_some(), camera.order)
+        });
+    view.camera = camera.map(|(entity, ..)| entity);
+    if let Some((_, _, transform, parent, ..)) = camera {
+        view.camera_transform = world_transform(transform, parent, &globals);
+    }
+
+    let sun = suns.iter().max_by(|a, b| {
+        (a.4.is_some().cmp(&b.4.is_some())).then(a.1.illuminance.total_cmp(&b.1.illuminance))
+    });
+    view.sun = sun.map(|(entity, ..)| entity);
+    if let Some((_, _, transform, parent, _)) = sun {
+        view.sun_direction = world_transform(transform, parent, &globals).forward();
+    }
+
+    view.time = time.raw_elapsed_seconds();
+}
+
+pub fn sync_environment_uniforms<M: EnvironmentUniforms>(
+    view: Res<EnvironmentView>,
+    mut materials: ResMut<Assets<M>>,
+) {
+    if !view.is_resolved() {
+        return;
+    }
+    for (_, material) in materials.iter_mut() {
+        material.set_environment(&view);
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{noise, CameraController};
+use crate::environment::{
+    add_environment_uniforms, resolve_environment, EnvironmentUniforms, EnvironmentView,
+};
+use crate::noise;
+use crate::phase::add_phase_shader;
 use bevy::{
     math::{dvec2, dvec3, ivec3, vec2, vec3, vec4, DVec2, DVec3},
     pbr::{MaterialPipeline, MaterialPipelineKey},
@@ -28,22 +32,23 @@ pub struct FinCloudPlugin;
 impl Plugin for FinCloudPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(MaterialPlugin::<FinCloudMaterial>::default());
-        app.add_system(update_cloud);
+        add_environment_uniforms::<FinCloudMaterial>(app);
+        add_phase_shader(app);
+        app.add_system(update_cloud.after(resolve_environment));
         app.add_startup_system(setup);
     }
 }
 
+/// Picks the fin draw order for the direction the main camera is looking in.
 fn update_cloud(
-    camera: Query<&Transform, With<CameraController>>,
-    sun: Query<&Transform, With<DirectionalLight>>,
+    view: Res<EnvironmentView>,
     clouds: Query<&FinCloudBase>,
-    mut materials: ResMut<Assets<FinCloudMaterial>>,
     mut meshes: ResMut<Assets<Mesh>>,
 ) {
-    let camera = camera.get_single().unwrap();
-    let view = camera.forward();
-    let camera_position = camera.translation;
-    let sun_facing = sun.get_single().unwrap().forward();
+    if view.camera.is_none() {
+        return;
+    }
+    let view = view.camera_transform.forward();
     for fin_cloud in &clouds {
         if let Some(mesh) = meshes.get_mut(&fin_cloud.mesh) {
             let iview = view.as_ivec3();
@@ -73,10 +78,6 @@ fn update_cloud(
             let key = sign * IVec3::from(weight);
             mesh.set_indices(Some(Indices::U32(fin_cloud.indices[&key].clone())))
         }
-        if let Some(material) = materials.get_mut(&fin_cloud.material) {
-            material.camera_position = camera_position;
-            material.sun_direction = sun_facing;
-        }
     }
 }
 
@@ -96,11 +97,13 @@ fn setup(
     )
     .expect("Extraction failed");
     let resoluiton = (2048, 2048);
-    let new_mesh_data = generate_fin_data(&base_mesh_data, 1., resoluiton);
-    let sorted_indices = voluetric_sort_and_cull(&new_mesh_data.indices, &new_mesh_data.positions);
-    let position_texture = rasterize_uv(&new_mesh_data, base_mesh_data.indices.len(), resoluiton);
-    let cloud_texture = generate_cloud_texture(&new_mesh_data, &position_texture, resoluiton);
-    let mesh = meshes.add(new_mesh_data.into());
+    let fin_data = generate_fin_data(&base_mesh_data, 1.);
+    let sorted_indices =
+        voluetric_sort_and_cull(&base_mesh_data.indices, &base_mesh_data.positions);
+    let position_texture = rasterize_uv(&base_mesh_data, base_mesh_data.indices.len(), resoluiton);
+    let cloud_texture = generate_cloud_texture(&base_mesh_data, &position_texture, resoluiton);
+    let mesh = meshes.add(base_mesh_data.into());
+    let fin_mesh = meshes.add(fin_data.into());
     let material = materials.add(FinCloudMaterial {
         texture: Some(
             images.add(Image::new(
@@ -131,30 +134,25 @@ fn setup(
     commands
         .spawn((
             FinCloudBase {
-                material: material.clone(),
                 indices: sorted_indices,
                 mesh: mesh.clone(),
             },
             MaterialMeshBundle {
                 mesh,
-                material,
+                material: material.clone(),
                 ..default()
             },
         ))
-        .push_children(&[commands
-            .spawn((
-                FinCloudFin {
-                    material: material.clone(),
-                    indices: sorted_indices,
-                    mesh: mesh.clone(),
-                },
+        .with_children(|parent| {
+            parent.spawn((
+                FinCloudFin,
                 MaterialMeshBundle {
-                    mesh,
+                    mesh: fin_mesh,
                     material,
                     ..default()
                 },
-            ))
-            .id()]);
+            ));
+        });
 }
 
 fn generate_cloud_texture(
@@ -231,14 +229,14 @@ fn extract_mesh_data(mesh: Mesh) -> Option<MeshData> {
     })
 }
 
-fn generate_fin_data(data: &MeshData, scale: f64, resolution: (usize, usize)) -> FinData {
+fn generate_fin_data(data: &MeshData, scale: f64) -> FinData {
     let num_indices = data.indices.len();
     let num_vertices = data.positions.len();
     let new_buffer_size = num_vertices + num_indices * 9 / 2;
     let mut indices = Vec::with_capacity(num_indices * 3);
     let mut positions = Vec::with_capacity(new_buffer_size);
+    let mut normals = Vec::with_capacity(new_buffer_size);
     let mut uvs = Vec::with_capacity(new_buffer_size);
-    // Bool flag to signal a vertex on the transparent edge
 
     /*
     A fin is a square extruded out from an edge on the mesh
@@ -295,12 +293,17 @@ fn generate_fin_data(data: &MeshData, scale: f64, resolution: (usize, usize)) ->
             fin_positions[(edge.1 * 2) as usize],
             fin_positions[(edge.1 * 2) as usize + 1],
         ]);
+        // u runs from the edge out to the tip, the shader fades the fin out along it
         uvs.extend_from_slice(&[
-            dvec3(0.0, 1.0, i as f64),
-            dvec3(1.0, 1.0, i as f64),
-            dvec3(0.0, 0.0, i as f64),
-            dvec3(1.0, 0.0, i as f64),
+            dvec2(0.0, 1.0),
+            dvec2(1.0, 1.0),
+            dvec2(0.0, 0.0),
+            dvec2(1.0, 0.0),
         ]);
+        let along = fin_positions[(edge.1 * 2) as usize] - fin_positions[(edge.0 * 2) as usize];
+        let out = fin_positions[(edge.0 * 2) as usize + 1] - fin_positions[(edge.0 * 2) as usize];
+        let normal = along.cross(out).normalize_or_zero();
+        normals.extend_from_slice(&[normal; 4]);
         let i_vert = i * 4;
         let a = (i_vert) as u32;
         let b = (i_vert + 1) as u32;
@@ -309,24 +312,18 @@ fn generate_fin_data(data: &MeshData, scale: f64, resolution: (usize, usize)) ->
 
         indices.extend_from_slice(&[a, d, b]);
         indices.extend_from_slice(&[a, c, d]);
-        i_vert += 4;
     }
 
-    let num_fins = (positions.len() / 4) as f64;
-    uvs.iter_mut().map(|z| *z /= num_fins);
-
     FinData {
         positions,
+        normals,
         uvs,
         indices,
     }
 }
 
-struct FinData {
-    positions: Vec<DVec3>,
-    uvs: Vec<DVec3>,
-    indices: Vec<u32>,
-}
+/// Fins share the `MeshData` layout so they convert to a `Mesh` the same way.
+type FinData = MeshData;
 
 struct Triangle {
     mid_point: DVec3,
@@ -554,9 +551,12 @@ fn raster<F: FnMut(DVec2)>(a: DVec2, b: DVec2, c: DVec2, mut f: F) {
     }
 }
 
+/// Marks the fin mesh spawned as a child of each `FinCloudBase`.
+#[derive(Component)]
+struct FinCloudFin;
+
 #[derive(Component, Default)]
 struct FinCloudBase {
-    material: Handle<FinCloudMaterial>,
     mesh: Handle<Mesh>,
     indices: HashMap<IVec3, Vec<u32>>,
 }
@@ -597,6 +597,13 @@ impl Material for FinCloudMaterial {
     }
 }
 
+impl EnvironmentUniforms for FinCloudMaterial {
+    fn set_environment(&mut self, view: &EnvironmentView) {
+        self.camera_position = view.camera_position();
+        self.sun_direction = view.sun_direction;
+    }
+}
+
 struct MeshData {
     positions: Vec<DVec3>,
     normals: Vec<DVec3>,
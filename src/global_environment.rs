@@ -0,0 +1,309 @@
+//! The sun, wind, fog and time in one uniform buffer, bound at group 3 of every material
+//! registered with `add_global_environment`. Shaders read it with
+//! `#import resume::environment` and get the camera position from bevy's `view`.
+//!
+//! Bevy's material pipeline only knows about groups 0 to 2, so registering a material swaps
+//! its draw function for one that also sets group 3, and the material's `specialize` has to
+//! call `specialize_global_environment` to add the matching layout.
+
+use std::hash::Hash;
+use std::sync::OnceLock;
+
+use bevy::{
+    asset::load_internal_asset,
+    core_pipeline::core_3d::{AlphaMask3d, Opaque3d, Transparent3d},
+    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    pbr::{
+        DrawMesh, MaterialPipelineKey, MeshPipelineKey, SetMaterialBindGroup, SetMeshBindGroup,
+        SetMeshViewBindGroup,
+    },
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        extract_resource::ExtractResourcePlugin,
+        render_phase::{
+            CachedRenderPipelinePhaseItem, DrawFunctions, PhaseItem, RenderCommand,
+            RenderCommandResult, RenderCommandState, SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, BufferBindingType,
+            RenderPipelineDescriptor, ShaderStages, ShaderType, UniformBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp, RenderSet,
+    },
+};
+
+use crate::environment::{resolve_environment, EnvironmentView, EnvironmentViewPlugin};
+
+pub const ENVIRONMENT_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 0x3f5a_91c2_7d04_e6b8);
+
+/// After bevy's view, material and mesh groups.
+pub const ENVIRONMENT_GROUP: usize = 3;
+
+// `Material::specialize` can't reach the render world, so the layout lives here
+static ENVIRONMENT_LAYOUT: OnceLock<BindGroupLayout> = OnceLock::new();
+
+/// Drifts `offset` by `velocity` units per second, for scrolling clouds and waves together.
+#[derive(Resource, Clone, Copy, Debug, Default, Reflect)]
+#[reflect(Resource)]
+pub struct Wind {
+    pub velocity: Vec2,
+    pub offset: Vec2,
+}
+
+pub use uniform::GlobalEnvironment;
+
+mod uniform {
+    // The `ShaderType` derive emits a `check` fn per field that rustc reports as unused
+    #![allow(dead_code)]
+
+    use bevy::{
+        prelude::*,
+        render::{extract_resource::ExtractResource, render_resource::ShaderType},
+    };
+
+    /// What the shaders see as `environment`, laid out to match `environment.wgsl`.
+    #[derive(Resource, ExtractResource, ShaderType, Clone, Debug)]
+    pub struct GlobalEnvironment {
+        pub sun_direction: Vec3,
+        pub sun_illuminance: f32,
+        pub sun_color: Vec4,
+        pub wind_offset: Vec2,
+        pub wind_velocity: Vec2,
+        pub fog_color: Vec4,
+        pub fog_params: Vec4,
+        pub time: f32,
+    }
+}
+
+impl Default for GlobalEnvironment {
+    fn default() -> Self {
+        Self {
+            sun_direction: Vec3::NEG_Y,
+            sun_illuminance: 0.0,
+            sun_color: Vec4::ONE,
+            wind_offset: Vec2::ZERO,
+            wind_velocity: Vec2::ZERO,
+            fog_color: Vec4::ZERO,
+            fog_params: Vec4::ZERO,
+            time: 0.0,
+        }
+    }
+}
+
+pub struct GlobalEnvironmentPlugin;
+
+impl Plugin for GlobalEnvironmentPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            ENVIRONMENT_SHADER_HANDLE,
+            "../assets/shaders/environment.wgsl",
+            Shader::from_wgsl
+        );
+        if !app.is_plugin_added::<EnvironmentViewPlugin>() {
+            app.add_plugin(EnvironmentViewPlugin);
+        }
+        app.register_type::<Wind>();
+        app.init_resource::<Wind>();
+        app.init_resource::<GlobalEnvironment>();
+        app.add_system(update_global_environment.after(resolve_environment));
+        app.add_plugin(ExtractResourcePlugin::<GlobalEnvironment>::default());
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .init_resource::<EnvironmentBindGroup>()
+                .add_system(prepare_environment_bind_group.in_set(RenderSet::Prepare));
+        }
+    }
+}
+
+/// Binds the environment for `M`. Add `MaterialPlugin::<M>` first, and call
+/// `specialize_global_environment` from `M::specialize`.
+pub fn add_global_environment<M: Material>(app: &mut App)
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    if !app.is_plugin_added::<GlobalEnvironmentPlugin>() {
+        app.add_plugin(GlobalEnvironmentPlugin);
+    }
+    if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+        replace_draw_function::<Opaque3d, M>(&mut render_app.world);
+        replace_draw_function::<AlphaMask3d, M>(&mut render_app.world);
+        replace_draw_function::<Transparent3d, M>(&mut render_app.world);
+    }
+}
+
+/// Adds the environment layout to main pass pipelines. Prepass and shadow pipelines are left
+/// alone, their draw functions don't set group 3.
+pub fn specialize_global_environment<M: Material>(
+    descriptor: &mut RenderPipelineDescriptor,
+    key: &MaterialPipelineKey<M>,
+) {
+    if key
+        .mesh_key
+        .intersects(MeshPipelineKey::DEPTH_PREPASS | MeshPipelineKey::NORMAL_PREPASS)
+    {
+        return;
+    }
+    if let Some(layout) = ENVIRONMENT_LAYOUT.get() {
+        descriptor.layout.truncate(ENVIRONMENT_GROUP);
+        descriptor.layout.push(layout.clone());
+    }
+}
+
+fn update_global_environment(
+    view: Res<EnvironmentView>,
+    mut wind: ResMut<Wind>,
+    mut environment: ResMut<GlobalEnvironment>,
+    lights: Query<&DirectionalLight>,
+    fog: Query<&FogSettings>,
+    time: Res<Time>,
+) {
+    let velocity = wind.velocity;
+    wind.offset += velocity * time.delta_seconds();
+
+    environment.sun_direction = view.sun_direction;
+    if let Some(light) = view.sun.and_then(|sun| lights.get(sun).ok()) {
+        environment.sun_color = Vec4::from(light.color.as_linear_rgba_f32());
+        environment.sun_illuminance = light.illuminance;
+    }
+    environment.wind_offset = wind.offset;
+    environment.wind_velocity = wind.velocity;
+    match view.camera.and_then(|camera| fog.get(camera).ok()) {
+        Some(fog) => {
+            environment.fog_color = Vec4::from(fog.color.as_linear_rgba_f32());
+            environment.fog_params = match fog.falloff {
+                FogFalloff::Linear { start, end } => Vec4::new(1.0, start, end, 0.0),
+                FogFalloff::Exponential { density } => Vec4::new(2.0, density, 0.0, 0.0),
+                FogFalloff::ExponentialSquared { density } => Vec4::new(3.0, density, 0.0, 0.0),
+                // Approximated by its average extinction
+                FogFalloff::Atmospheric { extinction, .. } => Vec4::new(
+                    2.0,
+                    (extinction.x + extinction.y + extinction.z) / 3.0,
+                    0.0,
+                    0.0,
+                ),
+            };
+        }
+        None => {
+            environment.fog_color = Vec4::ZERO;
+            environment.fog_params = Vec4::ZERO;
+        }
+    }
+    environment.time = view.time;
+}
+
+#[derive(Resource)]
+pub struct EnvironmentBindGroup {
+    layout: BindGroupLayout,
+    buffer: UniformBuffer<GlobalEnvironment>,
+    bind_group: Option<BindGroup>,
+}
+
+impl FromWorld for EnvironmentBindGroup {
+    fn from_world(world: &mut World) -> Self {
+        let layout =
+            world
+                .resource::<RenderDevice>()
+                .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    label: Some("environment_layout"),
+                    entries: &[BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::VERTEX_FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: Some(GlobalEnvironment::min_size()),
+                        },
+                        count: None,
+                    }],
+                });
+        let _ = ENVIRONMENT_LAYOUT.set(layout.clone());
+        Self {
+            layout,
+            buffer: UniformBuffer::default(),
+            bind_group: None,
+        }
+    }
+}
+
+fn prepare_environment_bind_group(
+    environment: Res<GlobalEnvironment>,
+    mut bind_group: ResMut<EnvironmentBindGroup>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    let EnvironmentBindGroup {
+        layout,
+        buffer,
+        bind_group,
+    } = &mut *bind_group;
+    buffer.set(environment.clone());
+    buffer.write_buffer(&device, &queue);
+    let Some(binding) = buffer.binding() else {
+        return;
+    };
+    *bind_group = Some(device.create_bind_group(&BindGroupDescriptor {
+        label: Some("environment_bind_group"),
+        layout,
+        entries: &[BindGroupEntry {
+            binding: 0,
+            resource: binding,
+        }],
+    }));
+}
+
+pub struct SetEnvironmentBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetEnvironmentBindGroup<I> {
+    type Param = SRes<EnvironmentBindGroup>;
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        _entity: (),
+        environment: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(bind_group) = &environment.into_inner().bind_group else {
+            return RenderCommandResult::Failure;
+        };
+        pass.set_bind_group(I, bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+/// The command list `MaterialPlugin` registers for each material.
+type DrawMaterial<M> = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetMaterialBindGroup<M, 1>,
+    SetMeshBindGroup<2>,
+    DrawMesh,
+);
+
+pub type DrawEnvironmentMaterial<M> = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetMaterialBindGroup<M, 1>,
+    SetMeshBindGroup<2>,
+    SetEnvironmentBindGroup<ENVIRONMENT_GROUP>,
+    DrawMesh,
+);
+
+/// Points `DrawMaterial<M>`'s id at `DrawEnvironmentMaterial<M>`, so the meshes
+/// `MaterialPlugin` queues are drawn with the environment bound.
+fn replace_draw_function<P: CachedRenderPipelinePhaseItem, M: Material>(world: &mut World) {
+    let draw_function = RenderCommandState::<P, DrawEnvironmentMaterial<M>>::new(world);
+    world
+        .resource::<DrawFunctions<P>>()
+        .write()
+        .add_with::<DrawMaterial<M>, _>(draw_function);
+}
@@ -0,0 +1,493 @@
+//! Crepuscular rays as a post process. Each pixel of the HDR image is blurred towards the
+//! sun's position on screen, gathering light only from where the sky shows through: the
+//! depth prepass hides the scene, and with the `rm-cloud` feature the `CloudShadowMap`
+//! dims the sky behind the cloud layers.
+//!
+//! Cameras opt in with `GodRays`, which needs a `DepthPrepass` on the same camera. The
+//! pass runs between the main pass and bloom, so bright rays bloom like the rest of the sky.
+
+use bevy::{
+    asset::load_internal_asset,
+    core_pipeline::{
+        core_3d, fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+        prepass::ViewPrepassTextures,
+    },
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        extract_component::{ComponentUniforms, DynamicUniformIndex, UniformComponentPlugin},
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, SlotInfo, SlotType},
+        render_resource::{
+            BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+            BindGroupLayoutEntry, BindingResource, BindingType, BufferBindingType,
+            CachedRenderPipelineId, ColorTargetState, ColorWrites, FilterMode, FragmentState,
+            Operations, PipelineCache, RenderPassColorAttachment, RenderPassDescriptor,
+            RenderPipelineDescriptor, Sampler, SamplerBindingType, SamplerDescriptor, ShaderStages,
+            ShaderType, SpecializedRenderPipeline, SpecializedRenderPipelines, TextureFormat,
+            TextureSampleType, TextureViewDimension,
+        },
+        renderer::{RenderContext, RenderDevice},
+        texture::{BevyDefault, FallbackImage},
+        view::{ExtractedView, ViewTarget},
+        Extract, ExtractSchedule, RenderApp, RenderSet,
+    },
+};
+
+#[cfg(feature = "rm-cloud")]
+use crate::cloud::RMCloud;
+#[cfg(feature = "rm-cloud")]
+use crate::cloud_shadow::CloudShadowMap;
+use crate::global_environment::{GlobalEnvironment, GlobalEnvironmentPlugin};
+
+pub const GOD_RAYS_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 0x51e8_a3b7_0c29_d4f6);
+
+/// The pass in the 3d render graph, after the main pass and before bloom.
+pub const GOD_RAYS_NODE: &str = "god_rays";
+
+/// Per camera settings. The rays are drawn for cameras with this and a `DepthPrepass`.
+///
+/// Only the planar `RMCloud` layers occlude the rays, through the `CloudShadowMap`. Volume
+/// cloud boxes aren't in the depth prepass or the shadow map, so the sun shines straight
+/// through them.
+#[derive(Component, Clone, Debug, Reflect)]
+#[reflect(Component)]
+pub struct GodRays {
+    pub enabled: bool,
+    /// Brightness of the rays in units of the sun's colour.
+    pub intensity: f32,
+    /// How much each sample towards the sun counts compared to the one before, closer to 1
+    /// gives longer rays.
+    pub decay: f32,
+    /// Samples per pixel, more gives smoother rays.
+    pub samples: u32,
+    /// How much of the way to the sun the samples cover.
+    pub density: f32,
+    /// How tightly the light gathers around the sun, higher gives a smaller halo.
+    pub falloff: f32,
+    /// Depths past this count as sky, so distant skybox meshes still let the sun through.
+    pub sky_distance: f32,
+}
+
+impl Default for GodRays {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            intensity: 0.4,
+            decay: 0.97,
+            samples: 64,
+            density: 0.8,
+            falloff: 50.0,
+            sky_distance: 50_000.0,
+        }
+    }
+}
+
+pub use uniform::GodRayUniform;
+
+mod uniform {
+    // The `ShaderType` derive emits a `check` fn per field that rustc reports as unused
+    #![allow(dead_code)]
+
+    use bevy::{prelude::*, render::render_resource::ShaderType};
+
+    /// What the shader sees as `god_rays`, laid out to match `god_rays.wgsl`.
+    #[derive(Component, ShaderType, Clone, Debug)]
+    pub struct GodRayUniform {
+        pub sun_color: Vec4,
+        pub inverse_view_projection: Mat4,
+        pub cloud_shadow_projection: Mat4,
+        pub camera_position: Vec3,
+        pub cloud_altitude: f32,
+        pub to_sun: Vec3,
+        pub falloff: f32,
+        pub sun_uv: Vec2,
+        /// Fades the rays out as the sun leaves the screen.
+        pub sun_visibility: f32,
+        pub intensity: f32,
+        pub decay: f32,
+        pub density: f32,
+        pub samples: u32,
+        pub near: f32,
+        pub sky_distance: f32,
+        /// 0 when there's no cloud shadow map to dim the sky with.
+        pub cloud_occlusion: f32,
+    }
+}
+
+/// The cloud shadow map in the render world, if there is one.
+#[derive(Resource, Default)]
+struct GodRayClouds(Option<Handle<Image>>);
+
+pub struct GodRayPlugin;
+
+impl Plugin for GodRayPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            GOD_RAYS_SHADER_HANDLE,
+            "../assets/shaders/god_rays.wgsl",
+            Shader::from_wgsl
+        );
+        if !app.is_plugin_added::<GlobalEnvironmentPlugin>() {
+            app.add_plugin(GlobalEnvironmentPlugin);
+        }
+        app.register_type::<GodRays>();
+        app.add_plugin(UniformComponentPlugin::<GodRayUniform>::default());
+
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .init_resource::<GodRayClouds>()
+            .init_resource::<GodRayPipeline>()
+            .init_resource::<SpecializedRenderPipelines<GodRayPipeline>>()
+            .add_system(extract_god_rays.in_schedule(ExtractSchedule))
+            .add_system(prepare_god_ray_pipelines.in_set(RenderSet::Prepare));
+
+        let node = GodRayNode::new(&mut render_app.world);
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        let Some(draw_3d_graph) = graph.get_sub_graph_mut(core_3d::graph::NAME) else {
+            return;
+        };
+        draw_3d_graph.add_node(GOD_RAYS_NODE, node);
+        draw_3d_graph.add_slot_edge(
+            draw_3d_graph.input_node().id,
+            core_3d::graph::input::VIEW_ENTITY,
+            GOD_RAYS_NODE,
+            GodRayNode::IN_VIEW,
+        );
+        draw_3d_graph.add_node_edge(core_3d::graph::node::MAIN_PASS, GOD_RAYS_NODE);
+        draw_3d_graph.add_node_edge(GOD_RAYS_NODE, core_3d::graph::node::BLOOM);
+    }
+}
+
+/// Works out where the sun is on each camera's screen. Cameras looking away from it get no
+/// uniform, which skips their pass.
+fn extract_god_rays(
+    mut commands: Commands,
+    cameras: Extract<Query<(Entity, &Camera, &GlobalTransform, &GodRays)>>,
+    environment: Extract<Res<GlobalEnvironment>>,
+    #[cfg(feature = "rm-cloud")] shadow_map: Extract<Option<Res<CloudShadowMap>>>,
+    #[cfg(feature = "rm-cloud")] cloud_layers: Extract<Query<&RMCloud>>,
+) {
+    // The map holds the light through every layer along the sun's direction, so looking it
+    // up where a view ray crosses the lowest layer covers all of them
+    #[cfg(feature = "rm-cloud")]
+    let clouds = shadow_map.as_ref().and_then(|map| {
+        let altitude = cloud_layers
+            .iter()
+            .map(|cloud| cloud.altitude)
+            .reduce(f32::min)?;
+        Some((map.image.clone(), map.projection, altitude))
+    });
+    #[cfg(not(feature = "rm-cloud"))]
+    let clouds: Option<(Handle<Image>, Mat4, f32)> = None;
+    commands.insert_resource(GodRayClouds(
+        clouds.as_ref().map(|(image, ..)| image.clone()),
+    ));
+
+    for (entity, camera, transform, god_rays) in cameras.iter() {
+        if !camera.is_active || !god_rays.enabled {
+            continue;
+        }
+        let projection = camera.projection_matrix();
+        let view_projection = projection * transform.compute_matrix().inverse();
+        // The sun is a direction, so it projects like a point at infinity
+        let to_sun = -environment.sun_direction.normalize();
+        let sun = view_projection * to_sun.extend(0.0);
+        if sun.w <= 0.0 {
+            continue;
+        }
+        let ndc = Vec2::new(sun.x, sun.y) / sun.w;
+        // Full strength on screen, gone half a screen past the edge
+        let sun_visibility = (1.0 - (ndc.abs().max_element() - 1.0) * 2.0).clamp(0.0, 1.0);
+        if sun_visibility == 0.0 {
+            continue;
+        }
+        let (cloud_shadow_projection, cloud_altitude, cloud_occlusion) = match &clouds {
+            Some((_, projection, altitude)) => (*projection, *altitude, 1.0),
+            None => (Mat4::IDENTITY, 0.0, 0.0),
+        };
+        commands.get_or_spawn(entity).insert(GodRayUniform {
+            sun_color: environment.sun_color,
+            inverse_view_projection: view_projection.inverse(),
+            cloud_shadow_projection,
+            camera_position: transform.translation(),
+            cloud_altitude,
+            to_sun,
+            falloff: god_rays.falloff,
+            sun_uv: Vec2::new(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5),
+            sun_visibility,
+            intensity: god_rays.intensity,
+            decay: god_rays.decay,
+            density: god_rays.density,
+            samples: god_rays.samples.max(1),
+            // Bevy's perspective projection is infinite reverse z, so depth is near / distance
+            near: projection.w_axis.z,
+            sky_distance: god_rays.sky_distance,
+            cloud_occlusion,
+        });
+    }
+}
+
+#[derive(Resource)]
+struct GodRayPipeline {
+    layout: BindGroupLayout,
+    /// For views with MSAA, whose prepass depth is multisampled.
+    multisampled_layout: BindGroupLayout,
+    sampler: Sampler,
+}
+
+impl FromWorld for GodRayPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        let float_texture = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout = |multisampled| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("god_rays_layout"),
+                entries: &[
+                    // The image so far
+                    float_texture(0),
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Texture {
+                            sample_type: TextureSampleType::Depth,
+                            view_dimension: TextureViewDimension::D2,
+                            multisampled,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: true,
+                            min_binding_size: Some(GodRayUniform::min_size()),
+                        },
+                        count: None,
+                    },
+                    // The cloud shadow map
+                    float_texture(4),
+                ],
+            })
+        };
+        Self {
+            layout: layout(false),
+            multisampled_layout: layout(true),
+            sampler: device.create_sampler(&SamplerDescriptor {
+                mag_filter: FilterMode::Linear,
+                min_filter: FilterMode::Linear,
+                ..default()
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct GodRayPipelineKey {
+    texture_format: TextureFormat,
+    multisampled: bool,
+}
+
+impl SpecializedRenderPipeline for GodRayPipeline {
+    type Key = GodRayPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut shader_defs = vec![];
+        if key.multisampled {
+            shader_defs.push("MULTISAMPLED".into());
+        }
+        RenderPipelineDescriptor {
+            label: Some("god_rays".into()),
+            layout: vec![if key.multisampled {
+                self.multisampled_layout.clone()
+            } else {
+                self.layout.clone()
+            }],
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: GOD_RAYS_SHADER_HANDLE.typed(),
+                shader_defs,
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: key.texture_format,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: default(),
+            depth_stencil: None,
+            multisample: default(),
+            push_constant_ranges: vec![],
+        }
+    }
+}
+
+#[derive(Component)]
+struct GodRayPipelineId {
+    id: CachedRenderPipelineId,
+    multisampled: bool,
+}
+
+fn prepare_god_ray_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<GodRayPipeline>>,
+    god_ray_pipeline: Res<GodRayPipeline>,
+    msaa: Res<Msaa>,
+    views: Query<(Entity, &ExtractedView), With<GodRayUniform>>,
+) {
+    let multisampled = msaa.samples() > 1;
+    for (entity, view) in &views {
+        let texture_format = if view.hdr {
+            ViewTarget::TEXTURE_FORMAT_HDR
+        } else {
+            TextureFormat::bevy_default()
+        };
+        let id = pipelines.specialize(
+            &pipeline_cache,
+            &god_ray_pipeline,
+            GodRayPipelineKey {
+                texture_format,
+                multisampled,
+            },
+        );
+        commands
+            .entity(entity)
+            .insert(GodRayPipelineId { id, multisampled });
+    }
+}
+
+struct GodRayNode {
+    query: QueryState<
+        (
+            &'static ViewTarget,
+            &'static ViewPrepassTextures,
+            &'static GodRayPipelineId,
+            &'static DynamicUniformIndex<GodRayUniform>,
+        ),
+        With<ExtractedView>,
+    >,
+}
+
+impl GodRayNode {
+    const IN_VIEW: &'static str = "view";
+
+    fn new(world: &mut World) -> Self {
+        Self {
+            query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for GodRayNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((target, prepass, pipeline_id, uniform_index)) =
+            self.query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
+        let (Some(depth), Some(pipeline), Some(uniforms)) = (
+            &prepass.depth,
+            world
+                .resource::<PipelineCache>()
+                .get_render_pipeline(pipeline_id.id),
+            world
+                .resource::<ComponentUniforms<GodRayUniform>>()
+                .binding(),
+        ) else {
+            return Ok(());
+        };
+        let god_ray_pipeline = world.resource::<GodRayPipeline>();
+        // Without a shadow map the white fallback leaves the sky unoccluded
+        let clouds = world
+            .resource::<GodRayClouds>()
+            .0
+            .as_ref()
+            .and_then(|image| world.resource::<RenderAssets<Image>>().get(image))
+            .map_or(&world.resource::<FallbackImage>().texture_view, |image| {
+                &image.texture_view
+            });
+
+        let post_process = target.post_process_write();
+        let bind_group = render_context
+            .render_device()
+            .create_bind_group(&BindGroupDescriptor {
+                label: Some("god_rays_bind_group"),
+                layout: if pipeline_id.multisampled {
+                    &god_ray_pipeline.multisampled_layout
+                } else {
+                    &god_ray_pipeline.layout
+                },
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(post_process.source),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::Sampler(&god_ray_pipeline.sampler),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::TextureView(&depth.default_view),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: uniforms,
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: BindingResource::TextureView(clouds),
+                    },
+                ],
+            });
+
+        let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("god_rays_pass"),
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: post_process.destination,
+                resolve_target: None,
+                ops: Operations::default(),
+            })],
+            depth_stencil_attachment: None,
+        });
+        pass.set_render_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[uniform_index.index()]);
+        pass.draw(0..3, 0..1);
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+//! Procedural clouds, water and sky for Bevy, along with the camera rig,
+//! scroll timeline, weather and scene files the portfolio site drives them with.
+//!
+//! Each environment plugin is behind a cargo feature: `rm-cloud`,
+//! `volume-cloud`, `fin-cloud`, `cloud-blob`, `water` and `skybox`, and the
+//! crepuscular ray post process behind `god-rays`. The plugins shade for the
+//! camera picked by `environment::resolve_environment`: the one marked
+//! `environment::MainCamera`, otherwise the camera with a
+//! `camera::CameraController`.
+
+use bevy::{
+    prelude::ImagePlugin,
+    render::render_resource::{AddressMode, FilterMode, SamplerDescriptor},
+};
+
+#[cfg(feature = "volume-cloud")]
+pub mod bricks;
+pub mod camera;
+pub mod camera_path;
+#[cfg(feature = "rm-cloud")]
+pub mod cloud;
+#[cfg(feature = "cloud-blob")]
+pub mod cloud_blob;
+#[cfg(feature = "rm-cloud")]
+pub mod cloud_query;
+#[cfg(feature = "rm-cloud")]
+pub mod cloud_shadow;
+pub mod easing;
+pub mod environment;
+#[cfg(feature = "fin-cloud")]
+pub mod fin_cloud;
+pub mod global_environment;
+#[cfg(feature = "god-rays")]
+pub mod god_rays;
+pub mod noise;
+mod noise_shader;
+pub mod phase;
+#[cfg(feature = "volume-cloud")]
+pub mod rm_cloud;
+pub mod scene;
+pub mod sdf;
+#[cfg(feature = "skybox")]
+pub mod skybox;
+mod test_cloud_shader;
+pub mod timeline;
+#[cfg(feature = "volume-cloud")]
+pub mod volume_file;
+#[cfg(feature = "water")]
+pub mod water;
+pub mod weather;
+
+pub mod prelude {
+    #[cfg(feature = "volume-cloud")]
+    pub use crate::bricks::BrickVolume;
+    pub use crate::camera::{
+        CameraController, CameraMode, CameraRigPlugin, CameraShake, MotionSettings,
+    };
+    pub use crate::camera_path::{CameraPath, CameraPathFollower, CameraPathPlugin, PathDrive};
+    #[cfg(feature = "rm-cloud")]
+    pub use crate::cloud::{
+        CloudLayer, RMCloud, RMCloudBundle, RMCloudLayers, RMCloudMaterial, RMCloudPlugin,
+    };
+    #[cfg(feature = "cloud-blob")]
+    pub use crate::cloud_blob::{
+        CloudBlobAssets, CloudBlobBundle, CloudBlobMaterial, CloudBlobPlugin,
+    };
+    #[cfg(feature = "rm-cloud")]
+    pub use crate::cloud_query::CloudQuery;
+    #[cfg(feature = "rm-cloud")]
+    pub use crate::cloud_shadow::{CloudShadowPlugin, CloudShadowReceiver};
+    pub use crate::environment::{EnvironmentUniforms, EnvironmentView, MainCamera};
+    #[cfg(feature = "fin-cloud")]
+    pub use crate::fin_cloud::{FinCloudMaterial, FinCloudPlugin};
+    pub use crate::global_environment::{GlobalEnvironment, Wind};
+    #[cfg(feature = "god-rays")]
+    pub use crate::god_rays::{GodRayPlugin, GodRays};
+    #[cfg(feature = "volume-cloud")]
+    pub use crate::rm_cloud::{
+        LightBake, LightQuality, MultipleScattering, VolumeBake, VolumeCloud, VolumeCloudBox,
+        VolumeCloudBundle, VolumeCloudMaterial, VolumeCloudPlugin, VolumeRelight,
+    };
+    pub use crate::scene::{ActiveScene, SceneDescription, SceneFilePlugin};
+    #[cfg(feature = "skybox")]
+    pub use crate::skybox::{CubemapMaterial, SkyBoxPlugin};
+    pub use crate::timeline::{ScrollTimeline, TimelinePlugin};
+    #[cfg(feature = "volume-cloud")]
+    pub use crate::volume_file::DensityGrid;
+    #[cfg(feature = "water")]
+    pub use crate::water::{Water, WaterBundle, WaterMaterial, WaterPlugin};
+    pub use crate::weather::{Weather, WeatherPlugin};
+}
+
+/// The cloud and water textures tile, so images need a repeating sampler by default.
+pub fn image_plugin() -> ImagePlugin {
+    ImagePlugin {
+        default_sampler: SamplerDescriptor {
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            address_mode_w: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Linear,
+
+            ..Default::default()
+        },
+    }
+}
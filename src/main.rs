@@ -1,74 +1,48 @@
 // orbital scene
 
-use std::f32::consts::PI;
-
-use bevy::{
-    core_pipeline::bloom::BloomSettings,
-    input::mouse::MouseWheel,
-    math::vec3,
-    prelude::*,
-    render::render_resource::{AddressMode, FilterMode, SamplerDescriptor},
-};
+use bevy::{core_pipeline::bloom::BloomSettings, prelude::*};
 
+#[cfg(feature = "inspector")]
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
-use camera::{camera_controller, CameraController};
-use cloud::RMCloud;
-// use cloud_blob::CloudBlobPlugin;
-// use skybox::{CubemapMaterial, SkyBoxPlugin};
-// use water::WaterPlugin;
-mod camera;
-// mod cloud_blob;
-// mod fin_cloud;
-mod cloud;
-mod noise;
-mod noise_shader;
-mod sdf;
-mod skybox;
-mod test_cloud_shader;
-mod water;
+use resume::camera::{self, CameraController};
+use resume::camera_path::{self, CameraPathFollower, PathDrive};
+use resume::scene::{
+    self, ActiveScene, SceneDescription, SceneFilePlugin, SceneSun, DEFAULT_SCENE,
+};
+use resume::{image_plugin, timeline, weather};
 
 fn main() {
-    App::new()
-        .add_plugins(
-            DefaultPlugins
-                .set(AssetPlugin {
-                    watch_for_changes: true,
-                    ..Default::default()
-                })
-                .set(ImagePlugin {
-                    default_sampler: SamplerDescriptor {
-                        address_mode_u: AddressMode::Repeat,
-                        address_mode_v: AddressMode::Repeat,
-                        address_mode_w: AddressMode::Repeat,
-                        mag_filter: FilterMode::Linear,
-                        min_filter: FilterMode::Linear,
-                        mipmap_filter: FilterMode::Linear,
+    let mut app = App::new();
+    app.add_plugins(
+        DefaultPlugins
+            .set(AssetPlugin {
+                watch_for_changes: true,
+                ..Default::default()
+            })
+            .set(image_plugin()),
+    )
+    .add_plugin(weather::WeatherPlugin)
+    .add_plugin(SceneFilePlugin)
+    .add_startup_system(setup)
+    .add_plugin(timeline::TimelinePlugin)
+    .add_plugin(camera_path::CameraPathPlugin)
+    .add_plugin(camera::CameraRigPlugin);
+    // .add_plugin(LogDiagnosticsPlugin::default())
+    // .add_plugin(FrameTimeDiagnosticsPlugin::default())
 
-                        ..Default::default()
-                    },
-                }),
-        )
-        .add_plugin(WorldInspectorPlugin::new())
-        .add_plugin(cloud::RMCloudPlugin)
-        // .add_plugin(fin_cloud::FinCloudPlugin)
-        // .add_plugin(CloudBlobPlugin)
-        // .add_plugin(WaterPlugin)
-        .add_startup_system(setup)
-        .add_system(camera_controller)
-        .add_system(scroll)
-        // .add_plugin(FlyCameraPlugin)
-        // .add_plugin(MaterialPlugin::<CubemapMaterial>::default())
-        // .add_plugin(SkyBoxPlugin {})
-        // .add_plugin(LogDiagnosticsPlugin::default())
-        // .add_plugin(FrameTimeDiagnosticsPlugin::default())
-        .run();
-}
+    #[cfg(feature = "inspector")]
+    app.add_plugin(WorldInspectorPlugin::new());
+    #[cfg(feature = "rm-cloud")]
+    app.add_plugin(resume::cloud_shadow::CloudShadowPlugin);
 
-fn scroll(mut clouds: Query<&mut RMCloud>, mut scroll: EventReader<MouseWheel>) {
-    let scrolled: f32 = scroll.iter().map(|c| c.y * 0.001).sum();
-    for mut cloud in clouds.iter_mut() {
-        cloud.scroll += scrolled;
-    }
+    // `cargo run -- scenes/other.scene.ron` picks a different scene from the assets folder
+    let scene_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_SCENE.to_string());
+    let scene = SceneDescription::read(&scene_path);
+    scene::add_environment_plugins(&mut app, &scene);
+    app.insert_resource(ActiveScene::new(scene_path, scene));
+    app.run();
 }
 
 // #[derive(Component, Default)]
@@ -77,60 +51,72 @@ fn setup(
     mut commands: Commands,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut meshes: ResMut<Assets<Mesh>>,
-    // asset_server: Res<AssetServer>,
+    mut weather: ResMut<weather::Weather>,
+    mut timeline: ResMut<timeline::ScrollTimeline>,
+    asset_server: Res<AssetServer>,
+    scene: Res<ActiveScene>,
 ) {
+    let scene = &scene.description;
+
     // ambient light
     // NOTE: The ambient light is used to scale how bright the environment map is so with a bright
     // environment map, use an appropriate colour and brightness to match
+    let [r, g, b] = scene.ambient.color;
     commands.insert_resource(AmbientLight {
-        color: Color::rgb(0.54, 0.8, 1.),
-        brightness: 1.0,
+        color: Color::rgb(r, g, b),
+        brightness: scene.ambient.brightness,
     });
+    scene::apply_lighting(scene, &mut weather);
 
     // light
-    commands.spawn(DirectionalLightBundle {
-        directional_light: DirectionalLight {
-            color: Color::rgb(2.2, 2.05, 1.9),
-            illuminance: 20_000.0,
-            shadows_enabled: false,
+    let [r, g, b] = scene.sun.color;
+    commands.spawn((
+        DirectionalLightBundle {
+            directional_light: DirectionalLight {
+                color: Color::rgb(r, g, b),
+                illuminance: scene.sun.illuminance,
+                shadows_enabled: scene.sun.shadows,
+                ..default()
+            },
+            transform: scene.sun.transform(),
             ..default()
         },
-        transform: Transform::from_xyz(0.0, 0.0, 0.0).looking_at(vec3(-1., -0.3, 1.), Vec3::Y),
-        ..default()
-    });
+        SceneSun,
+    ));
 
     // test cube
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(shape::Cube { size: 2. }.into()),
-        material: materials.add(Color::rgba(1., 0.6, 0.5, 1.).into()),
-        transform: Transform::from_xyz(4., 0., 1.),
-        ..default()
-    });
+    if let Some(cube) = &scene.cube {
+        scene::spawn_cube(&mut commands, &mut meshes, &mut materials, cube);
+    }
 
     // camera
+    let transform = scene.camera.transform();
+    timeline.camera_origin = scene.camera.timeline_origin();
     commands.spawn((
         Camera3dBundle {
-            transform: Transform::from_xyz(0.0, 600., 0.0).with_rotation(Quat::from_euler(
-                EulerRot::XYZ,
-                -1.5,
-                0.0,
-                PI,
-            )),
+            transform,
             projection: Projection::Perspective(PerspectiveProjection {
-                far: 100_000.,
+                far: scene.camera.far,
                 ..default()
             }),
             camera: Camera {
                 hdr: true,
                 ..default()
             },
-            tonemapping: bevy::core_pipeline::tonemapping::Tonemapping::AcesFitted,
+            tonemapping: scene.camera.tonemapping.into(),
             ..default()
         },
         BloomSettings {
-            intensity: 0.5,
+            intensity: scene.camera.bloom_intensity,
             ..default()
         },
-        CameraController::default(),
+        CameraController::at(transform),
+        CameraPathFollower::new(
+            asset_server.load("paths/flythrough.path.ron"),
+            PathDrive::Time {
+                speed: 1.0,
+                looping: true,
+            },
+        ),
     ));
 }
@@ -177,30 +177,30 @@ pub fn dnoised(x: DVec3, f: DVec3) -> f64 {
         + k7 * u.x * u.y * u.z;
 }
 
-// pub fn fbmd(mut p: Vec3) -> Vec4 {
-//     let mut t = Vec4::ZERO;
-//     let mut s = 1.;
-//     let mut c = 1.;
-
-//     for i in 0..4 {
-//         p += vec3(13.123, -72., 234.23);
-//         let n = noised(p * s) * c;
-//         t.x += n.x;
-//         if i < 1 {
-//             t.y += n.y;
-//             t.z += n.z;
-//             t.w += n.w;
-//         }
-//         s *= 2.;
-//         c *= 0.5;
-
-//         let rot = rotate(2.135532) * p.xz();
-//         p = vec3(rot.x, p.y, rot.y);
-//         let rot = rotate(1.5532) * p.yz();
-//         p = vec3(p.x, rot.x, rot.y);
-//     }
-//     return t;
-// }
+pub fn fbmd(mut p: Vec3) -> Vec4 {
+    let mut t = Vec4::ZERO;
+    let mut s = 1.;
+    let mut c = 1.;
+
+    for i in 0..4 {
+        p += vec3(13.123, -72., 234.23);
+        let n = noised(p * s, Vec3::splat(100_000.)) * c;
+        t.x += n.x;
+        if i < 1 {
+            t.y += n.y;
+            t.z += n.z;
+            t.w += n.w;
+        }
+        s *= 2.;
+        c *= 0.5;
+
+        let rot = rotate(2.135532) * p.xz();
+        p = vec3(rot.x, p.y, rot.y);
+        let rot = rotate(1.5532) * p.yz();
+        p = vec3(p.x, rot.x, rot.y);
+    }
+    return t;
+}
 
 // TODO: tiling
 pub fn worley_noise(p: Vec3, f: Vec3) -> f32 {
@@ -0,0 +1,153 @@
+//! Phase functions, the share of light scattered at an angle `acos(costh)` from its
+//! direction of travel. Each one integrates to 1 over the sphere.
+//!
+//! `assets/shaders/phase.wgsl` has the same functions under the same names for shaders,
+//! imported with `#import resume::phase` by materials that add `PhaseShaderPlugin`.
+
+use std::f32::consts::PI;
+
+use bevy::{asset::load_internal_asset, math::vec4, prelude::*, reflect::TypeUuid};
+
+pub const PHASE_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 0x7c21_d0e4_58a3_9f16);
+
+/// Makes `#import resume::phase` available to shaders.
+pub struct PhaseShaderPlugin;
+
+impl Plugin for PhaseShaderPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            PHASE_SHADER_HANDLE,
+            "../assets/shaders/phase.wgsl",
+            Shader::from_wgsl
+        );
+    }
+}
+
+/// Adds `PhaseShaderPlugin` unless another material already has.
+pub fn add_phase_shader(app: &mut App) {
+    if !app.is_plugin_added::<PhaseShaderPlugin>() {
+        app.add_plugin(PhaseShaderPlugin);
+    }
+}
+
+/// Scattering by particles much smaller than the light's wavelength, like air.
+pub fn rayleigh(costh: f32) -> f32 {
+    3.0 / (16.0 * PI) * (1.0 + costh * costh)
+}
+
+/// Forward scattering for `g` above 0, backward below it and even at 0.
+pub fn henyey_greenstein(g: f32, costh: f32) -> f32 {
+    (1.0 - g * g) / (4.0 * PI * (1.0 + g * g - 2.0 * g * costh).powf(1.5))
+}
+
+/// A forward lobe `g_forward` and a backward lobe `g_back`, blended by `forward` from 0 to 1.
+pub fn dual_lobe_hg(g_forward: f32, g_back: f32, forward: f32, costh: f32) -> f32 {
+    let back = henyey_greenstein(g_back, costh);
+    back + (henyey_greenstein(g_forward, costh) - back) * forward
+}
+
+/// Henyey-Greenstein with a rayleigh-like `1 + costh²` term, closer to real mie scattering.
+pub fn cornette_shanks(g: f32, costh: f32) -> f32 {
+    let g2 = g * g;
+    3.0 / (8.0 * PI) * (1.0 - g2) * (1.0 + costh * costh)
+        / ((2.0 + g2) * (1.0 + g2 - 2.0 * g * costh).powf(1.5))
+}
+
+/// A fit to the mie scattering of cloud droplets.
+pub fn mie(costh: f32) -> f32 {
+    // This function was optimized to minimize (delta*delta)/reference in order to capture
+    // the low intensity behavior.
+    let params = [
+        9.805233e-06,
+        -65.0,
+        -55.0,
+        8.194068e-01,
+        1.388198e-01,
+        -8.370334e+01,
+        7.810083e+00,
+        2.054747e-03,
+        2.600563e-02,
+        -4.552125e-12,
+    ];
+
+    let p1 = costh + params[3];
+    let exp_values = vec4(
+        params[1] * costh + params[2],
+        params[5] * p1 * p1,
+        params[6] * costh,
+        params[9] * costh,
+    )
+    .exp();
+    let exp_val_weight = vec4(params[0], params[4], params[7], params[8]);
+    // The fit integrates to 4.5903 over the sphere
+    exp_values.dot(exp_val_weight) / 4.590_298_5
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::TAU;
+
+    use super::*;
+
+    /// Midpoints in `costh`, enough for the sharpest lobe below to be within `TOLERANCE`.
+    const STEPS: u32 = 200_000;
+    const TOLERANCE: f64 = 1e-3;
+    const ECCENTRICITIES: [f32; 5] = [-0.5, 0.0, 0.3, 0.6, 0.9];
+
+    /// The integral over the sphere, which by symmetry around the light's direction is
+    /// `2π` times the integral over `costh` from -1 to 1.
+    fn integrate(phase: impl Fn(f32) -> f32) -> f64 {
+        let step = 2.0 / STEPS as f64;
+        let sum: f64 = (0..STEPS)
+            .map(|i| phase((-1.0 + (i as f64 + 0.5) * step) as f32) as f64)
+            .sum();
+        TAU * sum * step
+    }
+
+    fn assert_normalised(name: &str, phase: impl Fn(f32) -> f32) {
+        let integral = integrate(phase);
+        assert!(
+            (integral - 1.0).abs() < TOLERANCE,
+            "{name} integrates to {integral}"
+        );
+    }
+
+    #[test]
+    fn rayleigh_normalised() {
+        assert_normalised("rayleigh", rayleigh);
+    }
+
+    #[test]
+    fn mie_normalised() {
+        assert_normalised("mie", mie);
+    }
+
+    #[test]
+    fn henyey_greenstein_normalised() {
+        for g in ECCENTRICITIES {
+            assert_normalised(&format!("henyey_greenstein {g}"), |costh| {
+                henyey_greenstein(g, costh)
+            });
+        }
+    }
+
+    #[test]
+    fn dual_lobe_hg_normalised() {
+        for g in ECCENTRICITIES {
+            assert_normalised(&format!("dual_lobe_hg {g} -0.3"), |costh| {
+                dual_lobe_hg(g, -0.3, 0.7, costh)
+            });
+        }
+    }
+
+    #[test]
+    fn cornette_shanks_normalised() {
+        for g in ECCENTRICITIES {
+            assert_normalised(&format!("cornette_shanks {g}"), |costh| {
+                cornette_shanks(g, costh)
+            });
+        }
+    }
+}
@@ -1,249 +1,838 @@
-use antidote::Mutex;
-use std::cell::Cell;
-use std::f32::consts::E;
-
-use crate::noise::fbmd;
-use crate::sdf::sdf as cloud_sdf;
-use crate::{noise, CameraController};
+use crate::bricks::BrickVolume;
+use crate::environment::{
+    add_environment_uniforms, resolve_environment, EnvironmentUniforms, EnvironmentView,
+};
+use crate::noise::{self, fbmd};
+use crate::phase::{add_phase_shader, mie};
+use crate::volume_file::{DensityGrid, DensityGridLoader};
 use bevy::{
     math::{vec3, vec4},
+    pbr::{MaterialPipeline, MaterialPipelineKey},
     prelude::*,
     reflect::TypeUuid,
-    render::render_resource::{AsBindGroup, Extent3d, ShaderRef, TextureDimension, TextureFormat},
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_resource::{
+            AsBindGroup, Extent3d, FilterMode, RenderPipelineDescriptor, SamplerDescriptor,
+            ShaderRef, SpecializedMeshPipelineError, TextureDimension, TextureFormat,
+        },
+        texture::ImageSampler,
+    },
+    tasks::{AsyncComputeTaskPool, Task},
 };
-use itertools::Itertools;
+use futures_lite::future;
+use rand::prelude::*;
 use rayon::prelude::*;
+use std::sync::Arc;
+
+/*
+Clouds raymarched through a baked 3d texture inside a box. Each box bakes its own
+volume: the density goes in `z` and the transmittance towards the sun in `y`, so
+the shader only needs one sample per step. The transmittance is averaged over a
+cone of directions around the sun, weighted by the mie phase, which softens the
+shadow edges into a penumbra.
+
+Light scattered more than once is approximated as in Wrenninge's "Oz" clouds: extra
+octaves see a thinner cloud and contribute less, and the shader gives them a wider
+phase lobe. Their sum goes in `x`, and the Beer-powder term, which darkens the
+sunward edges, goes in `w`.
+
+Once the sun has moved far enough the light channel is re-baked on the async
+compute pool from the density kept in `VolumeLighting`. Big moves get a coarse
+bake first and the full one after it, and the material fades from the old
+texture to the new one so the lighting never pops.
+
+Large boxes can be baked `sparse`, as bricks of 8³ voxels where only the ones with
+density are kept, see `bricks`. The shader looks each point's brick up in a table
+and jumps over the empty ones.
+
+Instead of the noise, a box can bake a `DensityGrid` loaded from a volume file, see
+`volume_file`. The grid is resampled to the box's resolution first.
+*/
+
+/// Spawns `boxes` at startup, more can be added with `VolumeCloudBundle`.
+pub struct VolumeCloudPlugin {
+    pub boxes: Vec<VolumeCloudBox>,
+}
+
+impl Default for VolumeCloudPlugin {
+    fn default() -> Self {
+        Self {
+            boxes: vec![VolumeCloudBox::default()],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VolumeCloudBox {
+    pub transform: Transform,
+    pub cloud: VolumeCloud,
+    pub bake: VolumeBake,
+    pub relight: VolumeRelight,
+    /// A volume file under `assets` to bake instead of the noise.
+    pub file: Option<String>,
+}
+
+impl Default for VolumeCloudBox {
+    fn default() -> Self {
+        Self {
+            transform: Transform::from_xyz(100.0, 100.0, 200.0)
+                .with_scale(vec3(1000.0, 250.0, 1000.0)),
+            cloud: VolumeCloud::default(),
+            bake: VolumeBake::default(),
+            relight: VolumeRelight::default(),
+            file: None,
+        }
+    }
+}
+
+/// How a box is drawn, can be changed freely at runtime.
+#[derive(Component, Clone, Debug, Reflect)]
+#[reflect(Component)]
+pub struct VolumeCloud {
+    /// Extinction per world unit at full density.
+    pub density: f32,
+    pub steps: u32,
+    /// Henyey-Greenstein eccentricity of the multiple scattering, lower than the direct
+    /// light's mie lobe since each bounce spreads the light out.
+    pub eccentricity: f32,
+    /// How much the Beer-powder term darkens the edges facing the sun, 0 turns it off.
+    pub powder: f32,
+}
+
+impl Default for VolumeCloud {
+    fn default() -> Self {
+        Self {
+            density: 0.05,
+            steps: 96,
+            eccentricity: 0.4,
+            powder: 1.0,
+        }
+    }
+}
+
+/// How a box's volume is baked, changing it re-bakes the box.
+#[derive(Component, Clone, Debug, Reflect)]
+#[reflect(Component)]
+pub struct VolumeBake {
+    /// Voxels along each axis of the box.
+    pub resolution: UVec3,
+    /// Noise frequency across the box, scale it with the box to keep the clouds the same size.
+    pub noise_scale: Vec3,
+    /// Noise below this is clear air, higher values give fewer, thinner clouds.
+    pub threshold: f32,
+    pub light: LightBake,
+    /// Stores the volume as bricks, leaving out the empty ones so larger resolutions fit.
+    /// Relights skip the coarse bake, the bricks are already quick to light.
+    pub sparse: bool,
+    /// Densities to bake instead of the noise, resampled to `resolution`. The box waits for
+    /// the grid to load, and re-bakes when the file changes.
+    pub grid: Option<Handle<DensityGrid>>,
+}
 
-#[derive(Component, Default)]
-struct RMCloud {
-    handle: Handle<RMCloudMaterial>,
+impl Default for VolumeBake {
+    fn default() -> Self {
+        Self {
+            resolution: UVec3::new(128, 32, 128),
+            noise_scale: vec3(5.0, 1.25, 5.0),
+            threshold: 0.9,
+            light: LightBake::default(),
+            sparse: false,
+            grid: None,
+        }
+    }
+}
+
+/// How the sunlight reaching each voxel is baked.
+#[derive(Clone, Debug, Reflect, FromReflect)]
+pub struct LightBake {
+    /// Half angle of the sun cone in radians, wider cones give softer shadows.
+    pub cone_angle: f32,
+    /// Directions marched per voxel, see `LightQuality` for presets.
+    pub samples: u32,
+    /// Spreads the directions evenly over the cone instead of at random.
+    pub stratified: bool,
+    /// How quickly the optical depth darkens the sunlight.
+    pub absorption: f32,
+    pub scattering: MultipleScattering,
+}
+
+/// Octaves of approximate multiple scattering, each one seeing `extinction` times the
+/// optical depth of the last and contributing `contribution` times as much light.
+#[derive(Clone, Debug, Reflect, FromReflect)]
+pub struct MultipleScattering {
+    /// Octaves including the single scattering in `y`, 1 bakes no multiple scattering.
+    pub octaves: u32,
+    pub extinction: f32,
+    pub contribution: f32,
+}
+
+impl Default for MultipleScattering {
+    fn default() -> Self {
+        Self {
+            octaves: 4,
+            extinction: 0.5,
+            contribution: 0.5,
+        }
+    }
+}
+
+impl MultipleScattering {
+    /// The light reaching a point through `optical_depth` from every octave past the first.
+    pub fn scattered(&self, optical_depth: f32) -> f32 {
+        let mut extinction = 1.0;
+        let mut contribution = 1.0;
+        let mut light = 0.0;
+        for _ in 1..self.octaves {
+            extinction *= self.extinction;
+            contribution *= self.contribution;
+            light += contribution * (-extinction * optical_depth).exp();
+        }
+        light
+    }
+}
+
+/// Beer-powder's darkening of `exp(-optical_depth)`, near 0 at the edge of a cloud and 1
+/// deep inside it.
+pub fn powder(optical_depth: f32) -> f32 {
+    1.0 - (-2.0 * optical_depth).exp()
+}
+
+impl Default for LightBake {
+    fn default() -> Self {
+        LightQuality::default().into()
+    }
+}
+
+/// Sample counts for `LightBake`, each direction is a full march through the box so the
+/// bake time grows with the count.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect, FromReflect)]
+pub enum LightQuality {
+    /// A single ray straight at the sun, hard shadows.
+    Hard,
+    #[default]
+    Low,
+    Medium,
+    High,
+    Ultra,
+}
+
+impl LightQuality {
+    pub fn samples(self) -> u32 {
+        match self {
+            LightQuality::Hard => 1,
+            LightQuality::Low => 4,
+            LightQuality::Medium => 9,
+            LightQuality::High => 16,
+            LightQuality::Ultra => 32,
+        }
+    }
+}
+
+impl From<LightQuality> for LightBake {
+    fn from(quality: LightQuality) -> Self {
+        Self {
+            cone_angle: 0.15,
+            samples: quality.samples(),
+            stratified: true,
+            absorption: 8.0,
+            scattering: MultipleScattering::default(),
+        }
+    }
 }
 
-pub struct RMCloudPlugin;
-impl Plugin for RMCloudPlugin {
+impl LightBake {
+    /// Directions inside the cone around `to_sun` with their normalized phase weights. The
+    /// first direction is `to_sun` itself when stratified, or whenever there's one sample.
+    pub fn directions(&self, to_sun: Vec3) -> Vec<(Vec3, f32)> {
+        let samples = self.samples.max(1) as usize;
+        let axis = to_sun.normalize();
+        let (u, v) = axis.any_orthonormal_pair();
+        let cos_cone = self.cone_angle.clamp(0.0, std::f32::consts::PI).cos();
+        let around = |cos_theta: f32, phi: f32| {
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            axis * cos_theta + (u * phi.cos() + v * phi.sin()) * sin_theta
+        };
+
+        let directions = if samples == 1 {
+            vec![axis]
+        } else if self.stratified {
+            // The axis, then a fibonacci spiral over the rest of the cap
+            let golden_angle = std::f32::consts::PI * (3.0 - 5f32.sqrt());
+            std::iter::once(axis)
+                .chain((1..samples).map(|i| {
+                    let t = (i as f32 - 0.5) / (samples - 1) as f32;
+                    around(1.0 - (1.0 - cos_cone) * t, i as f32 * golden_angle)
+                }))
+                .collect()
+        } else {
+            // Seeded so the same settings always bake the same volume
+            let mut rng = StdRng::seed_from_u64(0);
+            (0..samples)
+                .map(|_| {
+                    around(
+                        rng.gen_range(cos_cone..=1.0),
+                        rng.gen_range(0.0..std::f32::consts::TAU),
+                    )
+                })
+                .collect()
+        };
+
+        let weights = directions
+            .iter()
+            .map(|direction| mie(direction.dot(axis)))
+            .collect::<Vec<_>>();
+        let total: f32 = weights.iter().sum();
+        directions
+            .into_iter()
+            .zip(weights)
+            .map(|(direction, weight)| (direction, weight / total))
+            .collect()
+    }
+}
+
+/// When a box's lighting is re-baked for a moving sun.
+#[derive(Component, Clone, Debug, Reflect)]
+#[reflect(Component)]
+pub struct VolumeRelight {
+    /// Radians the sun has to move before the light is re-baked.
+    pub angle: f32,
+    /// Past this many radians a quick coarse bake goes in first, then the full one.
+    pub coarse_angle: f32,
+    /// Voxels along each side of a coarse bake's cells.
+    pub coarse_stride: u32,
+    /// Seconds to fade from the old lighting to the new.
+    pub blend_time: f32,
+}
+
+impl Default for VolumeRelight {
+    fn default() -> Self {
+        Self {
+            angle: 0.02,
+            coarse_angle: 0.2,
+            coarse_stride: 4,
+            blend_time: 1.0,
+        }
+    }
+}
+
+/// The baked density of a box and the light bake in flight, added by the first bake.
+#[derive(Component)]
+pub struct VolumeLighting {
+    /// The last bake, only its density is read when re-baking the light.
+    volume: Arc<BakedVolume>,
+    dimensions: [usize; 3],
+    /// Towards the sun the current lighting was baked for.
+    baked_for: Vec3,
+    /// Cell size of the current lighting, 1 once it's fully refined.
+    stride: usize,
+    task: Option<Task<LightingBake>>,
+    /// How far the material has faded to the current lighting.
+    blend: f32,
+}
+
+enum BakedVolume {
+    Dense(Vec<Vec4>),
+    Bricks(BrickVolume),
+}
+
+struct LightingBake {
+    to_sun: Vec3,
+    stride: usize,
+    /// Size of `data`, the atlas of a sparse volume.
+    resolution: UVec3,
+    data: Vec<Vec4>,
+}
+
+#[derive(Bundle)]
+pub struct VolumeCloudBundle {
+    pub cloud: VolumeCloud,
+    pub bake: VolumeBake,
+    pub relight: VolumeRelight,
+    pub material_mesh: MaterialMeshBundle<VolumeCloudMaterial>,
+}
+
+impl VolumeCloudBundle {
+    /// A unit cube shaped by `transform`. The volume is baked on the next update.
+    pub fn new(
+        cloud: VolumeCloud,
+        bake: VolumeBake,
+        transform: Transform,
+        meshes: &mut Assets<Mesh>,
+        materials: &mut Assets<VolumeCloudMaterial>,
+        images: &mut Assets<Image>,
+    ) -> Self {
+        let placeholder = images.add(volume_image(UVec3::ONE, vec![Vec4::ZERO]));
+        let material = materials.add(VolumeCloudMaterial {
+            light_blend: 1.0,
+            eccentricity: cloud.eccentricity,
+            powder: cloud.powder,
+            sdf: Some(placeholder.clone()),
+            previous_sdf: Some(placeholder),
+            brick_table: Some(images.add(brick_table_image(UVec3::ONE, &[0]))),
+            ..default()
+        });
+        Self {
+            cloud,
+            bake,
+            relight: VolumeRelight::default(),
+            material_mesh: MaterialMeshBundle {
+                mesh: meshes.add(shape::Box::new(1., 1., 1.).into()),
+                material,
+                transform,
+                ..default()
+            },
+        }
+    }
+}
+
+impl Plugin for VolumeCloudPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(MaterialPlugin::<RMCloudMaterial>::default());
-        app.add_system(
-            |cam: Query<&Transform, With<CameraController>>,
-             clouds: Query<(&RMCloud, &Transform)>,
-             mut cloud_materials: ResMut<Assets<RMCloudMaterial>>,
-             time: Res<Time>| {
-                let camera_position = cam.get_single().unwrap().translation;
-                for (cloud, transform) in &clouds {
-                    if let Some(material) = cloud_materials.get_mut(&cloud.handle) {
-                        material.camera_position = camera_position;
-                        material.time = time.raw_elapsed_seconds();
-                        material.aabb_position = transform.translation;
-                        material.scale = transform.scale;
+        app.register_type::<VolumeCloud>();
+        app.register_type::<VolumeBake>();
+        app.register_type::<LightBake>();
+        app.register_type::<MultipleScattering>();
+        app.register_type::<VolumeRelight>();
+        app.add_asset::<DensityGrid>();
+        app.init_asset_loader::<DensityGridLoader>();
+        app.add_plugin(MaterialPlugin::<VolumeCloudMaterial>::default());
+        add_environment_uniforms::<VolumeCloudMaterial>(app);
+        add_phase_shader(app);
+        app.add_system(bake_volume_clouds.after(resolve_environment));
+        app.add_system(relight_volume_clouds.after(bake_volume_clouds));
+        app.add_system(sync_volume_clouds);
+
+        let boxes = self.boxes.clone();
+        app.add_startup_system(
+            move |mut commands: Commands,
+                  asset_server: Res<AssetServer>,
+                  mut meshes: ResMut<Assets<Mesh>>,
+                  mut cloud_materials: ResMut<Assets<VolumeCloudMaterial>>,
+                  mut images: ResMut<Assets<Image>>| {
+                for cloud_box in &boxes {
+                    let mut bake = cloud_box.bake.clone();
+                    if let Some(file) = &cloud_box.file {
+                        bake.grid = Some(asset_server.load(file.as_str()));
                     }
+                    commands.spawn(VolumeCloudBundle {
+                        relight: cloud_box.relight.clone(),
+                        ..VolumeCloudBundle::new(
+                            cloud_box.cloud.clone(),
+                            bake,
+                            cloud_box.transform,
+                            &mut meshes,
+                            &mut cloud_materials,
+                            &mut images,
+                        )
+                    });
                 }
             },
         );
+    }
+}
 
-        app.add_startup_system(
-            |mut commands: Commands,
-             // asset_server: Res<AssetServer>,
-             mut meshes: ResMut<Assets<Mesh>>,
-             // mut materials: ResMut<Assets<StandardMaterial>>,
-             mut cloud_materials: ResMut<Assets<RMCloudMaterial>>,
-             // mut noise_materials: ResMut<Assets<NoiseMaterial>>,
-             mut images: ResMut<Assets<Image>>| {
-                {
-                    let res = [1000, 1, 1000];
-                    let resf = vec3(res[0] as f32, res[1] as f32, res[2] as f32);
-
-                    let sdf_data = new_cloud_data(res)
-                        .iter()
-                        .map(|v| {
-                            [
-                                v.x.to_ne_bytes(),
-                                v.y.to_ne_bytes(),
-                                v.z.to_ne_bytes(),
-                                v.w.to_ne_bytes(),
-                            ]
-                        })
-                        .flatten()
-                        .flatten()
-                        .collect::<Vec<u8>>();
-                    let texture = images.add(Image::new(
-                        Extent3d {
-                            width: res[0] as u32,
-                            height: res[1] as u32,
-                            depth_or_array_layers: res[2] as u32,
-                        },
-                        TextureDimension::D3,
-                        sdf_data,
-                        TextureFormat::Rgba32Float,
-                    ));
-                    let material = cloud_materials.add(RMCloudMaterial {
-                        sdf: Some(texture.clone()),
-                        texture_dimensions: vec3(res[0] as f32, res[1] as f32, res[2] as f32),
-                        sun_direction: vec3(1., 1., 0.).normalize(),
-                        ..default()
-                    });
+#[allow(clippy::type_complexity)]
+fn sync_volume_clouds(
+    clouds: Query<
+        (&VolumeCloud, &GlobalTransform, &Handle<VolumeCloudMaterial>),
+        Or<(Changed<VolumeCloud>, Changed<GlobalTransform>)>,
+    >,
+    mut cloud_materials: ResMut<Assets<VolumeCloudMaterial>>,
+) {
+    for (cloud, transform, handle) in &clouds {
+        if let Some(material) = cloud_materials.get_mut(handle) {
+            material.inverse_model = transform.compute_matrix().inverse();
+            material.density = cloud.density;
+            material.steps = cloud.steps.max(1) as f32;
+            material.eccentricity = cloud.eccentricity;
+            material.powder = cloud.powder;
+        }
+    }
+}
 
-                    commands.spawn((
-                        RMCloud {
-                            handle: material.clone(),
-                        },
-                        MaterialMeshBundle {
-                            // mesh: meshes.add(cloud_gen::new(100.)),
-                            mesh: meshes.add(shape::Box::new(1., 1., 1.).into()),
-                            material,
-                            transform: {
-                                let mut t = Transform::from_xyz(100.0, 100.0, 200.0);
-                                t.scale = resf;
-                                t
-                            },
-
-                            ..default()
-                        },
-                    ));
-                };
+/// Bakes new and changed boxes for the current sun, or straight overhead if there is none.
+/// Boxes baking a grid wait for it to load.
+fn bake_volume_clouds(
+    mut commands: Commands,
+    clouds: Query<(Entity, Ref<VolumeBake>, &Handle<VolumeCloudMaterial>)>,
+    mut grid_events: EventReader<AssetEvent<DensityGrid>>,
+    grids: Res<Assets<DensityGrid>>,
+    view: Res<EnvironmentView>,
+    mut cloud_materials: ResMut<Assets<VolumeCloudMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let to_sun = if view.sun.is_some() {
+        -view.sun_direction
+    } else {
+        Vec3::Y
+    };
+    let loaded = grid_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => Some(handle),
+            AssetEvent::Removed { .. } => None,
+        })
+        .collect::<Vec<_>>();
+    for (entity, bake, handle) in &clouds {
+        let grid_loaded = bake
+            .grid
+            .as_ref()
+            .is_some_and(|grid| loaded.contains(&grid));
+        if !bake.is_changed() && !grid_loaded {
+            continue;
+        }
+        let grid = match &bake.grid {
+            Some(grid) => match grids.get(grid) {
+                Some(grid) => Some(grid),
+                None => continue,
             },
-        );
+            None => None,
+        };
+        let Some(material) = cloud_materials.get_mut(handle) else {
+            continue;
+        };
+        let resolution = bake.resolution.max(UVec3::ONE);
+        let dimensions = [
+            resolution.x as usize,
+            resolution.y as usize,
+            resolution.z as usize,
+        ];
+        let resampled = grid.map(|grid| grid.resample(dimensions));
+        let voxels = dimensions_f32(dimensions);
+        let (noise_scale, threshold) = (bake.noise_scale, bake.threshold);
+        let density = |coord: [usize; 3]| match &resampled {
+            Some(grid) => grid.get(coord),
+            None => cloud_density(coord_to_pos(coord, voxels), noise_scale, threshold),
+        };
+        let (volume, texture_size, data) = if bake.sparse {
+            let mut bricks =
+                BrickVolume::bake(dimensions, |coord| vec4(0., 0., density(coord), 0.));
+            bricks.bake_light(&bake.light, to_sun);
+            info!("Sparse volume cloud: {}", bricks.memory());
+            let table_size = UVec3::from(bricks.bricks.map(|b| b as u32));
+            material.brick_table = Some(images.add(brick_table_image(table_size, &bricks.table)));
+            let (atlas_size, atlas) = bricks.atlas();
+            (BakedVolume::Bricks(bricks), atlas_size, atlas)
+        } else {
+            let mut data = bake_voxels(dimensions, density);
+            bake_light(&mut data, dimensions, &bake.light, to_sun, 1);
+            material.brick_table = Some(images.add(brick_table_image(UVec3::ONE, &[0])));
+            (BakedVolume::Dense(data.clone()), resolution, data)
+        };
+        let image = images.add(volume_image(texture_size, data));
+        material.sparse = bake.sparse;
+        material.sun_direction = to_sun;
+        material.texture_dimensions = resolution.as_vec3();
+        material.light_blend = 1.0;
+        material.sdf = Some(image.clone());
+        material.previous_sdf = Some(image);
+        // Replacing the lighting drops any bake still running for the old volume
+        commands.entity(entity).insert(VolumeLighting {
+            volume: Arc::new(volume),
+            dimensions,
+            baked_for: to_sun,
+            stride: 1,
+            task: None,
+            blend: 1.0,
+        });
     }
 }
 
-// A 16^3 chunk with 1-voxel boundary padding.
+/// Re-bakes the light of boxes the sun has moved away from, one bake per box at a time, and
+/// fades each material from its old lighting to the new.
+fn relight_volume_clouds(
+    mut clouds: Query<(
+        &VolumeBake,
+        &VolumeRelight,
+        &mut VolumeLighting,
+        &Handle<VolumeCloudMaterial>,
+    )>,
+    view: Res<EnvironmentView>,
+    time: Res<Time>,
+    mut cloud_materials: ResMut<Assets<VolumeCloudMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    for (bake, relight, mut lighting, handle) in &mut clouds {
+        let lighting = &mut *lighting;
 
-// This chunk will cover just a single octant of a sphere SDF (radius 15).
+        if lighting.blend < 1.0 {
+            let Some(material) = cloud_materials.get_mut(handle) else {
+                continue;
+            };
+            lighting.blend =
+                (lighting.blend + time.delta_seconds() / relight.blend_time.max(1e-3)).min(1.0);
+            material.light_blend = lighting.blend;
+            if lighting.blend == 1.0 {
+                // Let go of the old texture
+                material.previous_sdf = material.sdf.clone();
+            }
+            // The next bake waits for the fade to finish
+            continue;
+        }
+
+        if let Some(task) = &mut lighting.task {
+            let Some(baked) = future::block_on(future::poll_once(task)) else {
+                continue;
+            };
+            lighting.task = None;
+            let Some(material) = cloud_materials.get_mut(handle) else {
+                continue;
+            };
+            material.previous_sdf = material.sdf.take();
+            material.sdf = Some(images.add(volume_image(baked.resolution, baked.data)));
+            material.sun_direction = baked.to_sun;
+            material.light_blend = 0.0;
+            lighting.baked_for = baked.to_sun;
+            lighting.stride = baked.stride;
+            lighting.blend = 0.0;
+            continue;
+        }
 
-pub fn new_cloud_data(buffer_dimensions: [usize; 3]) -> Vec<Vec4> {
-    let resolution = vec3(
-        buffer_dimensions[0] as f32,
-        buffer_dimensions[1] as f32,
-        buffer_dimensions[2] as f32,
+        if view.sun.is_none() {
+            continue;
+        }
+        let to_sun = -view.sun_direction;
+        let angle = lighting.baked_for.angle_between(to_sun);
+        if angle <= relight.angle && lighting.stride == 1 {
+            continue;
+        }
+        let sparse = matches!(*lighting.volume, BakedVolume::Bricks(_));
+        let stride = if angle > relight.coarse_angle && !sparse {
+            relight.coarse_stride.max(1) as usize
+        } else {
+            1
+        };
+        // Coarse bakes only need to be quick
+        let light = if stride > 1 {
+            LightBake {
+                samples: 1,
+                ..bake.light.clone()
+            }
+        } else {
+            bake.light.clone()
+        };
+        let volume = lighting.volume.clone();
+        let dimensions = lighting.dimensions;
+        lighting.task = Some(AsyncComputeTaskPool::get().spawn(async move {
+            let (resolution, data) = match &*volume {
+                BakedVolume::Dense(data) => {
+                    let mut data = data.clone();
+                    bake_light(&mut data, dimensions, &light, to_sun, stride);
+                    (UVec3::from(dimensions.map(|d| d as u32)), data)
+                }
+                BakedVolume::Bricks(bricks) => {
+                    let mut bricks = bricks.clone();
+                    bricks.bake_light(&light, to_sun);
+                    bricks.atlas()
+                }
+            };
+            LightingBake {
+                to_sun,
+                stride,
+                resolution,
+                data,
+            }
+        }));
+    }
+}
+
+/// An Rgba32Float volume with clamped linear sampling, `data` in x, y then z order.
+fn volume_image(resolution: UVec3, data: Vec<Vec4>) -> Image {
+    let mut image = Image::new(
+        Extent3d {
+            width: resolution.x,
+            height: resolution.y,
+            depth_or_array_layers: resolution.z,
+        },
+        TextureDimension::D3,
+        data.iter()
+            .flat_map(|v| v.to_array())
+            .flat_map(|f| f.to_ne_bytes())
+            .collect(),
+        TextureFormat::Rgba32Float,
     );
+    image.sampler_descriptor = ImageSampler::Descriptor(SamplerDescriptor {
+        mag_filter: FilterMode::Linear,
+        min_filter: FilterMode::Linear,
+        ..default()
+    });
+    image
+}
 
-    let data = Vec::from_iter((0..buffer_dimensions[0]).map(|_| {
-        (0..buffer_dimensions[1])
-            .map(|_| {
-                (0..buffer_dimensions[2])
-                    .map(|_| Mutex::new(vec4(1., 1., 1., 1.)))
-                    .collect_vec()
-            })
-            .collect_vec()
-    }));
-    (0..buffer_dimensions[0])
-        .flat_map(move |x| {
-            (0..buffer_dimensions[1])
-                .flat_map(move |y| (0..buffer_dimensions[2]).map(move |z| (x, y, z)))
-        })
-        .par_bridge()
-        .for_each(|(x, y, z)| {
-            let p = coord_to_pos([x, y, z], resolution);
-            // let d = cloud_sdf(p);
-            let sca = vec3(0.50, 0.50, 0.50) / 100.0 * resolution;
-            let n = ((noise::wfbm(p * sca, Vec3::ONE * 1000.0)
-                * (2.0 + fbmd(p * sca + 110.1231231).x)
-                * 0.5)/*
-             * ((1.0 - (-4.0 * (p.y + 1.0)).exp()) * ((-p.y).exp() - 0.37))*/)
-                .clamp(0.0, 3.0);
-            *data[x][y][z].lock() = vec4(n, 0., n, 0.);
+/// An R32Uint volume of brick table entries, read with `textureLoad`.
+fn brick_table_image(bricks: UVec3, table: &[u32]) -> Image {
+    Image::new(
+        Extent3d {
+            width: bricks.x,
+            height: bricks.y,
+            depth_or_array_layers: bricks.z,
+        },
+        TextureDimension::D3,
+        table.iter().flat_map(|entry| entry.to_ne_bytes()).collect(),
+        TextureFormat::R32Uint,
+    )
+}
+
+/// Bakes the density into `z` and the light from `to_sun` into `x`, `y` and `w` as described
+/// in `bake_light`, returned in texture order with x varying fastest.
+pub fn new_cloud_data(
+    buffer_dimensions: [usize; 3],
+    noise_scale: Vec3,
+    threshold: f32,
+    light: &LightBake,
+    to_sun: Vec3,
+) -> Vec<Vec4> {
+    let mut data = bake_density(buffer_dimensions, noise_scale, threshold);
+    bake_light(&mut data, buffer_dimensions, light, to_sun, 1);
+    data
+}
+
+/// The density channel of `new_cloud_data`, with the light channels left at zero.
+pub fn bake_density(buffer_dimensions: [usize; 3], noise_scale: Vec3, threshold: f32) -> Vec<Vec4> {
+    let resolution = dimensions_f32(buffer_dimensions);
+    bake_voxels(buffer_dimensions, |coord| {
+        cloud_density(coord_to_pos(coord, resolution), noise_scale, threshold)
+    })
+}
+
+/// `density` of every voxel in `z`, with the light channels left at zero.
+pub fn bake_voxels(
+    buffer_dimensions: [usize; 3],
+    density: impl Fn([usize; 3]) -> f32 + Sync,
+) -> Vec<Vec4> {
+    let [width, height, _] = buffer_dimensions;
+    let mut data = vec![Vec4::ZERO; buffer_dimensions.iter().product()];
+
+    // One row of x per chunk
+    data.par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(row, voxels)| {
+            let (y, z) = (row % height, row / height);
+            for (x, voxel) in voxels.iter_mut().enumerate() {
+                *voxel = vec4(0., 0., density([x, y, z]), 0.);
+            }
         });
 
-    // for x in 0..buffer_dimensions[0] {
-    //     for y in 0..buffer_dimensions[1] {
-    //         for z in 0..buffer_dimensions[2] {
-    //         }
-    //     }
-    // }
-    // Sun light info requires sdf and density info
-
-    let sun_base = vec3(-0., 2., 0.).normalize();
-
-    let mut sun_directions = Vec::with_capacity(27);
-    sun_directions.push((sun_base, mie(1.)));
-    // let theta = 0.15;
-    // let angles = [-theta, 0.0, theta];
-    // for x in angles {
-    //     for y in angles {
-    //         for z in angles {
-    //             let direction = rotate(sun_base, x, y, z);
-    //             sun_directions.push((direction, mie(sun_base.dot(direction))));
-    //         }
-    //     }
-    // }
-
-    // Sun raymarching
-
-    (0..buffer_dimensions[0])
-        .flat_map(|x| {
-            (0..buffer_dimensions[1])
-                .flat_map(move |y| (0..buffer_dimensions[2]).map(move |z| (x, y, z)))
-        })
-        .par_bridge()
-        .for_each(|(x, y, z)| {
-            let mut total = 0.;
-            let dt = 2. / resolution.x.min(resolution.y).min(resolution.z) * 0.1;
-            for (sun_direction, phase) in sun_directions.iter() {
-                let mut t = 1.;
-                let mut p = coord_to_pos([x, y, z], resolution);
-                let mut sample_point = [x, y, z];
-                while let Some(samp) = data
-                    .get(sample_point[0])
-                    .and_then(|slice| slice.get(sample_point[1]))
-                    .and_then(|row| row.get(sample_point[2]))
-                {
-                    let samp = samp.lock();
-                    if p.x.abs() > 1. || p.y.abs() > 1. || p.z.abs() > 1. {
-                        break;
-                    }
+    data
+}
 
-                    let dm = 0.5;
-                    let noise = 0.0_f32.max(samp.z - dm);
-                    // if noise > 0.0 {
-                    t += noise * dt * 5.0;
-                    // println!("{noise} {t}");
-                    // }
-                    p += *sun_direction * dt * *phase;
-                    sample_point = pos_to_coord(p, resolution);
-                }
-                total += t;
+/// The density at `p` in the box's -1..1 space.
+pub fn cloud_density(p: Vec3, noise_scale: Vec3, threshold: f32) -> f32 {
+    let n = (noise::wfbm(p * noise_scale, Vec3::ONE * 1000.0)
+        * (2.0 + fbmd(p * noise_scale + 110.123_12).x)
+        * 0.5)
+        .clamp(0.0, 3.0);
+    // Rounds off the bottom and top of the box
+    let height = (1.0 - (-4.0 * (p.y + 1.0)).exp()) * ((-p.y).exp() - 0.37);
+    (n * height - threshold).max(0.0)
+}
+
+/// Fills `y` with the transmittance towards `to_sun` through the density in `z`, `x` with the
+/// multiple scattering and `w` with the powder term. A `stride` above 1 marches once per
+/// cube of `stride` voxels, with steps as long as the cube.
+pub fn bake_light(
+    data: &mut [Vec4],
+    buffer_dimensions: [usize; 3],
+    light: &LightBake,
+    to_sun: Vec3,
+    stride: usize,
+) {
+    let stride = stride.max(1);
+    let [width, height, _] = buffer_dimensions;
+    let resolution = dimensions_f32(buffer_dimensions);
+    let cells = buffer_dimensions.map(|d| d.div_ceil(stride));
+
+    // Sun raymarching from the middle of each cell, in the box's -1..1 space
+    let density = data.iter().map(|voxel| voxel.z).collect::<Vec<_>>();
+    let directions = light.directions(to_sun);
+    let dt = 2. / resolution.max_element() * stride as f32;
+    let mut received = vec![Vec3::ZERO; cells.iter().product()];
+    received
+        .par_chunks_mut(cells[0])
+        .enumerate()
+        .for_each(|(row, cell_row)| {
+            let (cy, cz) = (row % cells[1], row / cells[1]);
+            for (cx, cell) in cell_row.iter_mut().enumerate() {
+                let start = [0, 1, 2].map(|axis| {
+                    ([cx, cy, cz][axis] * stride + stride / 2).min(buffer_dimensions[axis] - 1)
+                });
+                *cell = received_light(
+                    start,
+                    buffer_dimensions,
+                    &directions,
+                    dt,
+                    light,
+                    |[x, y, z]| density[(z * height + y) * width + x],
+                );
+            }
+        });
+
+    data.par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(row, voxels)| {
+            let (y, z) = (row % height, row / height);
+            for (x, voxel) in voxels.iter_mut().enumerate() {
+                let cell = ((z / stride) * cells[1] + y / stride) * cells[0] + x / stride;
+                let [single, multiple, powder] = received[cell].to_array();
+                (voxel.x, voxel.y, voxel.w) = (multiple, single, powder);
             }
-            data[x][y][z].lock().y = total / sun_directions.len() as f32;
         });
-    data.iter()
-        .flat_map(|row_col| {
-            row_col
-                .iter()
-                .flat_map(|row| row.iter().map(|c| c.lock().clone()))
-        })
-        .collect_vec()
 }
 
-#[allow(dead_code)]
-fn rotate(v: Vec3, x: f32, y: f32, z: f32) -> Vec3 {
-    Mat3::from_euler(bevy::prelude::EulerRot::XYZ, x, y, z) * v
+/// The single scattering, multiple scattering and powder at `start`, marching towards each
+/// of `directions` in steps of `dt` through `density`, which is only asked for voxels inside
+/// `buffer_dimensions`.
+pub(crate) fn received_light(
+    start: [usize; 3],
+    buffer_dimensions: [usize; 3],
+    directions: &[(Vec3, f32)],
+    dt: f32,
+    light: &LightBake,
+    density: impl Fn([usize; 3]) -> f32,
+) -> Vec3 {
+    let resolution = dimensions_f32(buffer_dimensions);
+    let mut sum = Vec3::ZERO;
+    for &(direction, weight) in directions {
+        let mut depth = 0.;
+        let mut p = coord_to_pos(start, resolution);
+        let mut sample_point = start;
+        while voxel_index(sample_point, buffer_dimensions).is_some() {
+            if p.x.abs() > 1. || p.y.abs() > 1. || p.z.abs() > 1. {
+                break;
+            }
+            depth += density(sample_point) * dt;
+            p += direction * dt;
+            sample_point = pos_to_coord(p, resolution);
+        }
+        let optical_depth = light.absorption * depth;
+        sum += weight
+            * vec3(
+                (-optical_depth).exp(),
+                light.scattering.scattered(optical_depth),
+                powder(optical_depth),
+            );
+    }
+    sum
 }
 
-fn mie(costh: f32) -> f32 {
-    // This function was optimized to minimize (delta*delta)/reference in order to capture
-    // the low intensity behavior.
-    let params = [
-        9.805233e-06,
-        -6.500000e+01,
-        -5.500000e+01,
-        8.194068e-01,
-        1.388198e-01,
-        -8.370334e+01,
-        7.810083e+00,
-        2.054747e-03,
-        2.600563e-02,
-        -4.552125e-12,
-    ];
-
-    let p1 = costh + params[3];
-    let exp_values = vec4(
-        params[1] * costh + params[2],
-        params[5] * p1 * p1,
-        params[6] * costh,
-        params[9] * costh,
+pub(crate) fn dimensions_f32(dimensions: [usize; 3]) -> Vec3 {
+    vec3(
+        dimensions[0] as f32,
+        dimensions[1] as f32,
+        dimensions[2] as f32,
     )
-    .exp();
-    let exp_val_weight = vec4(params[0], params[4], params[7], params[8]);
-    return exp_values.dot(exp_val_weight) * 0.25;
+}
+
+/// Index of `coord` in a flat buffer with x varying fastest, if it's inside `dimensions`.
+pub fn voxel_index(coord: [usize; 3], dimensions: [usize; 3]) -> Option<usize> {
+    let [x, y, z] = coord;
+    let [width, height, depth] = dimensions;
+    (x < width && y < height && z < depth).then(|| (z * height + y) * width + x)
+}
+
+#[allow(dead_code)]
+fn rotate(v: Vec3, x: f32, y: f32, z: f32) -> Vec3 {
+    Mat3::from_euler(bevy::prelude::EulerRot::XYZ, x, y, z) * v
 }
 
 pub fn coord_to_pos<T: AsF32 + Copy>(coord: [T; 3], res: Vec3) -> Vec3 {
@@ -280,46 +869,197 @@ impl AsF32 for usize {
 
 /// The Material trait is very configurable, but comes with sensible defaults for all methods.
 /// You only need to implement functions for features that need non-default behavior. See the Material api docs for details!
-impl Material for RMCloudMaterial {
+impl Material for VolumeCloudMaterial {
     fn fragment_shader() -> ShaderRef {
         "shaders/rm_cloud.wgsl".into()
     }
 
     fn alpha_mode(&self) -> AlphaMode {
-        AlphaMode::Blend
+        AlphaMode::Premultiplied
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if key.bind_group_data.sparse {
+            if let Some(fragment) = descriptor.fragment.as_mut() {
+                fragment.shader_defs.push("BRICKS".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EnvironmentUniforms for VolumeCloudMaterial {
+    // The sun direction stays at the one the lighting was baked for
+    fn set_environment(&mut self, view: &EnvironmentView) {
+        self.time = view.time;
     }
 }
 
 // This is the struct that will be passed to your shader
 #[derive(AsBindGroup, TypeUuid, Debug, Clone, Default, Reflect)]
-#[uuid = "f690fdae-d598-45ab-8225-97e2a3f056e0"]
-pub struct RMCloudMaterial {
+#[uuid = "b41c6d2e-93a7-4f08-8d55-0e7c2a9f61d3"]
+#[bind_group_data(VolumeCloudMaterialKey)]
+pub struct VolumeCloudMaterial {
+    /// Towards the sun the volume was baked for.
     #[uniform(0)]
     pub sun_direction: Vec3,
+    /// World to box space, where the box spans -0.5..0.5.
     #[uniform(0)]
-    pub camera_position: Vec3,
-    #[uniform(0)]
-    pub aabb_position: Vec3,
+    pub inverse_model: Mat4,
     #[uniform(0)]
     pub texture_dimensions: Vec3,
     #[uniform(0)]
-    pub scale: Vec3,
+    pub density: f32,
+    #[uniform(0)]
+    pub steps: f32,
     #[uniform(0)]
     pub time: f32,
+    /// Fades the light channel from `previous_sdf` at 0 to `sdf` at 1.
+    #[uniform(0)]
+    pub light_blend: f32,
+    #[uniform(0)]
+    pub eccentricity: f32,
+    #[uniform(0)]
+    pub powder: f32,
     #[texture(1, dimension = "3d")]
     #[sampler(2)]
     pub sdf: Option<Handle<Image>>,
+    /// The volume before the last relight, only its light channels are read.
+    #[texture(3, dimension = "3d")]
+    #[sampler(4)]
+    pub previous_sdf: Option<Handle<Image>>,
+    /// The brick table of a sparse volume, whose `sdf` is then the brick atlas. A single
+    /// empty entry for dense volumes.
+    #[texture(5, dimension = "3d", sample_type = "u_int")]
+    pub brick_table: Option<Handle<Image>>,
+    pub sparse: bool,
 }
 
-#[derive(AsBindGroup, TypeUuid, Debug, Clone, Default, Reflect)]
-#[uuid = "f692fd8e-d598-45ab-8225-97e2a3f056e0"]
-pub struct GodRayMaterial {
-    #[uniform(0)]
-    pub sun_direction: Vec3,
-    #[uniform(0)]
-    pub camera_position: Vec3,
-    #[uniform(0)]
-    pub scale: Vec3,
-    #[uniform(0)]
-    pub time: f32,
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct VolumeCloudMaterialKey {
+    sparse: bool,
+}
+
+impl From<&VolumeCloudMaterial> for VolumeCloudMaterialKey {
+    fn from(material: &VolumeCloudMaterial) -> Self {
+        Self {
+            sparse: material.sparse,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use antidote::Mutex;
+    use itertools::Itertools;
+
+    use super::*;
+
+    /// The bake as it was before the flat buffer, every voxel behind a mutex. It stores the
+    /// raw noise in `x` and the optical depth towards the sun in `y`.
+    fn locked_cloud_data(
+        buffer_dimensions: [usize; 3],
+        noise_scale: Vec3,
+        threshold: f32,
+        to_sun: Vec3,
+    ) -> Vec<Vec4> {
+        let resolution = vec3(
+            buffer_dimensions[0] as f32,
+            buffer_dimensions[1] as f32,
+            buffer_dimensions[2] as f32,
+        );
+
+        let data = Vec::from_iter((0..buffer_dimensions[0]).map(|_| {
+            (0..buffer_dimensions[1])
+                .map(|_| {
+                    (0..buffer_dimensions[2])
+                        .map(|_| Mutex::new(vec4(1., 1., 1., 1.)))
+                        .collect_vec()
+                })
+                .collect_vec()
+        }));
+        (0..buffer_dimensions[0])
+            .flat_map(move |x| {
+                (0..buffer_dimensions[1])
+                    .flat_map(move |y| (0..buffer_dimensions[2]).map(move |z| (x, y, z)))
+            })
+            .par_bridge()
+            .for_each(|(x, y, z)| {
+                let p = coord_to_pos([x, y, z], resolution);
+                let n = (noise::wfbm(p * noise_scale, Vec3::ONE * 1000.0)
+                    * (2.0 + fbmd(p * noise_scale + 110.123_12).x)
+                    * 0.5)
+                    .clamp(0.0, 3.0);
+                // Rounds off the bottom and top of the box
+                let height = (1.0 - (-4.0 * (p.y + 1.0)).exp()) * ((-p.y).exp() - 0.37);
+                let density = (n * height - threshold).max(0.0);
+                *data[x][y][z].lock() = vec4(n, 0., density, 0.);
+            });
+
+        // Sun raymarching, one voxel per step in the box's -1..1 space
+        let to_sun = to_sun.normalize();
+        let dt = 2. / resolution.max_element();
+        (0..buffer_dimensions[0])
+            .flat_map(|x| {
+                (0..buffer_dimensions[1])
+                    .flat_map(move |y| (0..buffer_dimensions[2]).map(move |z| (x, y, z)))
+            })
+            .par_bridge()
+            .for_each(|(x, y, z)| {
+                let mut depth = 0.;
+                let mut p = coord_to_pos([x, y, z], resolution);
+                let mut sample_point = [x, y, z];
+                while let Some(samp) = data
+                    .get(sample_point[0])
+                    .and_then(|slice| slice.get(sample_point[1]))
+                    .and_then(|row| row.get(sample_point[2]))
+                {
+                    if p.x.abs() > 1. || p.y.abs() > 1. || p.z.abs() > 1. {
+                        break;
+                    }
+                    depth += samp.lock().z * dt;
+                    p += to_sun * dt;
+                    sample_point = pos_to_coord(p, resolution);
+                }
+                data[x][y][z].lock().y = depth;
+            });
+
+        (0..buffer_dimensions[2])
+            .flat_map(|z| (0..buffer_dimensions[1]).map(move |y| (y, z)))
+            .flat_map(|(y, z)| (0..buffer_dimensions[0]).map(move |x| (x, y, z)))
+            .map(|(x, y, z)| *data[x][y][z].lock())
+            .collect_vec()
+    }
+
+    #[test]
+    fn bake_matches_reference() {
+        let noise_scale = vec3(5.0, 1.25, 5.0);
+        let to_sun = vec3(1.0, 2.0, 0.5);
+        // Uneven, so a mixed up axis shows
+        let dimensions = [24, 8, 16];
+        let light = LightBake::from(LightQuality::Hard);
+        let baked = new_cloud_data(dimensions, noise_scale, 0.9, &light, to_sun);
+        let reference = locked_cloud_data(dimensions, noise_scale, 0.9, to_sun);
+        assert!(
+            reference.iter().any(|voxel| voxel.z > 0.0),
+            "no density to compare"
+        );
+        // The flat bake stores transmittance where the old one stored optical depth, and
+        // multiple scattering where it stored the raw noise. Convert the depth, and skip the
+        // noise
+        for (index, (a, b)) in baked.iter().zip(&reference).enumerate() {
+            let transmittance = (-light.absorption * b.y).exp();
+            assert_eq!(
+                [a.y, a.z].map(f32::to_bits),
+                [transmittance, b.z].map(f32::to_bits),
+                "voxel {index} differs"
+            );
+        }
+        assert_eq!(baked.len(), reference.len());
+    }
 }
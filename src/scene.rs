@@ -0,0 +1,433 @@
+use bevy::{
+    asset::{AssetLoader, FileAssetIo, LoadContext, LoadedAsset},
+    core_pipeline::{bloom::BloomSettings, tonemapping::Tonemapping},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::camera::CameraController;
+#[cfg(feature = "rm-cloud")]
+use crate::cloud_shadow::CloudShadowReceiver;
+use crate::timeline::ScrollTimeline;
+use crate::weather::Weather;
+
+/*
+Everything `setup` used to hard-code lives in a `*.scene.ron` file under
+`assets/scenes`. The file is read once while the app is being built, because the
+plugin list decides what gets added to it, and then loaded again as an
+asset so edits to the lighting, cube and camera show up while running.
+Changes to `plugins` need a restart.
+*/
+
+pub const DEFAULT_SCENE: &str = "scenes/portfolio.scene.ron";
+
+pub struct SceneFilePlugin;
+impl Plugin for SceneFilePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<SceneDescription>();
+        app.init_asset_loader::<SceneDescriptionLoader>();
+        app.add_startup_system(watch_scene);
+        app.add_system(reload_scene);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EnvironmentPlugin {
+    RMCloud,
+    VolumeCloud,
+    Water,
+    SkyBox,
+    CloudBlob,
+    FinCloud,
+}
+
+impl EnvironmentPlugin {
+    /// The cargo feature the plugin is built behind.
+    pub fn feature(self) -> &'static str {
+        match self {
+            EnvironmentPlugin::RMCloud => "rm-cloud",
+            EnvironmentPlugin::VolumeCloud => "volume-cloud",
+            EnvironmentPlugin::Water => "water",
+            EnvironmentPlugin::SkyBox => "skybox",
+            EnvironmentPlugin::CloudBlob => "cloud-blob",
+            EnvironmentPlugin::FinCloud => "fin-cloud",
+        }
+    }
+}
+
+/// Mirrors `Tonemapping` so the scene file doesn't depend on bevy's serde support.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TonemappingMode {
+    None,
+    Reinhard,
+    ReinhardLuminance,
+    #[default]
+    AcesFitted,
+    AgX,
+    SomewhatBoringDisplayTransform,
+    TonyMcMapface,
+    BlenderFilmic,
+}
+
+impl From<TonemappingMode> for Tonemapping {
+    fn from(mode: TonemappingMode) -> Self {
+        match mode {
+            TonemappingMode::None => Tonemapping::None,
+            TonemappingMode::Reinhard => Tonemapping::Reinhard,
+            TonemappingMode::ReinhardLuminance => Tonemapping::ReinhardLuminance,
+            TonemappingMode::AcesFitted => Tonemapping::AcesFitted,
+            TonemappingMode::AgX => Tonemapping::AgX,
+            TonemappingMode::SomewhatBoringDisplayTransform => {
+                Tonemapping::SomewhatBoringDisplayTransform
+            }
+            TonemappingMode::TonyMcMapface => Tonemapping::TonyMcMapface,
+            TonemappingMode::BlenderFilmic => Tonemapping::BlenderFilmic,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AmbientDescription {
+    pub color: [f32; 3],
+    pub brightness: f32,
+}
+
+impl Default for AmbientDescription {
+    fn default() -> Self {
+        Self {
+            color: [0.54, 0.8, 1.0],
+            brightness: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SunDescription {
+    pub color: [f32; 3],
+    pub illuminance: f32,
+    /// The direction the light travels in.
+    pub direction: [f32; 3],
+    pub shadows: bool,
+}
+
+impl Default for SunDescription {
+    fn default() -> Self {
+        Self {
+            color: [2.2, 2.05, 1.9],
+            illuminance: 20_000.0,
+            direction: [-1.0, -0.3, 1.0],
+            shadows: false,
+        }
+    }
+}
+
+impl SunDescription {
+    pub fn transform(&self) -> Transform {
+        Transform::IDENTITY.looking_at(Vec3::from(self.direction), Vec3::Y)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CubeDescription {
+    pub size: f32,
+    pub color: [f32; 4],
+    pub position: [f32; 3],
+}
+
+impl Default for CubeDescription {
+    fn default() -> Self {
+        Self {
+            size: 2.0,
+            color: [1.0, 0.6, 0.5, 1.0],
+            position: [4.0, 0.0, 1.0],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraDescription {
+    pub position: [f32; 3],
+    /// Euler angles in radians, applied in XYZ order.
+    pub rotation: [f32; 3],
+    pub far: f32,
+    pub tonemapping: TonemappingMode,
+    pub bloom_intensity: f32,
+    /// Lets the scroll timeline move the camera, its keyframes are offsets from this pose.
+    pub timeline: bool,
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        Self {
+            position: [0.0, 600.0, 0.0],
+            rotation: [-1.5, 0.0, std::f32::consts::PI],
+            far: 100_000.0,
+            tonemapping: TonemappingMode::AcesFitted,
+            bloom_intensity: 0.5,
+            timeline: true,
+        }
+    }
+}
+
+impl CameraDescription {
+    /// Where the scroll timeline's camera keyframes start from, if it may move the camera.
+    pub fn timeline_origin(&self) -> Option<Transform> {
+        self.timeline.then(|| self.transform())
+    }
+
+    pub fn transform(&self) -> Transform {
+        let [x, y, z] = self.rotation;
+        Transform::from_translation(Vec3::from(self.position)).with_rotation(Quat::from_euler(
+            EulerRot::XYZ,
+            x,
+            y,
+            z,
+        ))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, TypeUuid)]
+#[uuid = "5c1e9a77-0b3d-4f62-8e2a-d94b61f3c8a0"]
+#[serde(default)]
+pub struct SceneDescription {
+    pub ambient: AmbientDescription,
+    pub sun: SunDescription,
+    pub cube: Option<CubeDescription>,
+    pub camera: CameraDescription,
+    pub plugins: Vec<EnvironmentPlugin>,
+}
+
+impl Default for SceneDescription {
+    fn default() -> Self {
+        Self {
+            ambient: default(),
+            sun: default(),
+            cube: Some(default()),
+            camera: default(),
+            plugins: vec![EnvironmentPlugin::RMCloud],
+        }
+    }
+}
+
+impl SceneDescription {
+    /// Reads a scene from the assets folder, falling back to the default scene. The folder is
+    /// found the way the `AssetServer` finds it, so this works from any directory.
+    pub fn read(path: &str) -> Self {
+        let file = FileAssetIo::get_base_path()
+            .join(AssetPlugin::default().asset_folder)
+            .join(path);
+        let parsed = std::fs::read(&file)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| ron::de::from_bytes(&bytes).map_err(|err| err.to_string()));
+        match parsed {
+            Ok(scene) => scene,
+            Err(err) => {
+                warn!("Couldn't read scene {:?}, using the default: {err}", file);
+                Self::default()
+            }
+        }
+    }
+}
+
+/// Adds the plugins listed in `scene.plugins`, skipping any that weren't built in.
+#[allow(unused_variables)]
+pub fn add_environment_plugins(app: &mut App, scene: &SceneDescription) {
+    for plugin in &scene.plugins {
+        match plugin {
+            #[cfg(feature = "rm-cloud")]
+            EnvironmentPlugin::RMCloud => {
+                app.add_plugin(crate::cloud::RMCloudPlugin::default());
+            }
+            #[cfg(feature = "volume-cloud")]
+            EnvironmentPlugin::VolumeCloud => {
+                app.add_plugin(crate::rm_cloud::VolumeCloudPlugin::default());
+            }
+            #[cfg(feature = "water")]
+            EnvironmentPlugin::Water => {
+                app.add_plugin(crate::water::WaterPlugin::default());
+            }
+            #[cfg(feature = "skybox")]
+            EnvironmentPlugin::SkyBox => {
+                app.add_plugin(crate::skybox::SkyBoxPlugin {});
+            }
+            #[cfg(feature = "cloud-blob")]
+            EnvironmentPlugin::CloudBlob => {
+                app.add_plugin(crate::cloud_blob::CloudBlobPlugin::default());
+            }
+            #[cfg(feature = "fin-cloud")]
+            EnvironmentPlugin::FinCloud => {
+                app.add_plugin(crate::fin_cloud::FinCloudPlugin);
+            }
+            #[allow(unreachable_patterns)]
+            _ => warn!(
+                "{plugin:?} was skipped, build with the `{}` feature to use it",
+                plugin.feature()
+            ),
+        }
+    }
+}
+
+/// The scene the app was started with, kept up to date with the file.
+#[derive(Resource)]
+pub struct ActiveScene {
+    pub path: String,
+    pub description: SceneDescription,
+    handle: Handle<SceneDescription>,
+}
+
+impl ActiveScene {
+    pub fn new(path: impl Into<String>, description: SceneDescription) -> Self {
+        Self {
+            path: path.into(),
+            description,
+            handle: Handle::default(),
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct SceneSun;
+
+#[derive(Component)]
+pub struct SceneCube;
+
+pub fn spawn_cube(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    cube: &CubeDescription,
+) -> Entity {
+    let [r, g, b, a] = cube.color;
+    let entity = commands
+        .spawn((
+            MaterialMeshBundle {
+                mesh: meshes.add(shape::Cube { size: cube.size }.into()),
+                material: materials.add(Color::rgba(r, g, b, a).into()),
+                transform: Transform::from_translation(Vec3::from(cube.position)),
+                ..default()
+            },
+            SceneCube,
+        ))
+        .id();
+    #[cfg(feature = "rm-cloud")]
+    commands.entity(entity).insert(CloudShadowReceiver);
+    entity
+}
+
+/// Applies the lighting to `weather`'s clear state, which is what's on screen
+/// unless the weather has been changed.
+pub fn apply_lighting(scene: &SceneDescription, weather: &mut Weather) {
+    if let Some(clear) = weather.states.get_mut("clear") {
+        let [r, g, b] = scene.ambient.color;
+        clear.ambient_color = Color::rgb(r, g, b);
+        clear.ambient_brightness = scene.ambient.brightness;
+        let [r, g, b] = scene.sun.color;
+        clear.sun_color = Color::rgb(r, g, b);
+        clear.sun_illuminance = scene.sun.illuminance;
+        clear.bloom_intensity = scene.camera.bloom_intensity;
+    }
+}
+
+fn watch_scene(asset_server: Res<AssetServer>, mut scene: ResMut<ActiveScene>) {
+    scene.handle = asset_server.load(scene.path.as_str());
+}
+
+#[derive(Default)]
+pub struct SceneDescriptionLoader;
+
+impl AssetLoader for SceneDescriptionLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let scene: SceneDescription = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(scene));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["scene.ron"]
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn reload_scene(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<SceneDescription>>,
+    scenes: Res<Assets<SceneDescription>>,
+    mut active: ResMut<ActiveScene>,
+    mut weather: Option<ResMut<Weather>>,
+    mut timeline: Option<ResMut<ScrollTimeline>>,
+    mut ambient: ResMut<AmbientLight>,
+    mut suns: Query<(&mut DirectionalLight, &mut Transform), With<SceneSun>>,
+    mut cameras: Query<(
+        &mut CameraController,
+        &mut Projection,
+        &mut Tonemapping,
+        &mut BloomSettings,
+    )>,
+    cubes: Query<Entity, With<SceneCube>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    // The first load is the same file `setup` spawned from, so only react to edits
+    let modified = events
+        .iter()
+        .any(|event| matches!(event, AssetEvent::Modified { handle } if *handle == active.handle));
+    if !modified {
+        return;
+    }
+    let Some(scene) = scenes.get(&active.handle) else {
+        return;
+    };
+    if scene.plugins != active.description.plugins {
+        info!("Scene plugin list changed, restart to apply it");
+    }
+    active.description = scene.clone();
+    info!("Reloaded scene {}", active.path);
+
+    let [r, g, b] = scene.ambient.color;
+    ambient.color = Color::rgb(r, g, b);
+    ambient.brightness = scene.ambient.brightness;
+    if let Some(weather) = weather.as_mut() {
+        apply_lighting(scene, weather);
+    }
+
+    for (mut light, mut transform) in suns.iter_mut() {
+        let [r, g, b] = scene.sun.color;
+        light.color = Color::rgb(r, g, b);
+        light.illuminance = scene.sun.illuminance;
+        light.shadows_enabled = scene.sun.shadows;
+        *transform = scene.sun.transform();
+    }
+
+    if let Some(timeline) = timeline.as_mut() {
+        timeline.camera_origin = scene.camera.timeline_origin();
+    }
+    for (mut controller, mut projection, mut tonemapping, mut bloom) in cameras.iter_mut() {
+        let transform = scene.camera.transform();
+        controller.handheld.position = transform.translation;
+        controller.handheld.base_rotation = transform.rotation;
+        if let Projection::Perspective(perspective) = projection.as_mut() {
+            perspective.far = scene.camera.far;
+        }
+        *tonemapping = scene.camera.tonemapping.into();
+        bloom.intensity = scene.camera.bloom_intensity;
+    }
+
+    for cube in &cubes {
+        commands.entity(cube).despawn_recursive();
+    }
+    if let Some(cube) = &scene.cube {
+        spawn_cube(&mut commands, &mut meshes, &mut materials, cube);
+    }
+}
@@ -16,16 +16,20 @@ use bevy::{
     },
 };
 
-use crate::{noise, CameraController};
+use crate::global_environment::{add_global_environment, specialize_global_environment};
+use crate::noise;
+use crate::phase::add_phase_shader;
 
 pub struct SkyBoxPlugin {}
 
 impl Plugin for SkyBoxPlugin {
     fn build(&self, app: &mut App) {
+        app.add_plugin(MaterialPlugin::<CubemapMaterial>::default());
         app.add_startup_system(setup);
         app.add_system(cycle_cubemap_asset);
         app.add_system(asset_loaded.after(cycle_cubemap_asset));
-        app.add_system(animate_sky);
+        add_global_environment::<CubemapMaterial>(app);
+        add_phase_shader(app);
     }
 }
 
@@ -216,30 +220,9 @@ pub fn asset_loaded(
     }
 }
 
-pub fn animate_sky(
-    time: Res<Time>,
-    mut cubemap_materials: ResMut<Assets<CubemapMaterial>>,
-    sun: Query<&Transform, With<DirectionalLight>>,
-    camera: Query<&Transform, With<CameraController>>,
-) {
-    let sun_direction = sun.single().forward();
-    let cam_pos = camera.single().translation;
-    for material in cubemap_materials.iter_mut() {
-        material.1.time = time.elapsed_seconds();
-        material.1.sun_direction = sun_direction;
-        material.1.camera_positon = cam_pos;
-    }
-}
-
 #[derive(AsBindGroup, Debug, Clone, TypeUuid, Default, PartialEq)]
 #[uuid = "9509a0f8-3c05-48ee-a13e-a93226c7f488"]
 pub struct CubemapMaterial {
-    #[uniform(0)]
-    camera_positon: Vec3,
-    #[uniform(0)]
-    sun_direction: Vec3,
-    #[uniform(0)]
-    time: f32,
     #[texture(1)]
     #[sampler(2)]
     pub noise_texture: Option<Handle<Image>>,
@@ -258,9 +241,10 @@ impl Material for CubemapMaterial {
         _pipeline: &MaterialPipeline<Self>,
         descriptor: &mut RenderPipelineDescriptor,
         _layout: &MeshVertexBufferLayout,
-        _key: MaterialPipelineKey<Self>,
+        key: MaterialPipelineKey<Self>,
     ) -> Result<(), SpecializedMeshPipelineError> {
         descriptor.primitive.cull_mode = None;
+        specialize_global_environment(descriptor, &key);
         Ok(())
     }
 }
@@ -0,0 +1,497 @@
+use bevy::{
+    input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel},
+    prelude::*,
+    utils::HashSet,
+};
+
+use crate::camera::{CameraController, CameraShake};
+#[cfg(feature = "rm-cloud")]
+use crate::cloud::RMCloud;
+use crate::easing::Easing;
+use crate::weather::{update_weather, CloudParams};
+
+/*
+The page is one long scroll. Input moves `target`, `progress` chases it with
+some smoothing, and whichever sections contain `progress` drive the camera and
+cloud parameters from their keyframes.
+
+Camera keyframes are offsets from `camera_origin`, which the scene file sets to
+its camera, so the timeline moves whatever camera the scene starts with. It
+leaves the camera alone while there is no origin.
+*/
+
+pub struct TimelinePlugin;
+impl Plugin for TimelinePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ScrollTimeline>();
+        app.add_event::<SectionEntered>();
+        app.add_event::<SectionExited>();
+        app.add_system(timeline_input);
+        app.add_system(advance_timeline.after(timeline_input));
+        app.add_system(apply_timeline.after(advance_timeline).after(update_weather));
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TimelineKeyframe {
+    /// Position within the section, `0..=1`.
+    pub at: f32,
+    /// Easing used on the way into this keyframe from the previous one.
+    pub easing: Easing,
+    /// Offset from `ScrollTimeline::camera_origin`. The translation is added to the
+    /// origin's and the rotation is applied after the origin's, in world space.
+    pub camera: Option<Transform>,
+    pub cloud: Option<CloudParams>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TimelineSection {
+    pub name: String,
+    pub start: f32,
+    pub end: f32,
+    /// Sorted by `at`.
+    pub keyframes: Vec<TimelineKeyframe>,
+}
+
+impl TimelineSection {
+    pub fn contains(&self, progress: f32) -> bool {
+        progress >= self.start && progress <= self.end
+    }
+
+    pub fn local(&self, progress: f32) -> f32 {
+        ((progress - self.start) / (self.end - self.start).max(f32::EPSILON)).clamp(0.0, 1.0)
+    }
+
+    /// The keyframes either side of `t` that have a value picked by `get`, and the eased blend between them.
+    fn sample<T: Clone>(
+        &self,
+        t: f32,
+        get: impl Fn(&TimelineKeyframe) -> Option<T>,
+    ) -> Option<(T, T, f32)> {
+        let keys = self
+            .keyframes
+            .iter()
+            .filter_map(|key| get(key).map(|value| (key, value)))
+            .collect::<Vec<_>>();
+        let first = keys.first()?;
+        if t <= first.0.at {
+            return Some((first.1.clone(), first.1.clone(), 0.0));
+        }
+        for pair in keys.windows(2) {
+            let ((a, va), (b, vb)) = (&pair[0], &pair[1]);
+            if t <= b.at {
+                let s = (t - a.at) / (b.at - a.at).max(f32::EPSILON);
+                return Some((va.clone(), vb.clone(), b.easing.apply(s)));
+            }
+        }
+        let last = keys.last()?;
+        Some((last.1.clone(), last.1.clone(), 0.0))
+    }
+
+    pub fn camera_at(&self, t: f32) -> Option<Transform> {
+        let (a, b, s) = self.sample(t, |key| key.camera)?;
+        Some(Transform {
+            translation: a.translation.lerp(b.translation, s),
+            rotation: a.rotation.slerp(b.rotation, s),
+            scale: a.scale.lerp(b.scale, s),
+        })
+    }
+
+    pub fn cloud_at(&self, t: f32) -> Option<CloudParams> {
+        let (a, b, s) = self.sample(t, |key| key.cloud)?;
+        Some(a.lerp(&b, s))
+    }
+}
+
+#[derive(Resource)]
+pub struct ScrollTimeline {
+    /// Where input has asked to go, `0..=1`.
+    pub target: f32,
+    /// Smoothed position along the timeline, `0..=1`.
+    pub progress: f32,
+    /// Left over scroll momentum, in progress per second.
+    pub velocity: f32,
+    /// How quickly `progress` catches up with `target`, per second.
+    pub smoothing: f32,
+    /// How quickly momentum dies off, per second.
+    pub friction: f32,
+    /// Progress per wheel notch.
+    pub line_speed: f32,
+    /// Progress per touchpad pixel.
+    pub pixel_speed: f32,
+    /// Progress per arrow key press, page keys move four times as far.
+    pub key_step: f32,
+    /// Progress per pixel of mouse or touch drag.
+    pub drag_speed: f32,
+    /// How far the clouds scroll over the whole timeline.
+    pub cloud_scroll: f32,
+    /// Camera trauma added when scrolling into a new section.
+    pub section_shake: f32,
+    /// The pose camera keyframes are relative to, `None` leaves the camera alone.
+    pub camera_origin: Option<Transform>,
+    pub sections: Vec<TimelineSection>,
+    active: HashSet<String>,
+}
+
+impl Default for ScrollTimeline {
+    fn default() -> Self {
+        // Sinks and tilts up towards the horizon from the scene's camera
+        let down = |drop: f32, tilt: f32| {
+            Transform::from_xyz(0.0, -drop, 0.0).with_rotation(Quat::from_rotation_x(tilt))
+        };
+        let key = |at: f32, camera: Transform| TimelineKeyframe {
+            at,
+            easing: Easing::SmoothStep,
+            camera: Some(camera),
+            cloud: None,
+        };
+        Self {
+            target: 0.0,
+            progress: 0.0,
+            velocity: 0.0,
+            smoothing: 6.0,
+            friction: 4.0,
+            line_speed: 0.02,
+            pixel_speed: 0.0005,
+            key_step: 0.05,
+            drag_speed: 0.001,
+            cloud_scroll: 1.0,
+            section_shake: 0.15,
+            camera_origin: None,
+            sections: vec![
+                TimelineSection {
+                    name: "intro".to_string(),
+                    start: 0.0,
+                    end: 0.25,
+                    keyframes: vec![key(0.0, down(0., 0.)), key(1.0, down(150., 0.2))],
+                },
+                TimelineSection {
+                    name: "about".to_string(),
+                    start: 0.25,
+                    end: 0.5,
+                    keyframes: vec![key(0.0, down(150., 0.2)), key(1.0, down(250., 0.5))],
+                },
+                TimelineSection {
+                    name: "projects".to_string(),
+                    start: 0.5,
+                    end: 0.75,
+                    keyframes: vec![key(0.0, down(250., 0.5)), key(1.0, down(350., 0.9))],
+                },
+                TimelineSection {
+                    name: "contact".to_string(),
+                    start: 0.75,
+                    end: 1.0,
+                    keyframes: vec![key(0.0, down(350., 0.9)), key(1.0, down(400., 1.2))],
+                },
+            ],
+            active: HashSet::default(),
+        }
+    }
+}
+
+impl ScrollTimeline {
+    /// Moves the target, clamped to the timeline.
+    pub fn nudge(&mut self, amount: f32) {
+        self.target = (self.target + amount).clamp(0.0, 1.0);
+    }
+
+    pub fn jump_to_section(&mut self, name: &str) {
+        if let Some(section) = self.sections.iter().find(|section| section.name == name) {
+            self.target = section.start;
+            self.velocity = 0.0;
+        }
+    }
+
+    pub fn active_sections(&self) -> impl Iterator<Item = &TimelineSection> {
+        self.sections
+            .iter()
+            .filter(|section| section.contains(self.progress))
+    }
+
+    /// Carries `target` along with the scroll momentum, which stops at either end, and
+    /// eases `progress` towards it.
+    pub fn advance(&mut self, dt: f32) {
+        self.target = (self.target + self.velocity * dt).clamp(0.0, 1.0);
+        if self.target <= 0.0 || self.target >= 1.0 {
+            self.velocity = 0.0;
+        }
+        self.velocity *= (-self.friction * dt).exp();
+        let blend = 1.0 - (-self.smoothing * dt).exp();
+        self.progress += (self.target - self.progress) * blend;
+    }
+
+    /// Sections share their boundaries, so the section being scrolled into can be entered
+    /// before the one being left is exited. Returns the names exited and entered, each in
+    /// timeline order.
+    fn update_active(&mut self) -> (Vec<String>, Vec<String>) {
+        let active = self
+            .active_sections()
+            .map(|section| section.name.clone())
+            .collect::<HashSet<_>>();
+        let left = |from: &HashSet<String>, to: &HashSet<String>| {
+            self.sections
+                .iter()
+                .filter(|section| from.contains(&section.name) && !to.contains(&section.name))
+                .map(|section| section.name.clone())
+                .collect::<Vec<_>>()
+        };
+        let exited = left(&self.active, &active);
+        let entered = left(&active, &self.active);
+        self.active = active;
+        (exited, entered)
+    }
+
+    /// The camera offset at `progress`, the later section wins where two meet.
+    pub fn camera_pose(&self) -> Option<Transform> {
+        self.active_sections().fold(None, |pose, section| {
+            section.camera_at(section.local(self.progress)).or(pose)
+        })
+    }
+
+    pub fn cloud_params(&self) -> Option<CloudParams> {
+        self.active_sections().fold(None, |params, section| {
+            section.cloud_at(section.local(self.progress)).or(params)
+        })
+    }
+}
+
+pub struct SectionEntered {
+    pub name: String,
+}
+
+pub struct SectionExited {
+    pub name: String,
+}
+
+fn timeline_input(
+    mut timeline: ResMut<ScrollTimeline>,
+    mut wheel: EventReader<MouseWheel>,
+    mut motion: EventReader<MouseMotion>,
+    mouse: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    touches: Res<Touches>,
+) {
+    // Wheel and touchpad scrolling carry momentum, everything else moves the target directly
+    for event in wheel.iter() {
+        let speed = match event.unit {
+            MouseScrollUnit::Line => timeline.line_speed,
+            MouseScrollUnit::Pixel => timeline.pixel_speed,
+        };
+        timeline.velocity -= event.y * speed * timeline.friction;
+    }
+
+    let step = timeline.key_step;
+    for key in keys.get_just_pressed() {
+        match key {
+            KeyCode::Down | KeyCode::Space => timeline.nudge(step),
+            KeyCode::Up => timeline.nudge(-step),
+            KeyCode::PageDown => timeline.nudge(step * 4.0),
+            KeyCode::PageUp => timeline.nudge(-step * 4.0),
+            KeyCode::Home => timeline.target = 0.0,
+            KeyCode::End => timeline.target = 1.0,
+            _ => {}
+        }
+    }
+
+    let mut drag: f32 = 0.0;
+    if mouse.pressed(MouseButton::Left) {
+        drag += motion.iter().map(|m| m.delta.y).sum::<f32>();
+    } else {
+        motion.clear();
+    }
+    drag += touches.iter().map(|touch| touch.delta().y).sum::<f32>();
+    if drag != 0.0 {
+        let speed = timeline.drag_speed;
+        timeline.nudge(-drag * speed);
+        timeline.velocity = 0.0;
+    }
+}
+
+fn advance_timeline(
+    time: Res<Time>,
+    mut timeline: ResMut<ScrollTimeline>,
+    mut entered: EventWriter<SectionEntered>,
+    mut exited: EventWriter<SectionExited>,
+    mut shake: EventWriter<CameraShake>,
+) {
+    timeline.advance(time.delta_seconds());
+
+    // Nothing was active on the first frame, so starting up doesn't count as a transition
+    let started = !timeline.active.is_empty();
+    let (left, reached) = timeline.update_active();
+    if started && !reached.is_empty() {
+        shake.send(CameraShake {
+            trauma: timeline.section_shake,
+        });
+    }
+    exited.send_batch(left.into_iter().map(|name| SectionExited { name }));
+    entered.send_batch(reached.into_iter().map(|name| SectionEntered { name }));
+}
+
+pub fn apply_timeline(
+    timeline: Res<ScrollTimeline>,
+    mut camera: Query<&mut CameraController>,
+    #[cfg(feature = "rm-cloud")] mut clouds: Query<&mut RMCloud>,
+) {
+    if let (Some(origin), Some(pose), Ok(mut controller)) = (
+        timeline.camera_origin,
+        timeline.camera_pose(),
+        camera.get_single_mut(),
+    ) {
+        controller.handheld.position = origin.translation + pose.translation;
+        controller.handheld.base_rotation = pose.rotation * origin.rotation;
+    }
+
+    #[cfg(feature = "rm-cloud")]
+    {
+        let cloud_params = timeline.cloud_params();
+        for mut cloud in clouds.iter_mut() {
+            cloud.scroll = timeline.progress * timeline.cloud_scroll;
+            if let (Some(params), true) = (cloud_params, cloud.follows_weather) {
+                params.apply(&mut cloud);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn at(progress: f32) -> ScrollTimeline {
+        ScrollTimeline {
+            progress,
+            ..default()
+        }
+    }
+
+    fn pose_at(progress: f32) -> Transform {
+        at(progress).camera_pose().unwrap()
+    }
+
+    fn assert_same_pose(actual: Transform, expected: Transform) {
+        assert!(
+            actual.translation.abs_diff_eq(expected.translation, 1e-3)
+                && actual.rotation.abs_diff_eq(expected.rotation, 1e-3),
+            "{actual:?} is not {expected:?}"
+        );
+    }
+
+    #[test]
+    fn sampling_at_section_boundaries() {
+        let timeline = ScrollTimeline::default();
+        for section in &timeline.sections {
+            let (first, last) = (&section.keyframes[0], section.keyframes.last().unwrap());
+            assert_eq!(section.local(section.start), 0.0);
+            assert_eq!(section.local(section.end), 1.0);
+            assert_eq!(section.camera_at(0.0), first.camera);
+            assert_eq!(section.camera_at(-1.0), first.camera);
+            assert_same_pose(section.camera_at(1.0).unwrap(), last.camera.unwrap());
+        }
+
+        // The later section wins where two meet, and both agree on the pose there
+        let about = &timeline.sections[1];
+        assert_eq!(at(0.25).camera_pose(), about.keyframes[0].camera);
+        assert_same_pose(pose_at(0.25 - 1e-4), pose_at(0.25));
+        assert_same_pose(pose_at(0.25 + 1e-4), pose_at(0.25));
+        assert_eq!(
+            at(0.0).camera_pose(),
+            timeline.sections[0].keyframes[0].camera
+        );
+
+        // Keys inside the section hold their values out to its ends
+        let inset = TimelineSection {
+            name: "inset".to_string(),
+            start: 0.0,
+            end: 1.0,
+            keyframes: vec![
+                TimelineKeyframe {
+                    at: 0.3,
+                    easing: Easing::Linear,
+                    camera: Some(Transform::from_xyz(1.0, 0.0, 0.0)),
+                    cloud: None,
+                },
+                TimelineKeyframe {
+                    at: 0.7,
+                    easing: Easing::Linear,
+                    camera: Some(Transform::from_xyz(3.0, 0.0, 0.0)),
+                    cloud: None,
+                },
+            ],
+        };
+        assert_eq!(inset.camera_at(0.1).unwrap().translation.x, 1.0);
+        assert_eq!(inset.camera_at(0.5).unwrap().translation.x, 2.0);
+        assert_eq!(inset.camera_at(0.9).unwrap().translation.x, 3.0);
+        assert_eq!(inset.cloud_at(0.5), None);
+    }
+
+    #[test]
+    fn momentum_stops_at_the_ends() {
+        let mut timeline = ScrollTimeline {
+            target: 0.99,
+            progress: 0.99,
+            velocity: 1.0,
+            ..default()
+        };
+        timeline.advance(0.1);
+        assert_eq!((timeline.target, timeline.velocity), (1.0, 0.0));
+        assert!(timeline.progress > 0.99 && timeline.progress < 1.0);
+
+        timeline.target = 0.01;
+        timeline.velocity = -1.0;
+        timeline.advance(0.1);
+        assert_eq!((timeline.target, timeline.velocity), (0.0, 0.0));
+
+        // Away from the ends momentum carries on and dies off
+        timeline.target = 0.5;
+        timeline.velocity = 0.1;
+        timeline.advance(0.1);
+        assert!((timeline.target - 0.51).abs() < 1e-6);
+        assert!((timeline.velocity - 0.1 * (-timeline.friction * 0.1).exp()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn section_lookup() {
+        let active = |progress: f32| {
+            at(progress)
+                .active_sections()
+                .map(|section| section.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(active(0.1), ["intro"]);
+        assert_eq!(active(0.25), ["intro", "about"]);
+        assert_eq!(active(1.0), ["contact"]);
+        assert!(active(1.5).is_empty());
+
+        let mut timeline = at(0.9);
+        timeline.velocity = 0.5;
+        timeline.jump_to_section("projects");
+        assert_eq!((timeline.target, timeline.velocity), (0.5, 0.0));
+        timeline.jump_to_section("missing");
+        assert_eq!(timeline.target, 0.5);
+    }
+
+    #[test]
+    fn sections_entered_and_exited_in_order() {
+        let mut timeline = at(0.2);
+        let mut scroll_to = |progress: f32| {
+            timeline.progress = progress;
+            timeline.update_active()
+        };
+        let none = Vec::<String>::new();
+        assert_eq!(scroll_to(0.2), (none.clone(), names(&["intro"])));
+        assert_eq!(scroll_to(0.2), (none.clone(), none.clone()));
+        // On the boundary the next section is entered first, the last one is left after
+        assert_eq!(scroll_to(0.25), (none.clone(), names(&["about"])));
+        assert_eq!(scroll_to(0.3), (names(&["intro"]), none.clone()));
+        assert_eq!(scroll_to(0.8), (names(&["about"]), names(&["contact"])));
+        assert_eq!(
+            scroll_to(0.25),
+            (names(&["contact"]), names(&["intro", "about"]))
+        );
+    }
+}
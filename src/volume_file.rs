@@ -0,0 +1,397 @@
+//! Density volumes on disk, for bringing clouds in from Blender or Houdini and saving the
+//! procedural bakes. Files end in `.cvol` and load as `DensityGrid` assets, which a
+//! `VolumeBake` can bake the lighting for instead of its noise.
+//!
+//! All values are little endian:
+//!
+//! | Offset | Size | Contents                                                   |
+//! |--------|------|------------------------------------------------------------|
+//! | 0      | 4    | `CVOL`                                                     |
+//! | 4      | 4    | u32 version, 1                                             |
+//! | 8      | 4    | u32 layout, 0 dense or 1 leaves                            |
+//! | 12     | 12   | u32 voxels along x, y and z                                |
+//! | 24     | 4    | f32 background, the density wherever no leaf is stored     |
+//! | 28     |      | the voxels in the layout below                             |
+//!
+//! A dense file follows with every voxel as an f32, x varying fastest, then y, then z.
+//!
+//! A leaf file follows with a u32 count of leaves, each one a u32 x, y and z origin on a
+//! multiple of 8 and then its 8³ voxels as f32, x varying fastest. These are the leaf
+//! nodes of an OpenVDB or NanoVDB float grid without the tree above them, so an exporter
+//! only has to walk the grid's active leaves. Voxels of a leaf past the edge of the volume
+//! are written but ignored.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    math::vec3,
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+
+use crate::bricks::{brick_voxel, BRICK_SIZE};
+use crate::rm_cloud::voxel_index;
+
+const MAGIC: &[u8; 4] = b"CVOL";
+const VERSION: u32 = 1;
+const DENSE: u32 = 0;
+const LEAVES: u32 = 1;
+const LEAF_VOXELS: usize = BRICK_SIZE * BRICK_SIZE * BRICK_SIZE;
+/// 1 GiB of densities, far past anything a cloud texture holds, so a broken header can't ask
+/// for more memory than that.
+const MAX_VOXELS: usize = 1 << 28;
+
+/// A grid of densities, read from or written to a volume file.
+#[derive(Clone, Debug, PartialEq, TypeUuid)]
+#[uuid = "3f9e2c71-6d0a-4b58-9e1f-c4a7d28b5e06"]
+pub struct DensityGrid {
+    /// Voxels along each axis.
+    pub dimensions: [usize; 3],
+    /// x varying fastest.
+    pub data: Vec<f32>,
+}
+
+#[derive(Debug)]
+pub enum VolumeFileError {
+    Io(io::Error),
+    NotAVolume,
+    Version(u32),
+    Layout(u32),
+    /// A leaf origin off the 8 voxel grid or outside the volume.
+    Leaf([u32; 3]),
+    /// No voxels along an axis, or more than `MAX_VOXELS` in all.
+    Dimensions([u32; 3]),
+}
+
+impl fmt::Display for VolumeFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VolumeFileError::Io(err) => write!(f, "{err}"),
+            VolumeFileError::NotAVolume => write!(f, "not a volume file"),
+            VolumeFileError::Version(version) => write!(f, "unknown version {version}"),
+            VolumeFileError::Layout(layout) => write!(f, "unknown layout {layout}"),
+            VolumeFileError::Leaf(origin) => write!(f, "leaf at {origin:?} is off the grid"),
+            VolumeFileError::Dimensions(dimensions) => {
+                write!(f, "can't hold {dimensions:?} voxels")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VolumeFileError {}
+
+impl From<io::Error> for VolumeFileError {
+    fn from(err: io::Error) -> Self {
+        VolumeFileError::Io(err)
+    }
+}
+
+impl DensityGrid {
+    /// The density channel of a baked volume, as `rm_cloud::new_cloud_data` returns it.
+    pub fn from_volume(dimensions: [usize; 3], volume: &[Vec4]) -> Self {
+        Self {
+            dimensions,
+            data: volume.iter().map(|voxel| voxel.z).collect(),
+        }
+    }
+
+    /// The density at `coord`, 0 outside the grid.
+    pub fn get(&self, coord: [usize; 3]) -> f32 {
+        voxel_index(coord, self.dimensions).map_or(0.0, |index| self.data[index])
+    }
+
+    /// Filters the grid to `dimensions` linearly, sampling at the voxel centres so the
+    /// volume keeps its place in the box.
+    pub fn resample(&self, dimensions: [usize; 3]) -> Self {
+        if dimensions == self.dimensions {
+            return self.clone();
+        }
+        let scale = [0, 1, 2].map(|axis| self.dimensions[axis] as f32 / dimensions[axis] as f32);
+        let last = self.dimensions.map(|d| d.max(1) as f32 - 1.0);
+        let mut data = Vec::with_capacity(dimensions.iter().product());
+        for z in 0..dimensions[2] {
+            for y in 0..dimensions[1] {
+                for x in 0..dimensions[0] {
+                    let source = [x, y, z].map(|c| c as f32 + 0.5);
+                    let source = vec3(
+                        source[0] * scale[0] - 0.5,
+                        source[1] * scale[1] - 0.5,
+                        source[2] * scale[2] - 0.5,
+                    )
+                    .clamp(Vec3::ZERO, Vec3::from(last));
+                    data.push(self.sample(source));
+                }
+            }
+        }
+        Self { dimensions, data }
+    }
+
+    /// Trilinear filtering at `voxel`, which must be inside the grid.
+    fn sample(&self, voxel: Vec3) -> f32 {
+        let low = voxel.floor();
+        let t = voxel - low;
+        let mut density = 0.0;
+        for corner in 0..8 {
+            let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let coord = [0, 1, 2].map(|axis| {
+                (low[axis] as usize + offset[axis]).min(self.dimensions[axis].saturating_sub(1))
+            });
+            let weight = [0, 1, 2]
+                .map(|axis| {
+                    if offset[axis] == 1 {
+                        t[axis]
+                    } else {
+                        1.0 - t[axis]
+                    }
+                })
+                .iter()
+                .product::<f32>();
+            if weight > 0.0 {
+                density += weight * self.get(coord);
+            }
+        }
+        density
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<Self, VolumeFileError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(VolumeFileError::NotAVolume);
+        }
+        let version = read_u32(&mut reader)?;
+        if version != VERSION {
+            return Err(VolumeFileError::Version(version));
+        }
+        let layout = read_u32(&mut reader)?;
+        let header_dimensions = [
+            read_u32(&mut reader)?,
+            read_u32(&mut reader)?,
+            read_u32(&mut reader)?,
+        ];
+        let dimensions = header_dimensions.map(|d| d as usize);
+        let voxels = dimensions
+            .iter()
+            .try_fold(1usize, |voxels, &d| voxels.checked_mul(d))
+            .filter(|&voxels| voxels > 0 && voxels <= MAX_VOXELS)
+            .ok_or(VolumeFileError::Dimensions(header_dimensions))?;
+        let background = read_f32(&mut reader)?;
+
+        let data = match layout {
+            DENSE => (0..voxels)
+                .map(|_| read_f32(&mut reader))
+                .collect::<Result<Vec<_>, _>>()?,
+            LEAVES => {
+                let mut data = vec![background; voxels];
+                for _ in 0..read_u32(&mut reader)? {
+                    let origin = [
+                        read_u32(&mut reader)?,
+                        read_u32(&mut reader)?,
+                        read_u32(&mut reader)?,
+                    ];
+                    let leaf = origin.map(|o| o as usize / BRICK_SIZE);
+                    let on_grid = origin
+                        .iter()
+                        .all(|&o| (o as usize).is_multiple_of(BRICK_SIZE));
+                    if !on_grid || voxel_index(origin.map(|o| o as usize), dimensions).is_none() {
+                        return Err(VolumeFileError::Leaf(origin));
+                    }
+                    for index in 0..LEAF_VOXELS {
+                        let density = read_f32(&mut reader)?;
+                        if let Some(index) = voxel_index(brick_voxel(leaf, index), dimensions) {
+                            data[index] = density;
+                        }
+                    }
+                }
+                data
+            }
+            layout => return Err(VolumeFileError::Layout(layout)),
+        };
+        Ok(Self { dimensions, data })
+    }
+
+    /// Writes every voxel.
+    pub fn write_dense(&self, mut writer: impl Write) -> io::Result<()> {
+        self.write_header(&mut writer, DENSE)?;
+        for density in &self.data {
+            writer.write_all(&density.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the 8³ leaves with any density in them, much smaller for clouds with a lot of
+    /// clear air.
+    pub fn write_leaves(&self, mut writer: impl Write) -> io::Result<()> {
+        let leaves = self.dimensions.map(|d| d.div_ceil(BRICK_SIZE));
+        let occupied = (0..leaves[2])
+            .flat_map(|z| (0..leaves[1]).flat_map(move |y| (0..leaves[0]).map(move |x| [x, y, z])))
+            .filter(|&leaf| (0..LEAF_VOXELS).any(|index| self.get(brick_voxel(leaf, index)) != 0.0))
+            .collect::<Vec<_>>();
+
+        self.write_header(&mut writer, LEAVES)?;
+        writer.write_all(&(occupied.len() as u32).to_le_bytes())?;
+        for leaf in occupied {
+            for origin in leaf {
+                writer.write_all(&((origin * BRICK_SIZE) as u32).to_le_bytes())?;
+            }
+            for index in 0..LEAF_VOXELS {
+                writer.write_all(&self.get(brick_voxel(leaf, index)).to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_header(&self, writer: &mut impl Write, layout: u32) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&layout.to_le_bytes())?;
+        for dimension in self.dimensions {
+            writer.write_all(&(dimension as u32).to_le_bytes())?;
+        }
+        // Leaves are only left out where there's no density
+        writer.write_all(&0f32.to_le_bytes())
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    read_u32(reader).map(f32::from_bits)
+}
+
+#[derive(Default)]
+pub struct DensityGridLoader;
+
+impl AssetLoader for DensityGridLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let grid = DensityGrid::read(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(grid));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["cvol"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rm_cloud::bake_density;
+
+    fn baked_grid() -> DensityGrid {
+        // Not a multiple of the leaf size, so the last leaves hang over the edge
+        let dimensions = [20, 10, 18];
+        let volume = bake_density(dimensions, vec3(5.0, 1.25, 5.0), 0.9);
+        DensityGrid::from_volume(dimensions, &volume)
+    }
+
+    fn header(layout: u32, dimensions: [u32; 3]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for value in [VERSION, layout].into_iter().chain(dimensions) {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.extend(0f32.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn dense_round_trip() {
+        let grid = baked_grid();
+        let mut dense = vec![];
+        grid.write_dense(&mut dense).unwrap();
+        assert_eq!(DensityGrid::read(dense.as_slice()).unwrap(), grid);
+    }
+
+    #[test]
+    fn leaves_round_trip() {
+        let grid = baked_grid();
+        assert!(grid.data.iter().any(|&d| d > 0.0), "nothing to write");
+        let mut leaves = vec![];
+        grid.write_leaves(&mut leaves).unwrap();
+        assert_eq!(DensityGrid::read(leaves.as_slice()).unwrap(), grid);
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        assert!(matches!(
+            DensityGrid::read(&b"VDB0"[..]),
+            Err(VolumeFileError::NotAVolume)
+        ));
+        let mut dense = vec![];
+        baked_grid().write_dense(&mut dense).unwrap();
+        assert!(matches!(
+            DensityGrid::read(&dense[..dense.len() - 1]),
+            Err(VolumeFileError::Io(_))
+        ));
+        assert!(matches!(
+            DensityGrid::read(header(7, [1, 1, 1]).as_slice()),
+            Err(VolumeFileError::Layout(7))
+        ));
+        let mut leaf = header(LEAVES, [8, 8, 8]);
+        leaf.extend([1u32, 4, 0, 0].iter().flat_map(|v| v.to_le_bytes()));
+        assert!(matches!(
+            DensityGrid::read(leaf.as_slice()),
+            Err(VolumeFileError::Leaf([4, 0, 0]))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_dimensions() {
+        for dimensions in [
+            [0, 4, 4],
+            [4, 4, 0],
+            [u32::MAX; 3],
+            [1 << 12, 1 << 12, 1 << 12],
+        ] {
+            for layout in [DENSE, LEAVES] {
+                assert!(
+                    matches!(
+                        DensityGrid::read(header(layout, dimensions).as_slice()),
+                        Err(VolumeFileError::Dimensions(d)) if d == dimensions
+                    ),
+                    "{dimensions:?} read"
+                );
+            }
+        }
+    }
+
+    /// Resampling keeps a constant grid constant and the density in the same place.
+    #[test]
+    fn resample() {
+        let constant = DensityGrid {
+            dimensions: [7, 5, 3],
+            data: vec![0.5; 7 * 5 * 3],
+        };
+        let resampled = constant.resample([20, 2, 9]);
+        assert_eq!(resampled.data.len(), 20 * 2 * 9);
+        assert!(resampled.data.iter().all(|&d| (d - 0.5).abs() < 1e-6));
+
+        let grid = baked_grid();
+        let up = grid.resample([40, 20, 36]);
+        let down = up.resample(grid.dimensions);
+        let mean = |grid: &DensityGrid| {
+            grid.data.iter().map(|&d| d as f64).sum::<f64>() / grid.data.len() as f64
+        };
+        let drift = (mean(&down) - mean(&grid)).abs() / mean(&grid);
+        assert!(
+            drift < 0.02,
+            "resampling changed the mean density by {drift}"
+        );
+    }
+}
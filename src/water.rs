@@ -1,86 +1,120 @@
 use std::f32::consts::PI;
 
 use bevy::{
+    pbr::{MaterialPipeline, MaterialPipelineKey},
     prelude::*,
     reflect::TypeUuid,
-    render::render_resource::{AsBindGroup, ShaderRef},
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_resource::{
+            AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError,
+        },
+    },
 };
 
-use crate::CameraController;
+use crate::global_environment::{add_global_environment, specialize_global_environment};
 
-pub struct WaterPlugin;
+/// Draws a flat disc of water. Set `spawn` to false to only register the material
+/// and place `WaterBundle`s yourself.
+pub struct WaterPlugin {
+    pub radius: f32,
+    pub vertices: usize,
+    pub spawn: bool,
+}
+
+impl Default for WaterPlugin {
+    fn default() -> Self {
+        Self {
+            radius: 90_000.,
+            vertices: 200,
+            spawn: true,
+        }
+    }
+}
 
 #[derive(Component)]
-struct Water {
-    handle: Handle<WaterMaterial>,
+pub struct Water {
+    pub handle: Handle<WaterMaterial>,
 }
-impl Plugin for WaterPlugin {
-    fn build(&self, app: &mut App) {
-        app.add_plugin(MaterialPlugin::<WaterMaterial>::default());
-        app.add_startup_system(
-            |mut materials: ResMut<Assets<WaterMaterial>>,
-             mut commands: Commands,
-             mut meshes: ResMut<Assets<Mesh>>| {
-                let material = materials.add(WaterMaterial::default());
-                commands.spawn((
-                    MaterialMeshBundle {
-                        mesh: meshes.add(generate_water_mesh()),
-                        material: material.clone(),
-                        transform: Transform::from_rotation(Quat::from_euler(
-                            EulerRot::XYZ,
-                            PI * -0.5,
-                            0.,
-                            0.,
-                        )),
-                        ..default()
-                    },
-                    Water { handle: material },
-                ));
+
+#[derive(Bundle)]
+pub struct WaterBundle {
+    pub water: Water,
+    pub material_mesh: MaterialMeshBundle<WaterMaterial>,
+}
+
+impl WaterBundle {
+    /// A water disc of `radius` lying in the XZ plane at the origin.
+    pub fn new(
+        radius: f32,
+        vertices: usize,
+        meshes: &mut Assets<Mesh>,
+        materials: &mut Assets<WaterMaterial>,
+    ) -> Self {
+        let material = materials.add(WaterMaterial::default());
+        Self {
+            water: Water {
+                handle: material.clone(),
             },
-        );
-        app.add_system(
-            |camera: Query<&Transform, With<CameraController>>,
-             sun: Query<&Transform, With<DirectionalLight>>,
-             water: Query<&Water>,
-             mut materials: ResMut<Assets<WaterMaterial>>,
-             time: Res<Time>| {
-                let camera_position = camera.get_single().unwrap().translation;
-                let sun_facing = sun.get_single().unwrap().forward();
-                for water in &water {
-                    if let Some(material) = materials.get_mut(&water.handle) {
-                        material.camera_position = camera_position;
-                        material.time = time.raw_elapsed_seconds();
-                        material.sun_direction = sun_facing;
-                    }
-                }
+            material_mesh: MaterialMeshBundle {
+                mesh: meshes.add(generate_water_mesh(radius, vertices)),
+                material,
+                transform: Transform::from_rotation(Quat::from_euler(
+                    EulerRot::XYZ,
+                    PI * -0.5,
+                    0.,
+                    0.,
+                )),
+                ..default()
             },
-        );
+        }
     }
 }
 
-fn generate_water_mesh() -> Mesh {
-    shape::Circle {
-        radius: 90_000.,
-        vertices: 200,
+impl Plugin for WaterPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(MaterialPlugin::<WaterMaterial>::default());
+        if self.spawn {
+            let (radius, vertices) = (self.radius, self.vertices);
+            app.add_startup_system(
+                move |mut materials: ResMut<Assets<WaterMaterial>>,
+                      mut commands: Commands,
+                      mut meshes: ResMut<Assets<Mesh>>| {
+                    commands.spawn(WaterBundle::new(
+                        radius,
+                        vertices,
+                        &mut meshes,
+                        &mut materials,
+                    ));
+                },
+            );
+        }
+        add_global_environment::<WaterMaterial>(app);
     }
-    .into()
+}
+
+fn generate_water_mesh(radius: f32, vertices: usize) -> Mesh {
+    shape::Circle { radius, vertices }.into()
 }
 
 #[derive(AsBindGroup, TypeUuid, Debug, Clone, Default, Reflect)]
 #[uuid = "f790fd8e-d598-45ab-8225-97e2a3f056e0"]
 pub struct WaterMaterial {
-    #[uniform(0)]
-    pub sun_direction: Vec3,
-    #[uniform(0)]
-    pub camera_position: Vec3,
     #[uniform(0)]
     pub scale: Vec3,
-    #[uniform(0)]
-    pub time: f32,
     // #[texture(1, dimension = "3d")]
     #[texture(1)]
     #[sampler(2)]
     pub noise: Option<Handle<Image>>,
+    /// The cloud shadow map, which `CloudShadowPlugin` fills in. Without it the water is
+    /// unshadowed.
+    #[texture(3)]
+    #[sampler(4)]
+    pub shadow_map: Option<Handle<Image>>,
+    #[uniform(5)]
+    pub shadow_projection: Mat4,
+    #[uniform(5)]
+    pub shadow_floor: f32,
 }
 
 impl Material for WaterMaterial {
@@ -91,4 +125,14 @@ impl Material for WaterMaterial {
     fn alpha_mode(&self) -> AlphaMode {
         AlphaMode::Blend
     }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        specialize_global_environment(descriptor, &key);
+        Ok(())
+    }
 }
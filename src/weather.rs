@@ -0,0 +1,292 @@
+use bevy::{core_pipeline::bloom::BloomSettings, prelude::*, utils::HashMap};
+
+#[cfg(feature = "rm-cloud")]
+use crate::cloud::RMCloud;
+use crate::easing::{lerp, lerp_color, Easing};
+
+/*
+Named weather states that the scene drifts between. Setting `Weather::target`
+(from code or the inspector) starts an eased transition from whatever is
+currently on screen, and `manual` pins the scene to a hand-tuned state.
+*/
+
+pub struct WeatherPlugin;
+impl Plugin for WeatherPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<Weather>();
+        app.insert_resource(Weather::default());
+        app.add_event::<WeatherTransitionStarted>();
+        app.add_event::<WeatherTransitionFinished>();
+        app.add_system(update_weather);
+    }
+}
+
+/// The shape and shading of an `RMCloud` layer, kept here so weather and timeline data
+/// look the same with or without the `rm-cloud` feature.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Reflect, FromReflect)]
+pub struct CloudParams {
+    pub shadow_dist: f32,
+    pub shadow_coef: f32,
+    pub sun_pen: f32,
+    pub worley_factor: f32,
+    pub value_factor: f32,
+    pub cloud_coef: f32,
+    pub cloud_height: f32,
+}
+
+impl CloudParams {
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            shadow_dist: lerp(self.shadow_dist, other.shadow_dist, t),
+            shadow_coef: lerp(self.shadow_coef, other.shadow_coef, t),
+            sun_pen: lerp(self.sun_pen, other.sun_pen, t),
+            worley_factor: lerp(self.worley_factor, other.worley_factor, t),
+            value_factor: lerp(self.value_factor, other.value_factor, t),
+            cloud_coef: lerp(self.cloud_coef, other.cloud_coef, t),
+            cloud_height: lerp(self.cloud_height, other.cloud_height, t),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Reflect, FromReflect)]
+pub struct WeatherState {
+    pub cloud: CloudParams,
+    pub ambient_color: Color,
+    pub ambient_brightness: f32,
+    pub sun_color: Color,
+    pub sun_illuminance: f32,
+    pub bloom_intensity: f32,
+}
+
+impl WeatherState {
+    pub fn clear() -> Self {
+        Self {
+            cloud: CloudParams {
+                shadow_dist: 50.0,
+                shadow_coef: 0.1,
+                sun_pen: 30.,
+                worley_factor: 0.1,
+                value_factor: 0.0,
+                cloud_coef: 0.2,
+                cloud_height: 0.2,
+            },
+            ambient_color: Color::rgb(0.54, 0.8, 1.),
+            ambient_brightness: 1.0,
+            sun_color: Color::rgb(2.2, 2.05, 1.9),
+            sun_illuminance: 20_000.0,
+            bloom_intensity: 0.5,
+        }
+    }
+
+    pub fn overcast() -> Self {
+        Self {
+            cloud: CloudParams {
+                shadow_dist: 60.0,
+                shadow_coef: 0.15,
+                sun_pen: 20.,
+                worley_factor: 0.0,
+                value_factor: -0.1,
+                cloud_coef: 0.35,
+                cloud_height: 0.1,
+            },
+            ambient_color: Color::rgb(0.6, 0.65, 0.7),
+            ambient_brightness: 0.8,
+            sun_color: Color::rgb(1.4, 1.4, 1.35),
+            sun_illuminance: 12_000.0,
+            bloom_intensity: 0.3,
+        }
+    }
+
+    pub fn stormy() -> Self {
+        Self {
+            cloud: CloudParams {
+                shadow_dist: 80.0,
+                shadow_coef: 0.3,
+                sun_pen: 10.,
+                worley_factor: -0.1,
+                value_factor: -0.2,
+                cloud_coef: 0.5,
+                cloud_height: 0.05,
+            },
+            ambient_color: Color::rgb(0.3, 0.35, 0.45),
+            ambient_brightness: 0.5,
+            sun_color: Color::rgb(0.7, 0.75, 0.85),
+            sun_illuminance: 5_000.0,
+            bloom_intensity: 0.15,
+        }
+    }
+
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            cloud: self.cloud.lerp(&other.cloud, t),
+            ambient_color: lerp_color(self.ambient_color, other.ambient_color, t),
+            ambient_brightness: lerp(self.ambient_brightness, other.ambient_brightness, t),
+            sun_color: lerp_color(self.sun_color, other.sun_color, t),
+            sun_illuminance: lerp(self.sun_illuminance, other.sun_illuminance, t),
+            bloom_intensity: lerp(self.bloom_intensity, other.bloom_intensity, t),
+        }
+    }
+}
+
+impl Default for WeatherState {
+    fn default() -> Self {
+        Self::clear()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct WeatherTransition {
+    from: WeatherState,
+    to: String,
+    elapsed: f32,
+}
+
+#[derive(Resource, Reflect)]
+#[reflect(Resource)]
+pub struct Weather {
+    pub states: HashMap<String, WeatherState>,
+    /// Name of the state that is fully applied, or that the last transition came from.
+    pub current: String,
+    /// Name of the state to move towards; changing it starts a transition.
+    pub target: String,
+    pub duration: f32,
+    pub easing: Easing,
+    /// When set, the scene uses these values directly and transitions are paused.
+    pub manual: Option<WeatherState>,
+    /// The values applied to the scene last frame.
+    pub blended: WeatherState,
+    #[reflect(ignore)]
+    transition: Option<WeatherTransition>,
+}
+
+impl Default for Weather {
+    fn default() -> Self {
+        let mut states = HashMap::default();
+        states.insert("clear".to_string(), WeatherState::clear());
+        states.insert("overcast".to_string(), WeatherState::overcast());
+        states.insert("stormy".to_string(), WeatherState::stormy());
+        Self {
+            states,
+            current: "clear".to_string(),
+            target: "clear".to_string(),
+            duration: 20.0,
+            easing: Easing::SmoothStep,
+            manual: None,
+            blended: WeatherState::clear(),
+            transition: None,
+        }
+    }
+}
+
+impl Weather {
+    pub fn transition_to(&mut self, state: impl Into<String>) {
+        self.target = state.into();
+    }
+
+    /// Progress of the running transition in `0..=1`, before easing.
+    pub fn progress(&self) -> Option<f32> {
+        self.transition
+            .as_ref()
+            .map(|transition| (transition.elapsed / self.duration.max(f32::EPSILON)).min(1.0))
+    }
+
+    pub fn is_transitioning(&self) -> bool {
+        self.transition.is_some()
+    }
+}
+
+pub struct WeatherTransitionStarted {
+    pub from: String,
+    pub to: String,
+}
+
+pub struct WeatherTransitionFinished {
+    pub state: String,
+}
+
+/// Only writes to the scene while the blended state changes, so the scene file and the
+/// inspector can change the lights in between.
+#[allow(clippy::too_many_arguments)]
+pub fn update_weather(
+    time: Res<Time>,
+    mut weather: ResMut<Weather>,
+    mut started: EventWriter<WeatherTransitionStarted>,
+    mut finished: EventWriter<WeatherTransitionFinished>,
+    mut ambient: ResMut<AmbientLight>,
+    #[cfg(feature = "rm-cloud")] mut clouds: Query<&mut RMCloud>,
+    mut suns: Query<&mut DirectionalLight>,
+    mut blooms: Query<&mut BloomSettings>,
+    mut applied: Local<bool>,
+) {
+    let weather = weather.as_mut();
+    let previous = weather.blended.clone();
+
+    if let Some(manual) = &weather.manual {
+        weather.blended = manual.clone();
+    } else {
+        let retarget = match &weather.transition {
+            Some(transition) => transition.to != weather.target,
+            None => weather.target != weather.current,
+        };
+        if retarget {
+            if weather.states.contains_key(&weather.target) {
+                started.send(WeatherTransitionStarted {
+                    from: weather.current.clone(),
+                    to: weather.target.clone(),
+                });
+                weather.transition = Some(WeatherTransition {
+                    from: weather.blended.clone(),
+                    to: weather.target.clone(),
+                    elapsed: 0.0,
+                });
+            } else {
+                warn!("Unknown weather state {:?}", weather.target);
+                weather.target = weather.current.clone();
+            }
+        }
+
+        if let Some(transition) = weather.transition.as_mut() {
+            transition.elapsed += time.delta_seconds();
+            let t = (transition.elapsed / weather.duration.max(f32::EPSILON)).min(1.0);
+            if let Some(to) = weather.states.get(&transition.to) {
+                weather.blended = transition.from.lerp(to, weather.easing.apply(t));
+                if t >= 1.0 {
+                    weather.current = transition.to.clone();
+                    weather.transition = None;
+                    finished.send(WeatherTransitionFinished {
+                        state: weather.current.clone(),
+                    });
+                }
+            } else {
+                // Removed mid transition, easily done from the inspector
+                warn!("Weather state {:?} was removed", transition.to);
+                weather.transition = None;
+            }
+        } else if let Some(state) = weather.states.get(&weather.current) {
+            weather.blended = state.clone();
+        }
+    }
+
+    let state = &weather.blended;
+    let changed = !*applied || *state != previous;
+    *applied = true;
+    if changed {
+        ambient.color = state.ambient_color;
+        ambient.brightness = state.ambient_brightness;
+    }
+    // Lights and clouds spawned since the last change still need the current state
+    for mut sun in suns.iter_mut().filter(|sun| changed || sun.is_added()) {
+        sun.color = state.sun_color;
+        sun.illuminance = state.sun_illuminance;
+    }
+    for mut bloom in blooms.iter_mut().filter(|bloom| changed || bloom.is_added()) {
+        bloom.intensity = state.bloom_intensity;
+    }
+    #[cfg(feature = "rm-cloud")]
+    for mut cloud in clouds
+        .iter_mut()
+        .filter(|cloud| cloud.follows_weather && (changed || cloud.is_added()))
+    {
+        state.cloud.apply(&mut cloud);
+    }
+}